@@ -0,0 +1,152 @@
+// Compares fetching nonces through one shared client with creating a client per request,
+// which is what the flow did before it passed a single `Client` through. It runs against a
+// local TLS server that counts the connections it accepts, every one of them being a TCP and
+// TLS handshake, so it needs no CA and its result doesn't depend on the network.
+//
+//     cargo run --example connection_reuse
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use acme::{
+    acc::Directory,
+    http::Client,
+    util::{generate_keypair, KeyType},
+};
+use openssl::{
+    asn1::Asn1Time,
+    hash::MessageDigest,
+    nid::Nid,
+    ssl::{SslAcceptor, SslMethod, SslStream},
+    x509::{X509NameBuilder, X509},
+};
+use serde_json::json;
+
+// About as many requests as an issuance for a single domain makes.
+const REQUESTS: usize = 6;
+
+// Serves a directory and nonces over TLS on a free port of the loopback interface and
+// returns its URL and the number of connections accepted so far.
+fn start_server() -> (String, Arc<AtomicUsize>) {
+    let (key, _) = generate_keypair(KeyType::Rsa).expect("Could not generate key");
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, "localhost")
+        .unwrap();
+    let name = name.build();
+    let mut certificate = X509::builder().unwrap();
+    certificate.set_version(2).unwrap();
+    certificate.set_subject_name(&name).unwrap();
+    certificate.set_issuer_name(&name).unwrap();
+    certificate.set_pubkey(&key).unwrap();
+    certificate
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    certificate
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    certificate.sign(&key, MessageDigest::sha256()).unwrap();
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.set_certificate(&certificate.build()).unwrap();
+    let acceptor = Arc::new(acceptor.build());
+
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind the server");
+    let url = format!("https://{}", listener.local_addr().unwrap());
+    let accepted = Arc::new(AtomicUsize::new(0));
+
+    let directory = json!({
+        "newNonce": format!("{}/nonce", url),
+        "newAccount": format!("{}/account", url),
+        "newOrder": format!("{}/order", url),
+        "revokeCert": format!("{}/revoke", url),
+        "keyChange": format!("{}/key-change", url),
+    })
+    .to_string();
+    let counter = accepted.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let acceptor = acceptor.clone();
+            let directory = directory.clone();
+            // a pooled connection stays open, so each one gets a thread of its own
+            std::thread::spawn(move || {
+                if let Ok(stream) = acceptor.accept(stream) {
+                    serve(stream, &directory);
+                }
+            });
+        }
+    });
+
+    (url, accepted)
+}
+
+// Answers the requests of a connection until the client closes it.
+fn serve(stream: SslStream<std::net::TcpStream>, directory: &str) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).unwrap_or(0) == 0 {
+                return;
+            }
+            if header.trim().is_empty() {
+                break;
+            }
+        }
+
+        // the directory is fetched with a GET, nonces with a HEAD
+        let response = if line.starts_with("GET") {
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                directory.len(),
+                directory
+            )
+        } else {
+            "HTTP/1.1 200 OK\r\nReplay-Nonce: nonce\r\nContent-Length: 0\r\n\r\n".to_owned()
+        };
+        if reader.get_mut().write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+// Fetches `REQUESTS` nonces and returns how many connections the server accepted for them.
+fn fetch_nonces(server: &str, accepted: &AtomicUsize, shared: bool) -> usize {
+    // the server's certificate is self-signed
+    let client = Client::builder().accept_invalid_certs(true).build();
+    let directory = Directory::fetch_dir(&client, server).expect("Could not fetch the directory");
+
+    let before = accepted.load(Ordering::SeqCst);
+    for _ in 0..REQUESTS {
+        let client = if shared {
+            client.clone()
+        } else {
+            Client::builder().accept_invalid_certs(true).build()
+        };
+        directory
+            .fetch_nonce(&client)
+            .expect("Could not fetch a nonce");
+    }
+    accepted.load(Ordering::SeqCst) - before
+}
+
+fn main() {
+    let (server, accepted) = start_server();
+
+    let separate = fetch_nonces(&server, &accepted, false);
+    let shared = fetch_nonces(&server, &accepted, true);
+    println!(
+        "{} requests with a client each:  {} handshakes",
+        REQUESTS, separate
+    );
+    println!(
+        "{} requests with a shared client: {} handshakes",
+        REQUESTS, shared
+    );
+}
@@ -0,0 +1,20 @@
+// Signs an ACME request with the public JWS helpers. Being an example, this is compiled
+// with the rest of the crate, so the helper signatures can't drift apart from their callers.
+use acme::util::{jwk, jws};
+use openssl::{pkey::PKey, rsa::Rsa};
+use serde_json::json;
+
+fn main() {
+    let rsa_key = Rsa::generate(2048).expect("Could not generate key");
+    let account_key = PKey::from_rsa(rsa_key).expect("Could not convert key");
+
+    let header = json!({
+        "url": "https://example.com/acme/new-account",
+        "jwk": jwk(&account_key).expect("Could not build JWK"),
+        "nonce": "nonce",
+    });
+    let payload = json!({ "termsOfServiceAgreed": true });
+
+    let request = jws(payload, header, &account_key).expect("Could not sign request");
+    println!("{}", serde_json::to_string_pretty(&request).unwrap());
+}
@@ -1,31 +1,155 @@
+use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{self, BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
 use core::fmt::Debug;
+use log::{info, warn};
 use openssl::{
-    hash::MessageDigest,
-    nid::Nid,
-    pkey::{Private, Public},
-    rsa::Rsa,
-    sha::Sha256,
-    x509::{X509NameBuilder, X509Req, X509ReqBuilder},
+    pkey::{Id, PKey, Private, Public},
+    x509::{X509Req, X509VerifyResult, X509},
 };
-use reqwest::blocking::Client;
+use reqwest::{blocking::Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
 use crate::{
+    dns,
     error::{Error, Result},
+    http::Client,
+    tls_alpn,
     util::{
-        b64, check_for_existing_server, extract_payload_and_nonce,
-        extract_payload_location_and_nonce, jwk, jws,
+        b64, bind_both_families, chain_root_issuer, check_for_existing_server,
+        check_for_existing_server_on, check_problem, dns_txt_value, extract_payload_and_nonce,
+        extract_payload_location_and_nonce, identifier_type, jwk, jws, key_info, links_with_rel,
+        normalize_chain, replay_nonce, request_csr, request_id, retry_after, run_hook, server_date,
+        to_ascii_domain, with_request_id, Clock, CsrExtension, KeyInfo, SubjectField,
     },
 };
+// the key and digest of an order's CSR are chosen with these, so callers of this module
+// don't have to reach into util for them
+pub use crate::util::{CsrDigest, KeyType};
 
 pub type Nonce = String;
 pub type Certificate = String;
 
+// Checks that the http-01 challenge files can be written to the web root of an existing
+// web server.
+pub fn check_web_root() -> Result<()> {
+    let dir = Path::new(WEB_ROOT).join(CHALLENGE_PATH.trim_start_matches('/'));
+    fs::create_dir_all(&dir)?;
+    let probe = dir.join(".acme-self-test");
+    File::create(&probe)?;
+    fs::remove_file(probe)?;
+
+    Ok(())
+}
+
+// Signs a request, fetching a fresh nonce first if the previous response carried none.
+fn sign(
+    client: &Client,
+    mut payload: serde_json::Value,
+    mut header: serde_json::Value,
+    p_key: &PKey<Private>,
+) -> Result<serde_json::Value> {
+    if header["nonce"].as_str().is_none_or(str::is_empty) {
+        header["nonce"] = json!(client.fresh_nonce()?);
+    }
+    // draft servers also want to know the resource in the payload itself, they predate the
+    // empty payload of a POST-as-GET
+    if let Some(resource) = header["url"].as_str().and_then(|url| client.resource(url)) {
+        if payload == json!("") {
+            payload = json!({});
+        }
+        if let Some(fields) = payload.as_object_mut() {
+            fields.insert("resource".to_owned(), json!(resource));
+        }
+    }
+    // servers of the early drafts, whose accounts are `reg` resources, know an account by its
+    // key only
+    let registration = header["kid"].as_str().and_then(|url| client.resource(url));
+    if let (Some("reg"), Some(fields)) = (registration.as_deref(), header.as_object_mut()) {
+        fields.remove("kid");
+        fields.insert("jwk".to_owned(), jwk(p_key)?);
+    }
+
+    jws(payload, header, p_key)
+}
+
+// Signs a request with the nonce `Client::post_signed` asks for, e.g. after the server
+// rejected the one in the header.
+fn signer<'a>(
+    client: &'a Client,
+    payload: serde_json::Value,
+    header: serde_json::Value,
+    p_key: &'a PKey<Private>,
+) -> impl Fn(Option<Nonce>) -> Result<serde_json::Value> + 'a {
+    move |nonce| {
+        let mut header = header.clone();
+        if let Some(nonce) = nonce {
+            header["nonce"] = json!(nonce);
+        }
+        sign(client, payload.clone(), header, p_key)
+    }
+}
+
+// Where the http-01 key authorisations are served from.
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge";
+// The web root of an existing web server the challenge files are written to.
+pub const WEB_ROOT: &str = "/var/www/html";
+
+// How long an external web server may take to acknowledge the handed off challenges.
+const SOCKET_ACK_TIMEOUT: Duration = Duration::from_secs(60);
+// How many `up` links are followed to complete a chain the CA returned as the bare leaf.
+const MAX_UP_LINKS: usize = 4;
+
+// A running standalone server and the channel that stops it.
+type Responder = (JoinHandle<()>, Sender<()>);
+
+// Shuts the standalone servers down when the challenges are done, however that went.
+struct Responders(Vec<Responder>);
+
+impl Drop for Responders {
+    fn drop(&mut self) {
+        for (handle, stop) in self.0.drain(..) {
+            let _ = stop.send(());
+            let _ = handle.join();
+        }
+    }
+}
+
+// Runs the cleanup hook for every challenge the auth hook was run for when the challenges
+// are done, however that went.
+struct Hooked<'a> {
+    cleanup_hook: Option<&'a str>,
+    variables: Vec<Vec<(&'static str, String)>>,
+}
+
+impl Drop for Hooked<'_> {
+    fn drop(&mut self) {
+        if let Some(cleanup_hook) = self.cleanup_hook {
+            for variables in &self.variables {
+                if let Err(e) = run_hook(cleanup_hook, variables) {
+                    warn!("{}", e);
+                }
+            }
+        }
+    }
+}
+
+// Authorisations whose challenges of one type were presented, with their key authorisations.
+type Presented<'a> = (Vec<&'a ChallengeAuthorisation>, Vec<(Challenge, String)>);
+
 // The current status of the request. 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum StatusType {
@@ -35,245 +159,1170 @@ pub enum StatusType {
     Pending,
     #[serde(rename = "invalid")]
     Invalid,
+    #[serde(rename = "processing")]
+    Processing,
+    #[serde(rename = "deactivated")]
+    Deactivated,
+    #[serde(rename = "expired")]
+    Expired,
+    #[serde(rename = "revoked")]
+    Revoked,
+}
+
+// The challenge types that can be solved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChallengeType {
+    Http01,
+    TlsAlpn01,
+    // Only solved in manual mode, as creating DNS records can't be automated yet.
+    Dns01,
+}
+
+impl ChallengeType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::TlsAlpn01 => "tls-alpn-01",
+            ChallengeType::Dns01 => "dns-01",
+        }
+    }
+
+    // Whether the challenge type can validate an identifier of the type. An IP address
+    // (RFC 8738) has no DNS records to publish, and its tls-alpn-01 validation would need
+    // the reverse DNS name that the standalone responder doesn't serve.
+    pub fn supports(self, identifier_type: &str) -> bool {
+        match identifier_type {
+            "dns" => true,
+            "ip" => self == ChallengeType::Http01,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for ChallengeType {
+    type Err = Error;
+
+    fn from_str(challenge_type: &str) -> Result<Self> {
+        match challenge_type {
+            "http-01" => Ok(ChallengeType::Http01),
+            "tls-alpn-01" => Ok(ChallengeType::TlsAlpn01),
+            "dns-01" => Ok(ChallengeType::Dns01),
+            _ => Err(Error::NoSupportedChallengePresent),
+        }
+    }
+}
+
+// Decides which challenge type gets solved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ChallengeStrategy {
+    // Pick the best challenge type for the environment.
+    #[default]
+    Auto,
+    // Only ever solve the given challenge type.
+    Only(ChallengeType),
+}
+
+impl ChallengeStrategy {
+    // The challenge types to try, in order of preference. A standalone run prefers
+    // tls-alpn-01 if port 443 is free and falls back to http-01 otherwise.
+    pub fn candidates(self, standalone: bool) -> Vec<ChallengeType> {
+        match self {
+            ChallengeStrategy::Only(challenge_type) => vec![challenge_type],
+            ChallengeStrategy::Auto => {
+                let mut candidates = Vec::new();
+                if standalone && !check_for_existing_server_on(443) {
+                    candidates.push(ChallengeType::TlsAlpn01);
+                }
+                candidates.push(ChallengeType::Http01);
+                candidates
+            }
+        }
+    }
+
+    // The challenge types to try when the user presents them manually. Auto prefers
+    // http-01 and falls back to dns-01, e.g. for wildcard domains.
+    pub fn manual_candidates(self) -> Vec<ChallengeType> {
+        match self {
+            ChallengeStrategy::Only(challenge_type) => vec![challenge_type],
+            ChallengeStrategy::Auto => vec![ChallengeType::Http01, ChallengeType::Dns01],
+        }
+    }
+}
+
+impl FromStr for ChallengeStrategy {
+    type Err = Error;
+
+    fn from_str(strategy: &str) -> Result<Self> {
+        match strategy {
+            "auto" => Ok(ChallengeStrategy::Auto),
+            challenge_type => Ok(ChallengeStrategy::Only(challenge_type.parse()?)),
+        }
+    }
+}
+
+// What the user has to do to present a challenge manually.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ManualStep {
+    // Serve `contents` at `url`, for http-01.
+    ServeFile {
+        domain: String,
+        url: String,
+        contents: String,
+    },
+    // Create a TXT record `name` with `value`, for dns-01.
+    TxtRecord {
+        domain: String,
+        name: String,
+        value: String,
+    },
+}
+
+// Shows the user the steps that present the challenges, e.g. on the terminal, and returns
+// once they confirm they are done. An error aborts the issuance.
+pub trait ManualPrompt: Debug + Send + Sync {
+    fn present(&self, steps: &[ManualStep]) -> Result<()>;
 }
 
-// The directory information that get returned in the first request.
+// Who presents the challenges to the CA.
+#[derive(Clone, Debug, Default)]
+pub enum Presentation {
+    // The client, with a standalone server, in the web root or through the challenge socket.
+    #[default]
+    Automatic,
+    // The user, following the steps `prompt` shows them. With `wait_for_propagation` the
+    // client waits until the dns-01 records are visible before the CA looks for them.
+    Manual {
+        prompt: Arc<dyn ManualPrompt>,
+        wait_for_propagation: bool,
+    },
+    // Nobody, the http-01 responses were placed out of band and are already reachable.
+    // With `self_check` the client fetches them first to catch mistakes before the CA does,
+    // e.g. through a reverse proxy, which gets `grace_period` to reload its configuration.
+    PrePlaced {
+        self_check: bool,
+        grace_period: Duration,
+    },
+    // Shell commands of the user, e.g. calling the API of their DNS provider, run for every
+    // challenge with its details in the environment: `auth_hook` before the CA validates
+    // it and `cleanup_hook` afterwards, even if the validation failed.
+    Hooks {
+        auth_hook: String,
+        cleanup_hook: Option<String>,
+        wait_for_propagation: bool,
+    },
+}
+
+// The directory information that get returned in the first request. Servers of the ACME
+// drafts name the endpoints differently, see `DraftDirectory`.
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Directory {
+    #[serde(default)]
     pub new_nonce: String,
     pub new_account: String,
     pub new_order: String,
     pub revoke_cert: String,
     pub key_change: String,
+    pub meta: Option<DirectoryMeta>,
+    // The endpoints servers of the early drafts offer instead of `newOrder`, every
+    // identifier is authorized on its own before the certificate is requested.
+    #[serde(skip)]
+    pub new_authz: String,
+    #[serde(skip)]
+    pub new_cert: String,
     #[serde(skip)]
     nonce: Nonce,
+    // How far the local clock is ahead of the server's, if the server sent its time.
+    #[serde(skip)]
+    pub clock_skew: Option<chrono::Duration>,
+    // the fields this client doesn't know, e.g. extensions of the CA, so they can still be
+    // read and survive when the struct is persisted instead of being dropped
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+// Optional metadata the server advertises in its directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectoryMeta {
+    pub terms_of_service: Option<String>,
+    pub website: Option<String>,
+    #[serde(default)]
+    pub caa_identities: Vec<String>,
+    #[serde(default)]
+    pub external_account_required: bool,
+    // The certificate profiles that can be selected in an order, mapped to their description.
+    #[serde(default)]
+    pub profiles: HashMap<String, String>,
+}
+
+// The directory of a server of the pre-RFC 8555 ACME drafts, which may not have a
+// `new-nonce` endpoint and, in the early drafts, no `new-order` either.
+#[derive(Deserialize)]
+struct DraftDirectory {
+    #[serde(default, rename = "new-nonce")]
+    new_nonce: String,
+    #[serde(rename = "new-reg", alias = "new-account")]
+    new_account: String,
+    #[serde(default, rename = "new-order")]
+    new_order: String,
+    #[serde(default, rename = "new-authz")]
+    new_authz: String,
+    #[serde(default, rename = "new-cert")]
+    new_cert: String,
+    #[serde(default, rename = "revoke-cert")]
+    revoke_cert: String,
+    #[serde(default, rename = "key-change")]
+    key_change: String,
+    meta: Option<DraftDirectoryMeta>,
+}
+
+#[derive(Deserialize)]
+struct DraftDirectoryMeta {
+    #[serde(rename = "terms-of-service")]
+    terms_of_service: Option<String>,
+    website: Option<String>,
+    #[serde(default, rename = "caa-identities")]
+    caa_identities: Vec<String>,
+}
+
+impl From<DraftDirectory> for Directory {
+    fn from(directory: DraftDirectory) -> Self {
+        Directory {
+            new_nonce: directory.new_nonce,
+            new_account: directory.new_account,
+            new_order: directory.new_order,
+            revoke_cert: directory.revoke_cert,
+            key_change: directory.key_change,
+            meta: directory.meta.map(|meta| DirectoryMeta {
+                terms_of_service: meta.terms_of_service,
+                website: meta.website,
+                caa_identities: meta.caa_identities,
+                ..DirectoryMeta::default()
+            }),
+            new_authz: directory.new_authz,
+            new_cert: directory.new_cert,
+            nonce: Nonce::new(),
+            clock_skew: None,
+            extra: serde_json::Map::new(),
+        }
+    }
 }
 
 impl Directory {
+    // Whether the server knows orders, which servers of the early ACME drafts don't. Their
+    // certificates are requested with `Account::new_authz` and `Account::new_cert` instead.
+    pub fn has_orders(&self) -> bool {
+        !self.new_order.is_empty()
+    }
+
+    // Checks that the server offers the given certificate profile. Servers that don't
+    // advertise any profiles are trusted to validate the profile themselves.
+    pub fn check_profile(&self, profile: &str) -> Result<()> {
+        match &self.meta {
+            Some(meta) if !meta.profiles.is_empty() && !meta.profiles.contains_key(profile) => {
+                Err(Error::InvalidProfile(profile.to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The domain names the CA recognizes as its own in CAA records, empty if the directory
+    // doesn't advertise them.
+    pub fn caa_identities(&self) -> Vec<String> {
+        self.meta
+            .as_ref()
+            .map(|meta| meta.caa_identities.clone())
+            .unwrap_or_default()
+    }
+
     // Fetches the directory information from a specific server. This is the first request
     // that's send to the server as it's return value holds information about the endpoints.
+    // If the url doesn't point to a directory (e.g. it's an account url), the directory
+    // advertised by the `Link: rel="index"` header is used instead.
     pub fn fetch_dir(client: &Client, server_url: &str) -> Result<Self> {
-        let mut dir_infos: Self = client.get(server_url).send()?.json()?;
+        let response = client.get(server_url)?;
+        let index = links_with_rel(&response, "index").into_iter().next();
+        let server_time = server_date(&response);
+
+        let mut dir_infos = match Directory::parse(client, response) {
+            Ok(dir_infos) => dir_infos,
+            Err(_) => {
+                let response = client.get(&index.ok_or(Error::NoDirectory)?)?;
+                Directory::parse(client, response).map_err(|_| Error::NoDirectory)?
+            }
+        };
+
+        dir_infos.clock_skew = server_time.map(|server_time| client.now() - server_time);
+
+        if client.is_acme_draft() {
+            // draft servers hand out nonces with every response, the directory included
+            if dir_infos.new_nonce.is_empty() {
+                dir_infos.new_nonce = server_url.to_owned();
+            }
+            for (url, resource) in [
+                (&dir_infos.new_account, "new-reg"),
+                (&dir_infos.new_order, "new-order"),
+                (&dir_infos.new_authz, "new-authz"),
+                (&dir_infos.new_cert, "new-cert"),
+                (&dir_infos.revoke_cert, "revoke-cert"),
+                (&dir_infos.key_change, "key-change"),
+            ] {
+                if !url.is_empty() {
+                    client.set_resource(url, resource);
+                }
+            }
+        }
 
         // fetch the new nonce
-        let nonce = client
-            .head(&dir_infos.new_nonce)
-            .send()?
+        client.set_new_nonce_url(&dir_infos.new_nonce);
+        dir_infos.nonce = dir_infos.fetch_nonce(client)?;
+
+        Ok(dir_infos)
+    }
+
+    // Reads the directory with the names of the ACME drafts if the client talks to a server
+    // of the drafts.
+    fn parse(client: &Client, response: Response) -> reqwest::Result<Self> {
+        if client.is_acme_draft() {
+            Ok(response.json::<DraftDirectory>()?.into())
+        } else {
+            response.json()
+        }
+    }
+
+    // Fetches a fresh nonce from the `newNonce` endpoint.
+    pub fn fetch_nonce(&self, client: &Client) -> Result<Nonce> {
+        Ok(client
+            .head(&self.new_nonce)?
             .headers()
             .get("replay-nonce")
             .ok_or(Error::BadNonce)?
             .to_str()?
-            .to_owned();
-
-        dir_infos.nonce = nonce;
-
-        Ok(dir_infos)
+            .to_owned())
     }
 
     /// Creates a new account.
     pub fn create_account(
         &self,
         client: &Client,
-        p_key: &Rsa<Private>,
-        email: &str,
+        p_key: &PKey<Private>,
+        contacts: &[&str],
     ) -> Result<Account> {
         let jwk = jwk(p_key)?;
         let header = json!({
-            "alg": "RS256",
             "url": self.new_account,
-            "jwk": jwk,
+            "jwk": jwk.clone(),
             "nonce": self.nonce,
         });
 
-        let payload = json!({
-            "termsOfServiceAgreed": true,
-            "contact": [format!("mailto:{}", email)]
+        // an account that's already registered for the key is returned without a contact
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if !contacts.is_empty() {
+            payload["contact"] = json!(contact_urls(contacts)?);
+        }
+        // servers of the early drafts want to know which terms of service were agreed to
+        let terms_of_service = self
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.terms_of_service.as_ref());
+        if let (false, Some(terms_of_service)) = (self.has_orders(), terms_of_service) {
+            payload["agreement"] = json!(terms_of_service);
+        }
+
+        let jws = signer(client, payload, header, p_key);
+
+        let response = client.post_signed(&self.new_account, jws)?;
+        // they also answer the registration of a key that already has an account with a
+        // conflict and the location of the account
+        let existing = match response.headers().get("location") {
+            Some(location) if !self.has_orders() && response.status() == StatusCode::CONFLICT => {
+                Some(location.to_str()?.to_owned())
+            }
+            _ => None,
+        };
+        let (location, nonce, mut account): (String, Nonce, Account) = match existing {
+            Some(location) => {
+                let nonce = replay_nonce(&response)?;
+                let (nonce, account) = self.fetch_registration(client, p_key, &location, nonce)?;
+                (location, nonce, account)
+            }
+            None => {
+                // not every CA accepts every key type for accounts, e.g. Let's Encrypt
+                // rejects Ed25519
+                let response = check_problem(response, |problem_type| {
+                    (problem_type == "urn:ietf:params:acme:error:badSignatureAlgorithm")
+                        .then_some(Error::BadSignatureAlgorithm)
+                })?;
+                extract_payload_location_and_nonce(response)?
+            }
+        };
+        if !self.has_orders() {
+            client.set_resource(&location, "reg");
+        }
+
+        account.nonce = nonce;
+        account.account_location = location;
+        account.key_info = key_info(p_key)?;
+        account.public_jwk = jwk;
+        account.check_active()?;
+
+        Ok(account)
+    }
+
+    // Fetches the account of the key from a server of the early ACME drafts, which calls it a
+    // registration.
+    fn fetch_registration(
+        &self,
+        client: &Client,
+        p_key: &PKey<Private>,
+        location: &str,
+        nonce: Nonce,
+    ) -> Result<(Nonce, Account)> {
+        client.set_resource(location, "reg");
+        let header = json!({
+            "url": location,
+            "kid": location,
+            "nonce": nonce,
+        });
+
+        let jws = signer(client, json!({}), header, p_key);
+
+        let response = check_problem(client.post_signed(location, jws)?, |_| None)?;
+
+        extract_payload_and_nonce(response)
+    }
+
+    // Looks up the account that's already registered for the key without creating one.
+    // Fails with `AccountDoesNotExist` if there is none.
+    pub fn find_account(&self, client: &Client, p_key: &PKey<Private>) -> Result<Account> {
+        let jwk = jwk(p_key)?;
+        let header = json!({
+            "url": self.new_account,
+            "jwk": jwk.clone(),
+            "nonce": self.nonce,
         });
 
-        let payload = jws(payload, header, p_key)?;
+        let payload = json!({ "onlyReturnExisting": true });
 
-        let response = client
-            .post(&self.new_account)
-            .header("Content-Type", "application/jose+json")
-            .body(serde_json::to_string_pretty(&payload)?)
-            .send()?;
+        let jws = signer(client, payload, header, p_key);
+
+        let response = client.post_signed(&self.new_account, jws)?;
+        let response = check_problem(response, |problem_type| match problem_type {
+            "urn:ietf:params:acme:error:accountDoesNotExist" => Some(Error::AccountDoesNotExist),
+            _ => None,
+        })?;
 
         let (location, nonce, mut account): (String, Nonce, Account) =
             extract_payload_location_and_nonce(response)?;
 
         account.nonce = nonce;
         account.account_location = location;
+        account.key_info = key_info(p_key)?;
+        account.public_jwk = jwk;
+        account.check_active()?;
 
         Ok(account)
     }
+
+    // Revokes the leaf of a PEM certificate chain, optionally with one of the reason codes
+    // of RFC 5280, e.g. 1 for a compromised key.
+    pub fn revoke_certificate(
+        &self,
+        client: &Client,
+        certificate: &str,
+        reason: Option<u32>,
+        key: RevocationKey,
+    ) -> Result<()> {
+        let (header, p_key) = match key {
+            RevocationKey::Account { p_key, account_url } => (
+                json!({
+                    "url": self.revoke_cert,
+                    "kid": account_url,
+                    "nonce": self.fetch_nonce(client)?,
+                }),
+                p_key,
+            ),
+            RevocationKey::Certificate(p_key) => (
+                json!({
+                    "url": self.revoke_cert,
+                    "jwk": jwk(p_key)?,
+                    "nonce": self.fetch_nonce(client)?,
+                }),
+                p_key,
+            ),
+        };
+
+        let leaf = X509::from_pem(certificate.as_bytes())?;
+        let mut payload = json!({ "certificate": b64(&leaf.to_der()?) });
+        if let Some(reason) = reason {
+            payload["reason"] = json!(reason);
+        }
+
+        let jws = signer(client, payload, header, p_key);
+
+        let response = client.post_signed(&self.revoke_cert, jws)?;
+        check_problem(response, |problem_type| match problem_type {
+            "urn:ietf:params:acme:error:alreadyRevoked" => Some(Error::AlreadyRevokedCertificate),
+            "urn:ietf:params:acme:error:badRevocationReason" => Some(Error::BadRevocationReason),
+            "urn:ietf:params:acme:error:unauthorized" => Some(Error::Unauthorized),
+            _ => None,
+        })?;
+
+        Ok(())
+    }
+}
+
+// The key that signs a revocation request.
+#[derive(Clone, Copy, Debug)]
+pub enum RevocationKey<'a> {
+    // The key of an account that's authorized for the domains of the certificate.
+    Account {
+        p_key: &'a PKey<Private>,
+        account_url: &'a str,
+    },
+    // The key of the certificate itself, e.g. when the account key got lost.
+    Certificate(&'a PKey<Private>),
+}
+
+// The contact URL schemes that are passed to the server.
+const CONTACT_SCHEMES: &[&str] = &["mailto", "tel"];
+
+// Turns the contacts into URLs. Every contact is checked, so all the invalid ones are
+// reported at once instead of only the first.
+pub fn contact_urls(contacts: &[&str]) -> Result<Vec<String>> {
+    let mut urls = Vec::new();
+    let mut invalid = Vec::new();
+    for contact in contacts {
+        match contact_url(contact) {
+            Ok(url) => urls.push(url),
+            Err(e) => invalid.push((contact.to_string(), e)),
+        }
+    }
+
+    if invalid.is_empty() {
+        Ok(urls)
+    } else {
+        Err(Error::InvalidContacts(invalid))
+    }
+}
+
+// Turns a contact into a URL, bare email addresses get the `mailto:` scheme. Fails with
+// `UnsupportedContact` for schemes outside of `CONTACT_SCHEMES`.
+fn contact_url(contact: &str) -> Result<String> {
+    match contact.split_once(':') {
+        Some((scheme, address)) if !address.is_empty() => {
+            let scheme = scheme.to_ascii_lowercase();
+            if !CONTACT_SCHEMES.contains(&scheme.as_str()) {
+                return Err(Error::UnsupportedContact);
+            }
+            if scheme == "mailto" && !is_email_address(address) {
+                return Err(Error::InvalidContact);
+            }
+            Ok(contact.to_owned())
+        }
+        Some(_) => Err(Error::InvalidContact),
+        None if is_email_address(contact) => Ok(format!("mailto:{}", contact)),
+        None => Err(Error::InvalidContact),
+    }
+}
+
+// Catches the typos of an email address, a local part and a domain with a dot in it.
+fn is_email_address(address: &str) -> bool {
+    match address.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty() && domain.contains('.') && to_ascii_domain(domain).is_ok()
+        }
+        None => false,
+    }
 }
 
 // A struct that holds information about an Account.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Account {
+    // registrations of the early ACME drafts have no status
+    #[serde(default)]
     pub status: String,
     contact: Option<Vec<String>>,
     terms_of_service_agreed: Option<bool>,
-    pub orders: Option<Vec<String>>,
+    // the URL of the list of the account's orders, which not every CA offers
+    pub orders: Option<String>,
     #[serde(skip)]
     pub nonce: Nonce,
     #[serde(skip)]
     pub account_location: String,
+    #[serde(skip)]
+    key_info: KeyInfo,
+    #[serde(skip)]
+    public_jwk: serde_json::Value,
+    // the unknown fields, see `Directory::extra`
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Account {
-    // Creates a new order for issuing a dns certificate for a certain domain.
-    pub fn create_new_order(
-        &self,
+    // The JWK thumbprint of the account key, computed once when the account is created and
+    // shared by the key authorisations of all challenges.
+    pub fn thumbprint(&self) -> &str {
+        &self.key_info.thumbprint
+    }
+
+    // The algorithm, size and thumbprint of the account key.
+    pub fn key_info(&self) -> &KeyInfo {
+        &self.key_info
+    }
+
+    // The public JWK of the account key, e.g. for a central system that registers the
+    // accounts of its agents out of band.
+    pub fn public_jwk(&self) -> &serde_json::Value {
+        &self.public_jwk
+    }
+
+    // Authorizes the account for an identifier at a server of the early ACME drafts, which
+    // have no orders. Returns the authorisation whose challenges prove the control of it.
+    pub fn new_authz(
+        &mut self,
         client: &Client,
-        new_order_url: &str,
-        p_key: &Rsa<Private>,
-        domain: &str,
-        optional_csr: Option<X509Req>,
-    ) -> Result<Order> {
+        new_authz_url: &str,
+        p_key: &PKey<Private>,
+        identifier: &str,
+    ) -> Result<ChallengeAuthorisation> {
         let header = json!({
-            "alg": "RS256",
-            "url": new_order_url,
+            "url": new_authz_url,
             "kid": self.account_location,
             "nonce": self.nonce,
         });
 
         let payload = json!({
-            "identifiers": [
-                { "type": "dns", "value": domain }
-            ],
+            "identifier": { "type": identifier_type(identifier), "value": identifier },
         });
 
-        let payload = jws(payload, header, p_key)?;
+        let jws = signer(client, payload, header, p_key);
 
-        let response = client
-            .post(new_order_url)
-            .header("Content-Type", "application/jose+json")
-            .body(serde_json::to_string_pretty(&payload)?)
-            .send()?;
+        let response = check_problem(client.post_signed(new_authz_url, jws)?, |_| None)?;
 
-        let (nonce, mut order): (Nonce, Order) = extract_payload_and_nonce(response)?;
-        order.nonce = nonce;
-        order.optional_csr = optional_csr;
+        let (location, nonce, authorisation): (String, Nonce, serde_json::Value) =
+            extract_payload_location_and_nonce(response)?;
 
-        Ok(order)
-    }
-}
+        let mut authorisation =
+            ChallengeAuthorisation::from_draft(client, &location, authorisation)?;
+        authorisation.nonce = nonce.clone();
+        self.nonce = nonce;
 
-// Holds information about an order.
-#[derive(Serialize, Deserialize)]
-pub struct Order {
-    pub status: String,
-    pub expires: String,
-    pub identifiers: serde_json::Value,
-    pub authorizations: Vec<String>,
-    pub finalize: String,
-    #[serde(skip)]
-    pub nonce: Nonce,
-    #[serde(skip)]
-    optional_csr: Option<X509Req>,
-}
+        Ok(authorisation)
+    }
 
-impl Order {
-    // Fetches the available authorisation options from the server for a certain order.
-    pub fn fetch_auth_challenges(
+    // Requests the certificate for a CSR at a server of the early ACME drafts once the
+    // account is authorized for all its identifiers. The server returns the leaf right away
+    // or at the location it answers with once it's issued, and links its issuers.
+    pub fn new_cert(
         &self,
         client: &Client,
-        account_url: &str,
-        p_key: &Rsa<Private>,
-    ) -> Result<ChallengeAuthorisation> {
-        let auth_url = self
-            .authorizations
-            .first()
-            .ok_or(Error::NoHttpChallengePresent)?
-            .to_string();
+        new_cert_url: &str,
+        nonce: Nonce,
+        p_key: &PKey<Private>,
+        csr: &X509Req,
+    ) -> Result<Certificate> {
+        let header = json!({
+            "url": new_cert_url,
+            "kid": self.account_location,
+            "nonce": nonce,
+        });
+
+        let payload = json!({ "csr": b64(&csr.to_der()?) });
+
+        let jws = signer(client, payload, header, p_key);
+
+        let response = client.post_signed(new_cert_url, jws)?;
+        let mut response = check_problem(response, |problem_type| match problem_type {
+            "urn:acme:error:badCSR" => Some(Error::BadCSR),
+            _ => None,
+        })?;
+        let location = response
+            .headers()
+            .get("location")
+            .map(|location| location.to_str().map(str::to_owned))
+            .transpose()?;
+
+        for attempt in 0..=client.retry_policy().max_retries {
+            let issuers = links_with_rel(&response, "up");
+            let delay = client
+                .retry_policy()
+                .orders
+                .delay(attempt, retry_after(&response));
+            let body = response.bytes()?;
+            if !body.is_empty() {
+                let leaf = X509::from_der(&body).or_else(|_| X509::from_pem(&body))?;
+                let chain = std::str::from_utf8(&leaf.to_pem()?)?.to_owned();
+                return normalize_chain(&follow_up_links(client, chain, &leaf, issuers)?);
+            }
+            if attempt == client.retry_policy().max_retries {
+                break;
+            }
+
+            client.sleep(delay)?;
+            let location = location.as_deref().ok_or(Error::IncorrectResponse)?;
+            response = check_problem(client.get(location)?, |_| None)?;
+        }
+
+        Err(Error::OrderNotReady)
+    }
 
+    // Creates a new order for issuing a dns certificate for the given domains, optionally
+    // selecting a certificate profile (e.g. `shortlived`).
+    pub fn create_new_order(
+        &self,
+        client: &Client,
+        new_order_url: &str,
+        p_key: &PKey<Private>,
+        domains: &[&str],
+        optional_csr: Option<X509Req>,
+        profile: Option<&str>,
+    ) -> Result<Order> {
         let header = json!({
-            "alg": "RS256",
-            "url": auth_url,
-            "kid": account_url,
+            "url": new_order_url,
+            "kid": self.account_location,
             "nonce": self.nonce,
         });
 
-        let payload = json!("");
+        // internationalized domains are ordered by their A-label
+        let identifiers = domains
+            .iter()
+            .map(|domain| {
+                Ok(json!({
+                    "type": identifier_type(domain),
+                    "value": to_ascii_domain(domain)?,
+                }))
+            })
+            .collect::<Result<Vec<serde_json::Value>>>()?;
+        let mut payload = json!({ "identifiers": identifiers });
+        if let Some(profile) = profile {
+            payload["profile"] = json!(profile);
+        }
 
-        let jws = jws(payload, header, p_key)?;
+        let jws = signer(client, payload, header, p_key);
 
-        let response = client
-            .post(&auth_url)
-            .header("Content-Type", "application/jose+json")
-            .body(serde_json::to_string_pretty(&jws)?)
-            .send()?;
+        let response = client.post_signed(new_order_url, jws)?;
 
-        let (nonce, mut challenge): (Nonce, ChallengeAuthorisation) =
-            extract_payload_and_nonce(response)?;
+        // the CA rejects orders until the account agreed to changed terms of service
+        let response = check_problem(response, |problem_type| match problem_type {
+            "urn:ietf:params:acme:error:userActionRequired" => Some(Error::UserActionRequired),
+            _ => None,
+        })?;
 
-        challenge.nonce = nonce;
+        let (location, nonce, mut order): (String, Nonce, Order) =
+            extract_payload_location_and_nonce(response)?;
+        order.nonce = nonce;
+        order.order_location = location;
+        order.optional_csr = optional_csr;
 
-        Ok(challenge)
+        Ok(order)
     }
 
-    // Finalizes an order whose challenge was already done. 
-    pub fn finalize_order(
-        self,
-        client: &Client,
-        account_url: &str,
-        new_nonce: Nonce,
-        p_key: &Rsa<Private>,
-        cert_keypair: &(Rsa<Private>, Rsa<Public>),
-        domain: &str,
-    ) -> Result<UpdatedOrder> {
+    // Agrees to the current terms of service of the CA, e.g. after they changed and new
+    // orders fail with `UserActionRequired`.
+    pub fn agree_to_terms(&mut self, client: &Client, p_key: &PKey<Private>) -> Result<()> {
         let header = json!({
-        "alg": "RS256",
-        "url": self.finalize,
-        "kid": account_url,
-        "nonce": new_nonce,
+            "url": self.account_location,
+            "kid": self.account_location,
+            "nonce": self.nonce,
         });
 
-        let csr = if let Some(csr) = self.optional_csr {
-            csr
-        } else {
-            Order::request_csr(cert_keypair, domain.to_owned())?
-        };
+        let payload = json!({ "termsOfServiceAgreed": true });
 
-        let csr_string = b64(&csr.to_der()?);
+        let jws = signer(client, payload, header, p_key);
 
-        let payload = json!({ "csr": csr_string });
+        let response = client.post_signed(&self.account_location, jws)?;
 
-        let jws = jws(payload, header, p_key)?;
+        let (nonce, account): (Nonce, Account) = extract_payload_and_nonce(response)?;
+        self.nonce = nonce;
+        self.status = account.status;
 
-        let response = client
-            .post(&self.finalize)
-            .header("Content-Type", "application/jose+json")
-            .header("Accept", "application/pem-certificate-chain")
-            .body(serde_json::to_string_pretty(&jws)?)
-            .send()?;
+        self.check_active()
+    }
 
-        let (nonce, mut updated_order): (Nonce, UpdatedOrder) =
+    // Confirms that the key controls the account by fetching it with a POST-as-GET, the
+    // server checks the signature against the key of the account. Fails with
+    // `Unauthorized` if the key doesn't belong to the account.
+    pub fn verify(&mut self, client: &Client, p_key: &PKey<Private>) -> Result<()> {
+        let header = json!({
+            "url": self.account_location,
+            "kid": self.account_location,
+            "nonce": self.nonce,
+        });
+
+        let payload = json!("");
+
+        let jws = signer(client, payload, header, p_key);
+
+        let response = client.post_signed(&self.account_location, jws)?;
+        if matches!(
+            response.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        ) {
+            return with_request_id(Err(Error::Unauthorized), request_id(&response));
+        }
+
+        let (nonce, account): (Nonce, Account) = extract_payload_and_nonce(response)?;
+        self.nonce = nonce;
+        self.status = account.status;
+
+        self.check_active()
+    }
+
+    // Fails with `AccountDeactivated` if the account was deactivated by its owner or revoked
+    // by the CA, every further request would fail.
+    pub fn check_active(&self) -> Result<()> {
+        match self.status.as_str() {
+            "deactivated" | "revoked" => Err(Error::AccountDeactivated(self.status.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    // Fetches an existing order, e.g. to resume an order that was interrupted.
+    pub fn fetch_order(
+        &self,
+        client: &Client,
+        order_url: &str,
+        p_key: &PKey<Private>,
+    ) -> Result<Order> {
+        let header = json!({
+            "url": order_url,
+            "kid": self.account_location,
+            "nonce": self.nonce,
+        });
+
+        let payload = json!("");
+
+        let jws = signer(client, payload, header, p_key);
+
+        let response = client.post_signed(order_url, jws)?;
+
+        let (nonce, mut order): (Nonce, Order) = extract_payload_and_nonce(response)?;
+        order.nonce = nonce;
+        order.order_location = order_url.to_owned();
+
+        Ok(order)
+    }
+
+    // The URLs of the orders of the account, following the pages of the list. Without a
+    // list, e.g. at Let's Encrypt, there are none.
+    pub fn list_orders(&mut self, client: &Client, p_key: &PKey<Private>) -> Result<Vec<String>> {
+        let mut orders = Vec::new();
+        let mut next = self.orders.clone();
+        while let Some(url) = next {
+            let header = json!({
+                "url": url,
+                "kid": self.account_location,
+                "nonce": self.nonce,
+            });
+
+            let jws = signer(client, json!(""), header, p_key);
+
+            let response = check_problem(client.post_signed(&url, jws)?, |_| None)?;
+            next = links_with_rel(&response, "next").into_iter().next();
+
+            let (nonce, page): (Nonce, OrderList) = extract_payload_and_nonce(response)?;
+            self.nonce = nonce;
+            orders.extend(page.orders);
+        }
+
+        Ok(orders)
+    }
+
+    // An order of the account for exactly the domains and the profile that can still be
+    // finalized, e.g. the one an earlier attempt opened before its response got lost, so a
+    // retry can reuse it instead of opening a duplicate that counts against the limits.
+    pub fn find_open_order(
+        &mut self,
+        client: &Client,
+        p_key: &PKey<Private>,
+        domains: &[&str],
+        profile: Option<&str>,
+    ) -> Result<Option<Order>> {
+        for order_url in self.list_orders(client, p_key)? {
+            let order = self.fetch_order(client, &order_url, p_key)?;
+            self.nonce = order.nonce.clone();
+
+            let order_profile = order.extra.get("profile").and_then(|p| p.as_str());
+            if !order.is_expired(client.clock())
+                && (order.status == "pending" || order.status == "ready")
+                && order.has_identifiers(domains)
+                && order_profile.is_none_or(|order_profile| Some(order_profile) == profile)
+            {
+                return Ok(Some(order));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+// A page of the list of orders of an account.
+#[derive(Deserialize)]
+struct OrderList {
+    orders: Vec<String>,
+}
+
+// Holds information about an order. It can be persisted as JSON to continue the issuance
+// in another process: the URLs and the CSR stay valid, the nonce is never persisted and a
+// fresh one is fetched before the next request.
+#[derive(Serialize, Deserialize)]
+pub struct Order {
+    pub status: String,
+    pub expires: Option<DateTime<Utc>>,
+    pub identifiers: serde_json::Value,
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    #[serde(skip)]
+    pub nonce: Nonce,
+    // not part of the server's response, only of the persisted order
+    #[serde(default)]
+    pub order_location: String,
+    #[serde(default, with = "csr_pem", skip_serializing_if = "Option::is_none")]
+    pub(crate) optional_csr: Option<X509Req>,
+    // the unknown fields, see `Directory::extra`
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+// Persists the CSR of an order as PEM.
+mod csr_pem {
+    use openssl::x509::X509Req;
+    use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        csr: &Option<X509Req>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let pem = match csr {
+            Some(csr) => Some(
+                String::from_utf8(csr.to_pem().map_err(ser::Error::custom)?)
+                    .map_err(ser::Error::custom)?,
+            ),
+            None => None,
+        };
+        pem.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<X509Req>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|pem| X509Req::from_pem(pem.as_bytes()).map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+impl Order {
+    // Whether the order (and with it its authorizations) has already expired.
+    pub fn is_expired(&self, clock: &dyn Clock) -> bool {
+        self.expires.is_some_and(|expires| expires <= clock.now())
+    }
+
+    // Fetches the available authorisation options from the server for a certain order.
+    pub fn fetch_auth_challenges(
+        &self,
+        client: &Client,
+        account_url: &str,
+        p_key: &PKey<Private>,
+    ) -> Result<ChallengeAuthorisation> {
+        let auth_url = self.authorizations.first().ok_or(Error::NoAuthorizations)?;
+
+        ChallengeAuthorisation::fetch(client, auth_url, account_url, self.nonce.clone(), p_key)
+    }
+
+    // Fetches the authorisations for all identifiers of the order.
+    pub fn fetch_all_auth_challenges(
+        &self,
+        client: &Client,
+        account_url: &str,
+        p_key: &PKey<Private>,
+    ) -> Result<Vec<ChallengeAuthorisation>> {
+        let mut nonce = self.nonce.clone();
+        let mut authorisations = Vec::with_capacity(self.authorizations.len());
+
+        for auth_url in &self.authorizations {
+            let authorisation =
+                ChallengeAuthorisation::fetch(client, auth_url, account_url, nonce, p_key)?;
+            nonce = authorisation.nonce.clone();
+            authorisations.push(authorisation);
+        }
+
+        Ok(authorisations)
+    }
+
+    // Fetches the authorisations for all identifiers of the order with the name each one is
+    // for, e.g. to tell which TXT record belongs to which domain. They stay in the order
+    // they were fetched in, so the nonce of the last one is the one for the next request.
+    pub fn fetch_auth_challenges_by_domain(
+        &self,
+        client: &Client,
+        account_url: &str,
+        p_key: &PKey<Private>,
+    ) -> Result<Vec<(String, ChallengeAuthorisation)>> {
+        self.fetch_all_auth_challenges(client, account_url, p_key)?
+            .into_iter()
+            .map(|authorisation| Ok((authorisation.domain()?, authorisation)))
+            .collect()
+    }
+
+    // Abandons the order, e.g. when it was opened for the wrong domains, by deactivating
+    // its pending authorizations so they stop counting against the CA's limits. Returns
+    // the nonce for the next request.
+    pub fn abandon(
+        &self,
+        client: &Client,
+        account_url: &str,
+        p_key: &PKey<Private>,
+    ) -> Result<Nonce> {
+        let mut nonce = self.nonce.clone();
+
+        for auth_url in &self.authorizations {
+            let authorisation =
+                ChallengeAuthorisation::fetch(client, auth_url, account_url, nonce, p_key)?;
+            nonce = match authorisation.status {
+                StatusType::Pending => authorisation.deactivate(client, account_url, p_key)?,
+                _ => authorisation.nonce,
+            };
+        }
+
+        Ok(nonce)
+    }
+
+    // Whether the order was opened for exactly the given identifiers.
+    pub fn has_identifiers(&self, domains: &[&str]) -> bool {
+        let mut identifiers: Vec<&str> = match self.identifiers.as_array() {
+            Some(identifiers) => identifiers
+                .iter()
+                .filter_map(|identifier| identifier["value"].as_str())
+                .collect(),
+            None => return false,
+        };
+        let mut domains = domains.to_vec();
+        identifiers.sort_unstable();
+        identifiers.dedup();
+        domains.sort_unstable();
+        domains.dedup();
+        identifiers == domains
+    }
+
+    // Finalizes an order whose challenge was already done. 
+    #[allow(clippy::too_many_arguments)]
+    pub fn finalize_order(
+        mut self,
+        client: &Client,
+        account_url: &str,
+        new_nonce: Nonce,
+        p_key: &PKey<Private>,
+        cert_keypair: &(PKey<Private>, PKey<Public>),
+        domains: &[&str],
+        csr_digest: CsrDigest,
+        csr_subject: &[SubjectField],
+        csr_extensions: &[CsrExtension],
+    ) -> Result<UpdatedOrder> {
+        let new_nonce = self.poll_until_ready(client, account_url, new_nonce, p_key)?;
+
+        let header = json!({
+        "url": self.finalize,
+        "kid": account_url,
+        "nonce": new_nonce,
+        });
+
+        // the certificate key is the one in the CSR, whatever the type of the account key
+        let csr = if let Some(csr) = self.optional_csr {
+            csr
+        } else {
+            request_csr(
+                cert_keypair,
+                domains,
+                csr_digest,
+                csr_subject,
+                csr_extensions,
+            )?
+        };
+        let cert_key_id = csr.public_key()?.id();
+
+        let csr_string = b64(&csr.to_der()?);
+
+        let payload = json!({ "csr": csr_string });
+
+        let jws = signer(client, payload, header, p_key);
+
+        let response = client.post_signed(&self.finalize, jws)?;
+
+        // not every CA issues certificates for every key type, so make a rejection obvious
+        let response = check_problem(response, |problem_type| match problem_type {
+            "urn:ietf:params:acme:error:badSignatureAlgorithm" => {
+                Some(Error::BadSignatureAlgorithm)
+            }
+            "urn:ietf:params:acme:error:badCSR" if cert_key_id == Id::ED25519 => {
+                Some(Error::BadSignatureAlgorithm)
+            }
+            "urn:ietf:params:acme:error:badCSR" => Some(Error::BadCSR),
+            _ => None,
+        })?;
+
+        let (nonce, mut updated_order): (Nonce, UpdatedOrder) =
             extract_payload_and_nonce(response)?;
 
         updated_order.nonce = nonce;
+        updated_order.order_location = self.order_location;
+
+        // some CAs issue the certificate right away, the others are polled until they did
+        if !updated_order.is_issued() {
+            updated_order.poll_certificate_url(client, account_url, p_key)?;
+        }
 
         Ok(updated_order)
     }
 
-    // Factors a csr request, which needs to be sent during finalization.
-    fn request_csr(keypair: &(Rsa<Private>, Rsa<Public>), common_name: String) -> Result<X509Req> {
-        let mut request = X509ReqBuilder::new()?;
-        let mut c_name = X509NameBuilder::new()?;
+    // Polls the order while it's still `pending`, which it can briefly be after the last
+    // challenge was validated. Fails with `OrderNotReady` if it's still pending after the
+    // first poll and `max_retries` more.
+    fn poll_until_ready(
+        &mut self,
+        client: &Client,
+        account_url: &str,
+        mut nonce: Nonce,
+        p_key: &PKey<Private>,
+    ) -> Result<Nonce> {
+        for attempt in 0..=client.retry_policy().max_retries {
+            if self.status != "pending" {
+                return Ok(nonce);
+            }
+            client.check_cancelled()?;
+
+            let header = json!({
+                "url": self.order_location,
+                "kid": account_url,
+                "nonce": nonce,
+            });
+            let payload = json!("");
+
+            let jws = signer(client, payload, header, p_key);
 
-        let pri_key = &openssl::pkey::PKey::private_key_from_pem(&keypair.0.private_key_to_pem()?)?;
-        let public_key =
-            &openssl::pkey::PKey::public_key_from_pem(&keypair.1.public_key_to_pem()?)?;
+            let response = client.post_signed(&self.order_location, jws)?;
 
-        c_name.append_entry_by_nid(Nid::COMMONNAME, &common_name)?;
-        let name = c_name.build();
-        request.set_pubkey(public_key)?;
-        request.set_subject_name(name.as_ref())?;
-        request.sign(pri_key, MessageDigest::sha256())?;
+            let delay = client
+                .retry_policy()
+                .orders
+                .delay(attempt, retry_after(&response));
+            let (new_nonce, order): (Nonce, Order) = extract_payload_and_nonce(response)?;
+
+            nonce = new_nonce;
+            self.status = order.status;
+            // there's no point in waiting once the last poll is done
+            if self.status == "pending" && attempt < client.retry_policy().max_retries {
+                client.sleep(delay)?;
+            }
+        }
 
-        Ok(request.build())
+        if self.status == "pending" {
+            Err(Error::OrderNotReady)
+        } else {
+            Ok(nonce)
+        }
     }
 }
 
@@ -286,6 +1335,7 @@ impl Debug for Order {
             .field("authorizations", &self.authorizations)
             .field("finalize", &self.finalize)
             .field("nonce", &self.nonce)
+            .field("order_location", &self.order_location)
             .field(
                 "optional_csr",
                 if self.optional_csr.is_some() {
@@ -306,9 +1356,13 @@ pub struct Challenge {
     #[serde(rename = "type")]
     pub challenge_type: String,
     pub url: String,
+    // the problem document of a failed validation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
 }
 
-// Holds information about the authentification options.
+// Holds information about the authentification options. Like an `Order` it can be
+// persisted, everything but the nonce is kept.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChallengeAuthorisation {
     // type, value
@@ -319,154 +1373,1822 @@ pub struct ChallengeAuthorisation {
     pub wildcard: Option<bool>,
     #[serde(skip)]
     pub nonce: Nonce,
+    // how long the server asked to wait before the next poll, if it did
+    #[serde(skip)]
+    pub retry_after: Option<Duration>,
+    // not part of the server's response, only of the persisted authorisation
+    #[serde(default)]
+    pub url: String,
 }
 
 impl ChallengeAuthorisation {
+    // The type of the identifier, `dns` or `ip`.
+    pub fn identifier_type(&self) -> &str {
+        self.identifier["type"].as_str().unwrap_or("dns")
+    }
+
+    // The domain name or IP address the authorisation is for.
+    pub fn identifier_value(&self) -> Result<&str> {
+        self.identifier["value"]
+            .as_str()
+            .ok_or(Error::IncorrectResponse)
+    }
+
+    // The name as it was ordered, i.e. with the `*.` of a wildcard that the identifier lacks.
+    pub fn domain(&self) -> Result<String> {
+        let value = self.identifier_value()?;
+        Ok(match self.wildcard {
+            Some(true) => format!("*.{}", value),
+            _ => value.to_owned(),
+        })
+    }
+
+    // Returns the types of all challenges the server offered for this authorisation.
+    pub fn challenge_types(&self) -> Vec<&str> {
+        self.challenges
+            .iter()
+            .map(|challenge| challenge.challenge_type.as_str())
+            .collect()
+    }
+
+    // Finds the offered challenge of a certain type, e.g. `http-01`.
+    pub fn challenge(&self, challenge_type: &str) -> Option<&Challenge> {
+        self.challenges
+            .iter()
+            .find(|challenge| challenge.challenge_type == challenge_type)
+    }
+
+    // Fetches an authorisation from the server.
+    pub fn fetch(
+        client: &Client,
+        auth_url: &str,
+        account_url: &str,
+        nonce: Nonce,
+        p_key: &PKey<Private>,
+    ) -> Result<ChallengeAuthorisation> {
+        // servers of the ACME drafts predate POST-as-GET
+        if client.is_acme_draft() {
+            let response = check_problem(client.get(auth_url)?, |_| None)?;
+            let retry_after = retry_after(&response);
+            let (nonce, authorisation) = extract_payload_and_nonce(response)?;
+            let mut authorisation =
+                ChallengeAuthorisation::from_draft(client, auth_url, authorisation)?;
+            authorisation.nonce = nonce;
+            authorisation.retry_after = retry_after;
+            return Ok(authorisation);
+        }
+
+        let header = json!({
+            "url": auth_url,
+            "kid": account_url,
+            "nonce": nonce,
+        });
+
+        let payload = json!("");
+
+        let jws = signer(client, payload, header, p_key);
+
+        let response = client.post_signed(auth_url, jws)?;
+
+        let retry_after = retry_after(&response);
+        let (nonce, mut challenge): (Nonce, ChallengeAuthorisation) =
+            extract_payload_and_nonce(response)?;
+
+        challenge.nonce = nonce;
+        challenge.retry_after = retry_after;
+        challenge.url = auth_url.to_owned();
+
+        Ok(challenge)
+    }
+
+    // Reads an authorisation of a server of the ACME drafts, whose challenges may have a `uri`
+    // instead of a `url`, and remembers the resources of it and its challenges.
+    fn from_draft(
+        client: &Client,
+        auth_url: &str,
+        mut authorisation: serde_json::Value,
+    ) -> Result<ChallengeAuthorisation> {
+        for challenge in authorisation["challenges"]
+            .as_array_mut()
+            .into_iter()
+            .flatten()
+        {
+            if let Some(uri) = challenge.get("uri").cloned() {
+                challenge["url"] = uri;
+            }
+        }
+        let mut authorisation: ChallengeAuthorisation = serde_json::from_value(authorisation)?;
+
+        authorisation.url = auth_url.to_owned();
+        client.set_resource(auth_url, "authz");
+        for challenge in &authorisation.challenges {
+            client.set_resource(&challenge.url, "challenge");
+        }
+
+        Ok(authorisation)
+    }
+
+    // Deactivates the authorisation, after which the server no longer accepts responses
+    // to its challenges. Returns the nonce for the next request.
+    pub fn deactivate(
+        &self,
+        client: &Client,
+        account_url: &str,
+        p_key: &PKey<Private>,
+    ) -> Result<Nonce> {
+        let header = json!({
+            "url": self.url,
+            "kid": account_url,
+            "nonce": self.nonce,
+        });
+
+        let payload = json!({
+            "status": "deactivated",
+        });
+
+        let jws = signer(client, payload, header, p_key);
+
+        let response = client.post_signed(&self.url, jws)?;
+
+        let (nonce, _): (Nonce, ChallengeAuthorisation) = extract_payload_and_nonce(response)?;
+
+        Ok(nonce)
+    }
+
     // Fetches the available authorisation options from the server for a certain order.
     pub fn solve_http_challenge(
         self,
         client: &Client,
-        account_url: &str,
-        p_key: &Rsa<Private>,
+        account: &Account,
+        p_key: &PKey<Private>,
         standalone: bool,
     ) -> Result<Nonce> {
-        let http_challenge = self
-            .challenges
-            .into_iter()
-            .find(|challenge| challenge.challenge_type == "http-01")
-            .ok_or(Error::NoHttpChallengePresent)?;
+        ChallengeAuthorisation::solve_http_challenges(
+            vec![self],
+            client,
+            account,
+            p_key,
+            standalone,
+        )
+    }
 
-        ChallengeAuthorisation::complete_challenge(
+    // Solves the http challenges of all authorisations of an order at once.
+    pub fn solve_http_challenges(
+        authorisations: Vec<ChallengeAuthorisation>,
+        client: &Client,
+        account: &Account,
+        p_key: &PKey<Private>,
+        standalone: bool,
+    ) -> Result<Nonce> {
+        ChallengeAuthorisation::solve_challenges(
+            authorisations,
+            ChallengeStrategy::Only(ChallengeType::Http01),
             client,
-            http_challenge,
-            self.nonce,
-            account_url,
+            account,
             p_key,
             standalone,
+            None,
+            &Presentation::Automatic,
         )
     }
 
-    // Completes a challenge.
-    fn complete_challenge(
+    // Solves the challenges of all authorisations of an order at once. The challenge type of
+    // each authorisation is picked by the strategy and its identifier type, falling back to
+    // the next candidate if one can't be presented.
+    // The challenges are presented first (by a single standalone server, in the web root,
+    // handed to an external web server through `challenge_socket`, by the user or out of
+    // band, see `Presentation`), then all of them are triggered and finally polled until the
+    // server validated them. Authorisations the server already considers valid are skipped.
+    #[allow(clippy::too_many_arguments)]
+    pub fn solve_challenges(
+        authorisations: Vec<ChallengeAuthorisation>,
+        strategy: ChallengeStrategy,
         client: &Client,
-        challenge_infos: Challenge,
-        nonce: Nonce,
-        acc_url: &str,
-        private_key: &Rsa<Private>,
+        account: &Account,
+        p_key: &PKey<Private>,
         standalone: bool,
+        challenge_socket: Option<&Path>,
+        presentation: &Presentation,
     ) -> Result<Nonce> {
-        const CHALLENGE_PATH: &str = "/.well-known/acme-challenge";
+        let nonce = authorisations
+            .last()
+            .map(|authorisation| authorisation.nonce.clone())
+            .ok_or(Error::NoSupportedChallengePresent)?;
+
+        let candidates = match (presentation, strategy) {
+            (Presentation::Automatic, _) => strategy.candidates(standalone),
+            (Presentation::Manual { .. } | Presentation::Hooks { .. }, _) => {
+                strategy.manual_candidates()
+            }
+            (Presentation::PrePlaced { .. }, ChallengeStrategy::Only(challenge_type)) => {
+                vec![challenge_type]
+            }
+            (Presentation::PrePlaced { .. }, ChallengeStrategy::Auto) => {
+                vec![ChallengeType::Http01]
+            }
+        };
 
-        let thumbprint = jwk(private_key)?;
-        let mut hasher = Sha256::new();
-        hasher.update(&thumbprint.to_string().into_bytes());
-        let thumbprint = hasher.finish();
+        // the CA keeps the authorisations a previous run validated, solving them again would
+        // only start the responders for nothing
+        let (valid, mut unsolved): (Vec<&ChallengeAuthorisation>, Vec<&ChallengeAuthorisation>) =
+            authorisations
+                .iter()
+                .partition(|authorisation| matches!(authorisation.status, StatusType::Valid));
+        for authorisation in &valid {
+            info!(
+                "The authorization of {} is already valid, skipping its challenge",
+                authorisation.identifier_value()?
+            );
+        }
+        if unsolved.is_empty() {
+            return Ok(nonce);
+        }
+        let mut presented = Vec::new();
+        let mut responders = Responders(Vec::new());
+        let mut hooked = Hooked {
+            cleanup_hook: match presentation {
+                Presentation::Hooks { cleanup_hook, .. } => cleanup_hook.as_deref(),
+                _ => None,
+            },
+            variables: Vec::new(),
+        };
+        let mut last_error = Error::NoSupportedChallengePresent;
+        // every authorisation gets the first candidate it offers for its identifier, so e.g.
+        // the IP address of a mixed order can be solved with http-01 while its wildcard
+        // domain falls back to dns-01
+        for challenge_type in candidates {
+            client.check_cancelled()?;
+            let (group, rest): (Vec<&ChallengeAuthorisation>, Vec<&ChallengeAuthorisation>) =
+                unsolved.into_iter().partition(|authorisation| {
+                    challenge_type.supports(authorisation.identifier_type())
+                        && authorisation.challenge(challenge_type.as_str()).is_some()
+                });
+            unsolved = rest;
+            if group.is_empty() {
+                continue;
+            }
+            let challenges = group
+                .iter()
+                .filter_map(|authorisation| authorisation.challenge(challenge_type.as_str()))
+                .map(|challenge| {
+                    let key_authorisation = format!("{}.{}", challenge.token, account.thumbprint());
+                    (challenge.clone(), key_authorisation)
+                })
+                .collect::<Vec<(Challenge, String)>>();
+
+            let result = match presentation {
+                Presentation::Automatic => ChallengeAuthorisation::present_challenges(
+                    &group,
+                    &challenges,
+                    challenge_type,
+                    standalone,
+                    challenge_socket,
+                ),
+                Presentation::Manual {
+                    prompt,
+                    wait_for_propagation,
+                } => ChallengeAuthorisation::present_manually(
+                    client,
+                    &group,
+                    &challenges,
+                    challenge_type,
+                    prompt.as_ref(),
+                    *wait_for_propagation,
+                )
+                .map(|_| Vec::new()),
+                Presentation::PrePlaced {
+                    self_check,
+                    grace_period,
+                } => ChallengeAuthorisation::check_pre_placed(
+                    client,
+                    &group,
+                    &challenges,
+                    challenge_type,
+                    *self_check,
+                    *grace_period,
+                )
+                .map(|_| Vec::new()),
+                Presentation::Hooks {
+                    auth_hook,
+                    wait_for_propagation,
+                    ..
+                } => ChallengeAuthorisation::present_with_hook(
+                    client,
+                    &group,
+                    &challenges,
+                    challenge_type,
+                    auth_hook,
+                    *wait_for_propagation,
+                    &mut hooked.variables,
+                )
+                .map(|_| Vec::new()),
+            };
+            match result {
+                Ok(responder) => {
+                    responders.0.extend(responder);
+                    presented.push((group, challenges));
+                }
+                // the next candidate might still be presentable for these authorisations
+                Err(e) => {
+                    last_error = e;
+                    unsolved.extend(group);
+                }
+            }
+        }
 
-        let challenge_content = format!("{}.{}", challenge_infos.token, b64(&thumbprint));
+        let result = if unsolved.is_empty() {
+            ChallengeAuthorisation::validate_challenges(
+                presented,
+                client,
+                &account.account_location,
+                nonce,
+                p_key,
+            )
+        } else {
+            Err(last_error)
+        };
 
-        let result = ChallengeAuthorisation::kick_off_http_challenge(
-            client,
-            challenge_infos.clone(),
-            nonce,
-            acc_url,
-            private_key,
-        )?;
+        drop(responders);
+        drop(hooked);
 
-        if standalone {
-            std::thread::spawn(|| {
-                rouille::start_server("0.0.0.0:80", move |request| {
-                    if request.raw_url() == format!("{}/{}", CHALLENGE_PATH, challenge_infos.token)
-                    {
-                        rouille::Response::text(challenge_content.clone())
-                    } else {
-                        rouille::Response::empty_404()
-                    }
+        result
+    }
+
+    // Triggers all presented challenges and then polls their authorisations until the
+    // server validated them. Every authorisation is polled even if another one failed, so
+    // the error of a partially failed order names each failed identifier.
+    fn validate_challenges(
+        presented: Vec<Presented>,
+        client: &Client,
+        account_url: &str,
+        mut nonce: Nonce,
+        p_key: &PKey<Private>,
+    ) -> Result<Nonce> {
+        for (_, challenges) in &presented {
+            for (challenge, key_authorisation) in challenges {
+                nonce = ChallengeAuthorisation::kick_off_challenge(
+                    client,
+                    challenge.clone(),
+                    key_authorisation,
+                    nonce,
+                    account_url,
+                    p_key,
+                )?;
+            }
+        }
+
+        let authorisations: Vec<&ChallengeAuthorisation> =
+            presented.into_iter().flat_map(|(group, _)| group).collect();
+        let mut failures = Vec::new();
+        for authorisation in &authorisations {
+            match ChallengeAuthorisation::poll_until_valid(
+                client,
+                &authorisation.url,
+                account_url,
+                nonce,
+                p_key,
+            ) {
+                Ok(new_nonce) => nonce = new_nonce,
+                Err(Error::Cancelled) => return Err(Error::Cancelled),
+                Err(e) => {
+                    // the next request fetches a fresh nonce
+                    nonce = Nonce::new();
+                    failures.push((authorisation.identifier_value()?.to_owned(), e));
+                }
+            }
+        }
+
+        match failures.pop() {
+            None => Ok(nonce),
+            // nothing partial about an order for a single identifier
+            Some((_, e)) if authorisations.len() == 1 => Err(e),
+            Some(failure) => {
+                failures.push(failure);
+                Err(Error::ChallengesFailed(failures))
+            }
+        }
+    }
+
+    // Presents the key authorisations in the way the challenge type requires.
+    fn present_challenges(
+        authorisations: &[&ChallengeAuthorisation],
+        challenges: &[(Challenge, String)],
+        challenge_type: ChallengeType,
+        standalone: bool,
+        challenge_socket: Option<&Path>,
+    ) -> Result<Vec<Responder>> {
+        match challenge_type {
+            ChallengeType::Http01 => match challenge_socket {
+                Some(path) => {
+                    ChallengeAuthorisation::hand_off_http_challenges(challenges, path)?;
+                    Ok(Vec::new())
+                }
+                None => ChallengeAuthorisation::present_http_challenges(challenges, standalone),
+            },
+            ChallengeType::TlsAlpn01 => {
+                let mut certificates = HashMap::new();
+                for (authorisation, (_, key_authorisation)) in authorisations.iter().zip(challenges)
+                {
+                    let domain = authorisation.identifier_value()?;
+                    certificates.insert(
+                        domain.to_owned(),
+                        tls_alpn::validation_certificate(domain, key_authorisation)?,
+                    );
+                }
+
+                Ok(vec![tls_alpn::start_responder(certificates)?])
+            }
+            // there's no way to create the records automatically yet
+            ChallengeType::Dns01 => Err(Error::NoSupportedChallengePresent),
+        }
+    }
+
+    // Makes sure the http-01 responses that were placed out of band are served, if asked to
+    // by fetching them the way the CA will. Misses are fetched again until the grace period
+    // is over, a proxy that was just reconfigured may still be reloading.
+    fn check_pre_placed(
+        client: &Client,
+        authorisations: &[&ChallengeAuthorisation],
+        challenges: &[(Challenge, String)],
+        challenge_type: ChallengeType,
+        self_check: bool,
+        grace_period: Duration,
+    ) -> Result<()> {
+        if challenge_type != ChallengeType::Http01 {
+            return Err(Error::NoSupportedChallengePresent);
+        }
+        if !self_check {
+            return Ok(());
+        }
+
+        for (authorisation, (challenge, key_authorisation)) in authorisations.iter().zip(challenges)
+        {
+            let domain = authorisation.identifier_value()?;
+            let url = format!("http://{}{}/{}", domain, CHALLENGE_PATH, challenge.token);
+
+            let deadline = Instant::now() + grace_period;
+            for attempt in 0.. {
+                let served = client.get(&url).and_then(|response| {
+                    Ok(response.status().is_success()
+                        && response.text()?.trim() == key_authorisation)
                 });
+                match served {
+                    Ok(true) => break,
+                    served if Instant::now() >= deadline => {
+                        return served.and(Err(Error::IncorrectResponse))
+                    }
+                    _ => {
+                        info!("{} is not served yet, retrying...", url);
+                        client.sleep(client.retry_policy().self_checks.delay(attempt, None))?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Hands the steps that present the challenges, derived from the real tokens and key
+    // authorisations, to the prompt, which returns once the user has taken them.
+    fn present_manually(
+        client: &Client,
+        authorisations: &[&ChallengeAuthorisation],
+        challenges: &[(Challenge, String)],
+        challenge_type: ChallengeType,
+        prompt: &dyn ManualPrompt,
+        wait_for_propagation: bool,
+    ) -> Result<()> {
+        let mut steps = Vec::new();
+        for (authorisation, (challenge, key_authorisation)) in authorisations.iter().zip(challenges)
+        {
+            let domain = authorisation.identifier_value()?.to_owned();
+
+            steps.push(match challenge_type {
+                ChallengeType::Http01 => ManualStep::ServeFile {
+                    url: format!("http://{}{}/{}", domain, CHALLENGE_PATH, challenge.token),
+                    contents: key_authorisation.clone(),
+                    domain,
+                },
+                ChallengeType::Dns01 => ManualStep::TxtRecord {
+                    name: format!("_acme-challenge.{}", domain),
+                    value: dns_txt_value(key_authorisation),
+                    domain,
+                },
+                ChallengeType::TlsAlpn01 => return Err(Error::NoSupportedChallengePresent),
             });
-        } else if check_for_existing_server() {
-            const WEB_ROOT: &str = "/var/www/html";
+        }
+
+        prompt.present(&steps)?;
+
+        if wait_for_propagation {
+            for step in &steps {
+                if let ManualStep::TxtRecord { name, value, .. } = step {
+                    dns::wait_for_txt_record(client, name, value)?;
+                }
+            }
+        }
 
-            let full_path = Path::new(WEB_ROOT).join(CHALLENGE_PATH);
+        Ok(())
+    }
+
+    // The environment of the hooks of each challenge: the identifier, the challenge type,
+    // the token, the key authorisation and the validation, which is the key authorisation
+    // for http-01 and the value of the TXT record for dns-01.
+    fn hook_variables(
+        authorisations: &[&ChallengeAuthorisation],
+        challenges: &[(Challenge, String)],
+        challenge_type: ChallengeType,
+    ) -> Result<Vec<Vec<(&'static str, String)>>> {
+        authorisations
+            .iter()
+            .zip(challenges)
+            .map(|(authorisation, (challenge, key_authorisation))| {
+                let validation = match challenge_type {
+                    ChallengeType::Http01 => key_authorisation.clone(),
+                    ChallengeType::Dns01 => dns_txt_value(key_authorisation),
+                    ChallengeType::TlsAlpn01 => return Err(Error::NoSupportedChallengePresent),
+                };
+                Ok(vec![
+                    ("ACME_DOMAIN", authorisation.identifier_value()?.to_owned()),
+                    ("ACME_CHALLENGE_TYPE", challenge_type.as_str().to_owned()),
+                    ("ACME_TOKEN", challenge.token.clone()),
+                    ("ACME_KEY_AUTHORIZATION", key_authorisation.clone()),
+                    ("ACME_VALIDATION", validation),
+                ])
+            })
+            .collect()
+    }
+
+    // Runs the auth hook for every challenge, then waits until the dns-01 records it
+    // created are visible if asked to. The environments of the challenges are added to
+    // `hooked` before the hook runs, so the cleanup hook covers every one of them.
+    fn present_with_hook(
+        client: &Client,
+        authorisations: &[&ChallengeAuthorisation],
+        challenges: &[(Challenge, String)],
+        challenge_type: ChallengeType,
+        auth_hook: &str,
+        wait_for_propagation: bool,
+        hooked: &mut Vec<Vec<(&'static str, String)>>,
+    ) -> Result<()> {
+        let variables =
+            ChallengeAuthorisation::hook_variables(authorisations, challenges, challenge_type)?;
+        hooked.extend(variables.iter().cloned());
+        for variables in &variables {
+            run_hook(auth_hook, variables)?;
+        }
+
+        if wait_for_propagation && challenge_type == ChallengeType::Dns01 {
+            for (authorisation, (_, key_authorisation)) in authorisations.iter().zip(challenges) {
+                let name = format!("_acme-challenge.{}", authorisation.identifier_value()?);
+                dns::wait_for_txt_record(client, &name, &dns_txt_value(key_authorisation))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Makes the key authorisations reachable under the challenge path, either through
+    // standalone servers on IPv4 and IPv6 that hold all tokens or as files in the existing
+    // web root.
+    fn present_http_challenges(
+        challenges: &[(Challenge, String)],
+        standalone: bool,
+    ) -> Result<Vec<Responder>> {
+        if standalone {
+            let responses: HashMap<String, String> = challenges
+                .iter()
+                .map(|(challenge, content)| {
+                    (
+                        format!("{}/{}", CHALLENGE_PATH, challenge.token),
+                        content.clone(),
+                    )
+                })
+                .collect();
+
+            let responses = Arc::new(responses);
+            let serve = |address| {
+                let responses = responses.clone();
+                rouille::Server::new(address, move |request| {
+                    // answer health checks of load balancers and uptime monitors on `/`
+                    match request.raw_url() {
+                        "/" => rouille::Response::text("OK"),
+                        url => match responses.get(url) {
+                            Some(content) => rouille::Response::text(content.clone()),
+                            None => rouille::Response::empty_404(),
+                        },
+                    }
+                })
+            };
+            fn io_error_kind(
+                error: &(dyn std::error::Error + Send + Sync + 'static),
+            ) -> Option<io::ErrorKind> {
+                error.downcast_ref::<io::Error>().map(io::Error::kind)
+            }
+            let servers = bind_both_families(80, serve, |error| {
+                io_error_kind(error.as_ref()) == Some(io::ErrorKind::AddrInUse)
+            })
+            .map_err(|error| match io_error_kind(error.as_ref()) {
+                Some(io::ErrorKind::PermissionDenied) => Error::PrivilegedPort(80),
+                _ => Error::NoWebServer,
+            })?;
+
+            Ok(servers
+                .into_iter()
+                .map(|server| server.stoppable())
+                .collect())
+        } else if check_for_existing_server() {
+            let full_path = Path::new(WEB_ROOT).join(CHALLENGE_PATH.trim_start_matches('/'));
             fs::create_dir_all(full_path.clone())?;
-            let mut output = File::create(full_path.join(challenge_infos.token))?;
-            write!(output, "{}", challenge_content)?;
+            for (challenge, content) in challenges {
+                let mut output = File::create(full_path.join(&challenge.token))?;
+                write!(output, "{}", content)?;
+            }
+
+            Ok(Vec::new())
         } else {
-            return Err(Error::NoWebServer);
+            Err(Error::NoWebServer)
+        }
+    }
+
+    // Hands the key authorisations to an external web server, e.g. a sidecar that serves
+    // port 80. Every challenge is written as a `<token> <key authorisation>` line to the
+    // Unix socket, then the web server has to answer with an `OK` line once it serves them.
+    #[cfg(unix)]
+    fn hand_off_http_challenges(challenges: &[(Challenge, String)], path: &Path) -> Result<()> {
+        let mut stream = UnixStream::connect(path)?;
+        for (challenge, key_authorisation) in challenges {
+            writeln!(stream, "{} {}", challenge.token, key_authorisation)?;
+        }
+        stream.shutdown(Shutdown::Write)?;
+
+        stream.set_read_timeout(Some(SOCKET_ACK_TIMEOUT))?;
+        let mut ack = String::new();
+        BufReader::new(stream).read_line(&mut ack)?;
+        if ack.trim().eq_ignore_ascii_case("ok") {
+            Ok(())
+        } else {
+            Err(Error::NoWebServer)
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn hand_off_http_challenges(_: &[(Challenge, String)], _: &Path) -> Result<()> {
+        Err(Error::NoWebServer)
+    }
+
+    // Polls an authorisation until the server validated it. Fails with the reason the server
+    // gives if the validation failed and with `OrderNotReady` if it's still pending after the
+    // first poll and `max_retries` more.
+    fn poll_until_valid(
+        client: &Client,
+        auth_url: &str,
+        account_url: &str,
+        mut nonce: Nonce,
+        p_key: &PKey<Private>,
+    ) -> Result<Nonce> {
+        for attempt in 0..=client.retry_policy().max_retries {
+            client.check_cancelled()?;
+            let authorisation =
+                ChallengeAuthorisation::fetch(client, auth_url, account_url, nonce, p_key)?;
+            nonce = authorisation.nonce.clone();
+
+            match authorisation.status {
+                StatusType::Valid => return Ok(nonce),
+                StatusType::Pending | StatusType::Processing
+                    if attempt < client.retry_policy().max_retries =>
+                {
+                    let delay = client
+                        .retry_policy()
+                        .challenges
+                        .delay(attempt, authorisation.retry_after);
+                    client.sleep(delay)?
+                }
+                StatusType::Pending | StatusType::Processing => {}
+                StatusType::Invalid => return Err(authorisation.validation_error()),
+                _ => return Err(Error::IncorrectResponse),
+            }
+        }
+
+        Err(Error::OrderNotReady)
+    }
+
+    // Maps the problem document of the failed challenge to an error that carries the
+    // server's explanation, e.g. that the served key authorization didn't match.
+    fn validation_error(&self) -> Error {
+        let problem = match self
+            .challenges
+            .iter()
+            .find_map(|challenge| challenge.error.as_ref())
+        {
+            Some(problem) => problem,
+            None => return Error::IncorrectResponse,
+        };
+        let error = match problem["type"].as_str() {
+            Some("urn:ietf:params:acme:error:unauthorized") => Error::Unauthorized,
+            Some("urn:ietf:params:acme:error:connection") => Error::Connection,
+            Some("urn:ietf:params:acme:error:dns") => Error::DnsError,
+            Some("urn:ietf:params:acme:error:tls") => Error::TlsError,
+            Some("urn:ietf:params:acme:error:caa") => Error::CaaError,
+            _ => Error::IncorrectResponse,
+        };
+        match problem["detail"].as_str() {
+            Some(detail) => Error::WithDetail(Box::new(error), detail.to_owned()),
+            None => error,
         }
-        std::thread::sleep(std::time::Duration::from_secs(5));
-        Ok(result)
     }
 
     // Requests the check of the server at the `ACME` server instance.
-    fn kick_off_http_challenge(
+    fn kick_off_challenge(
         client: &Client,
         challenge_infos: Challenge,
+        key_authorisation: &str,
         nonce: Nonce,
         acc_url: &str,
-        private_key: &Rsa<Private>,
+        private_key: &PKey<Private>,
     ) -> Result<Nonce> {
         let header = json!({
-            "alg": "RS256",
             "kid": acc_url,
             "nonce": nonce,
             "url": challenge_infos.url
         });
 
-        let payload = json!({});
+        // servers of the ACME drafts want the response to the challenge in the payload
+        let payload = if client.is_acme_draft() {
+            json!({
+                "type": challenge_infos.challenge_type,
+                "keyAuthorization": key_authorisation,
+            })
+        } else {
+            json!({})
+        };
 
-        let jws = jws(payload, header, private_key)?;
+        let jws = signer(client, payload, header, private_key);
 
-        Ok(client
-            .post(&challenge_infos.url)
-            .header("Content-Type", "application/jose+json")
-            .body(serde_json::to_string_pretty(&jws)?)
-            .send()?
-            .headers()
-            .get("replay-nonce")
-            .ok_or(Error::IncorrectResponse)?
-            .to_str()?
-            .to_owned())
+        replay_nonce(&client.post_signed(&challenge_infos.url, jws)?)
     }
 }
 
-// Holds information about the updated order.
+// Whether a failed download is worth another attempt, i.e. it failed in transit or on the
+// server's side rather than being refused.
+fn is_transient(error: &Error) -> bool {
+    match error.root() {
+        Error::FromReqwestError(e) => {
+            e.is_connect()
+                || e.is_timeout()
+                || e.is_body()
+                || e.is_decode()
+                || e.status().is_some_and(|status| status.is_server_error())
+        }
+        Error::InvalidChain(_) => true,
+        _ => false,
+    }
+}
+
+// Holds information about the updated order. Like an `Order` it can be persisted,
+// everything but the nonce is kept.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpdatedOrder {
     pub status: String,
-    expires: String,
+    pub expires: Option<DateTime<Utc>>,
     identifiers: serde_json::Value,
     authorizations: serde_json::Value,
     finalize: String,
-    pub certificate: String,
+    pub certificate: Option<String>,
     #[serde(skip)]
     pub nonce: Nonce,
+    // not part of the server's response, only of the persisted order
+    #[serde(default)]
+    pub order_location: String,
+    // the unknown fields, see `Directory::extra`
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl UpdatedOrder {
-    /// Downloads an issued certificate.
+    // Whether the order has already expired.
+    pub fn is_expired(&self, clock: &dyn Clock) -> bool {
+        self.expires.is_some_and(|expires| expires <= clock.now())
+    }
+
+    // Whether the certificate was issued and can be downloaded without polling.
+    pub fn is_issued(&self) -> bool {
+        self.status == "valid" && self.certificate.is_some()
+    }
+
+    /// Downloads an issued certificate, waiting for the server to issue it if necessary.
+    // With a `preferred_chain` the alternate chains the server links to are searched for
+    // one that leads to a root with that common name, e.g. `ISRG Root X1`. If there's none
+    // the default chain is returned.
     pub fn download_certificate(
-        &self,
+        &mut self,
         client: &Client,
         account_url: &str,
-        p_key: &Rsa<Private>,
+        p_key: &PKey<Private>,
+        preferred_chain: Option<&str>,
     ) -> Result<Certificate> {
+        let certificate_url = self.poll_certificate_url(client, account_url, p_key)?;
+
+        let (chain, alternates) =
+            self.download_chain(client, &certificate_url, account_url, p_key)?;
+
+        let preferred_chain = match preferred_chain {
+            Some(preferred_chain) => preferred_chain,
+            None => return Ok(chain),
+        };
+        if chain_root_issuer(&chain)?.as_deref() == Some(preferred_chain) {
+            return Ok(chain);
+        }
+        for url in alternates {
+            let (alternate, _) = self.download_chain(client, &url, account_url, p_key)?;
+            if chain_root_issuer(&alternate)?.as_deref() == Some(preferred_chain) {
+                return Ok(alternate);
+            }
+        }
+
+        warn!(
+            "The server offers no chain to {}, using the default chain",
+            preferred_chain
+        );
+        Ok(chain)
+    }
+
+    // Downloads one of the certificate chains and the links to its alternates. Unlike the
+    // other requests the download is retried with backoff if the transfer breaks off, it's
+    // a POST-as-GET that can safely be repeated.
+    fn download_chain(
+        &mut self,
+        client: &Client,
+        url: &str,
+        account_url: &str,
+        p_key: &PKey<Private>,
+    ) -> Result<(Certificate, Vec<String>)> {
+        let mut retry = 0;
+        loop {
+            let result = self
+                .fetch_chain(client, url, account_url, p_key)
+                .and_then(|response| {
+                    let alternates = links_with_rel(&response, "alternate");
+                    let issuers = links_with_rel(&response, "up");
+                    let mut chain = response.text()?;
+                    // a transfer that broke off can leave a truncated chain behind
+                    let certificates = X509::stack_from_pem(chain.as_bytes())
+                        .ok()
+                        .filter(|certificates| !certificates.is_empty())
+                        .ok_or_else(|| Error::InvalidChain("the download broke off".to_owned()))?;
+                    // some CAs link the issuer instead of bundling it
+                    if let [leaf] = &certificates[..] {
+                        chain = follow_up_links(client, chain, leaf, issuers)?;
+                    }
+                    Ok((normalize_chain(&chain)?, alternates))
+                });
+            match result {
+                Err(e) if is_transient(&e) && retry < client.retry_policy().max_retries => {
+                    warn!("Retrying the download of the certificate: {}", e);
+                    // the nonce might have been used up by the failed attempt
+                    self.nonce.clear();
+                    client.sleep(client.retry_policy().downloads.delay(retry, None))?;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Downloads one of the certificate chains.
+    fn fetch_chain(
+        &mut self,
+        client: &Client,
+        url: &str,
+        account_url: &str,
+        p_key: &PKey<Private>,
+    ) -> Result<Response> {
         let header = json!({
-            "alg": "RS256",
-            "url": self.certificate,
+            "url": url,
             "kid": account_url,
             "nonce": self.nonce,
         });
         let payload = json!("");
 
-        let jws = jws(payload, header, p_key)?;
+        let jws = signer(client, payload, header, p_key);
 
-        Ok(client
-            .post(&self.certificate)
-            .header("Content-Type", "application/jose+json")
-            .body(serde_json::to_string_pretty(&jws)?)
-            .send()?
-            .text()?)
+        let response = check_problem(client.post_signed_for_certificate(url, jws)?, |_| None)?;
+        self.nonce = replay_nonce(&response)?;
+
+        Ok(response)
+    }
+
+    // Polls the order until the server populated the `certificate` field, waiting as long as
+    // the `orders` backoff says. Fails with `OrderNotReady` if it's still missing after the
+    // first poll and `max_retries` more.
+    fn poll_certificate_url(
+        &mut self,
+        client: &Client,
+        account_url: &str,
+        p_key: &PKey<Private>,
+    ) -> Result<String> {
+        for attempt in 0..=client.retry_policy().max_retries {
+            if self.is_issued() {
+                break;
+            }
+            client.check_cancelled()?;
+
+            let header = json!({
+                "url": self.order_location,
+                "kid": account_url,
+                "nonce": self.nonce,
+            });
+            let payload = json!("");
+
+            let jws = signer(client, payload, header, p_key);
+
+            let response = client.post_signed(&self.order_location, jws)?;
+
+            let delay = client
+                .retry_policy()
+                .orders
+                .delay(attempt, retry_after(&response));
+            let (nonce, order): (Nonce, UpdatedOrder) = extract_payload_and_nonce(response)?;
+
+            self.nonce = nonce;
+            self.status = order.status;
+            self.certificate = order.certificate;
+
+            if !self.is_issued() && attempt < client.retry_policy().max_retries {
+                client.sleep(delay)?;
+            }
+        }
+
+        match &self.certificate {
+            Some(certificate) if self.is_issued() => Ok(certificate.clone()),
+            _ => Err(Error::OrderNotReady),
+        }
+    }
+}
+
+// Completes a chain the CA returned as the bare leaf by following the `up` links to its
+// issuers, which may be PEM or DER encoded. The root is left out, web servers don't send it.
+fn follow_up_links(
+    client: &Client,
+    mut chain: Certificate,
+    leaf: &X509,
+    mut links: Vec<String>,
+) -> Result<Certificate> {
+    let mut subject = leaf.clone();
+    for _ in 0..MAX_UP_LINKS {
+        let link = match links.first() {
+            Some(link) => link.clone(),
+            None => break,
+        };
+        let response = check_problem(client.get(&link)?, |_| None)?;
+        links = links_with_rel(&response, "up");
+        let body = response.bytes()?;
+        let issuer = X509::from_pem(&body).or_else(|_| X509::from_der(&body))?;
+        if issuer.issued(&subject) != X509VerifyResult::OK {
+            return Err(Error::InvalidChain(format!(
+                "the up link {} doesn't lead to the issuer",
+                link
+            )));
+        }
+        if issuer.issued(&issuer) == X509VerifyResult::OK {
+            break;
+        }
+
+        if !chain.ends_with('\n') {
+            chain.push('\n');
+        }
+        chain.push_str(std::str::from_utf8(&issuer.to_pem()?)?);
+        subject = issuer;
     }
+    Ok(chain)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Backoff, RetryPolicy};
+    use crate::mock::{self, MockResponse, MockServer};
+    use crate::util::{decode_jws, generate_keypair, DecodedJws, FixedClock, KeyType};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    // A signed request the mock CA received.
+    struct Signed {
+        path: String,
+        jws: DecodedJws,
+    }
+
+    // A CA at a mock server with the endpoints of RFC 8555 that records the signed requests
+    // and answers them with the response for their path.
+    fn recording_ca(
+        answer: impl Fn(&str) -> MockResponse + Send + 'static,
+    ) -> (MockServer, Arc<Mutex<Vec<Signed>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let signed = requests.clone();
+        let server = MockServer::start(move |request| {
+            let endpoint = |path: &str| format!("{}{}", request.origin, path);
+            match request.path.as_str() {
+                "/directory" => MockResponse::json(
+                    200,
+                    json!({
+                        "newNonce": endpoint("/new-nonce"),
+                        "newAccount": endpoint("/new-account"),
+                        "newOrder": endpoint("/new-order"),
+                        "revokeCert": endpoint("/revoke-cert"),
+                        "keyChange": endpoint("/key-change"),
+                    }),
+                ),
+                "/new-nonce" => MockResponse::new(200).header("Replay-Nonce", "nonce"),
+                path => {
+                    let jws = serde_json::from_slice(&request.body).unwrap();
+                    signed.lock().unwrap().push(Signed {
+                        path: path.to_owned(),
+                        jws: decode_jws(&jws).unwrap(),
+                    });
+                    answer(path)
+                }
+            }
+        });
+        (server, requests)
+    }
+
+    fn order_json(status: &str) -> serde_json::Value {
+        json!({
+            "status": status,
+            "identifiers": [{"type": "dns", "value": "example.org"}],
+            "authorizations": [],
+            "finalize": "/finalize",
+        })
+    }
+
+    // An order at a mock CA that answers the polls with the statuses in turn, repeating the
+    // last one, and the number of polls it got.
+    fn polled_order(statuses: &'static [&'static str]) -> (Order, Arc<AtomicUsize>) {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let counter = polls.clone();
+        let server = MockServer::start(move |_| {
+            let poll = counter.fetch_add(1, Ordering::SeqCst);
+            MockResponse::json(200, order_json(statuses[poll.min(statuses.len() - 1)]))
+        });
+        let mut order: Order = serde_json::from_value(order_json("pending")).unwrap();
+        order.order_location = format!("{}/order/1", server.url);
+        (order, polls)
+    }
+
+    fn polling_client(max_retries: usize, poll_interval: Duration) -> Client {
+        Client::builder()
+            .retry_policy(RetryPolicy::new(max_retries, Duration::ZERO, poll_interval))
+            .build()
+    }
+
+    fn account_key() -> PKey<Private> {
+        generate_keypair(KeyType::Ed25519).unwrap().0
+    }
+
+    #[test]
+    fn an_open_order_is_only_resumed_until_it_expires_by_the_clock_of_the_client() {
+        let server = MockServer::start(|request| match request.path.as_str() {
+            "/orders" => MockResponse::json(
+                200,
+                json!({ "orders": [format!("{}/order/1", request.origin)] }),
+            ),
+            _ => {
+                let mut order = order_json("pending");
+                order["expires"] = json!("2026-10-23T00:00:00Z");
+                MockResponse::json(200, order)
+            }
+        });
+        let expires: DateTime<Utc> = "2026-10-23T00:00:00Z".parse().unwrap();
+        let resumed = |now| {
+            let client = Client::builder()
+                .retry_policy(RetryPolicy::new(0, Duration::ZERO, Duration::ZERO))
+                .clock(Arc::new(FixedClock(now)))
+                .build();
+            let mut account: Account = serde_json::from_value(json!({
+                "status": "valid",
+                "orders": format!("{}/orders", server.url),
+            }))
+            .unwrap();
+            account.nonce = "nonce".to_owned();
+            account
+                .find_open_order(&client, &account_key(), &["example.org"], None)
+                .unwrap()
+                .is_some()
+        };
 
+        assert!(resumed(expires - chrono::Duration::seconds(1)));
+        assert!(!resumed(expires));
+    }
+
+    #[test]
+    fn poll_until_ready_waits_while_the_order_is_pending() {
+        let (mut order, polls) = polled_order(&["pending", "pending", "ready"]);
+        let client = polling_client(5, Duration::ZERO);
+
+        order
+            .poll_until_ready(&client, "/account", "nonce".to_owned(), &account_key())
+            .unwrap();
+        assert_eq!(order.status, "ready");
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn poll_until_ready_polls_once_without_retries() {
+        let (mut order, polls) = polled_order(&["ready"]);
+        let client = polling_client(0, Duration::ZERO);
+
+        order
+            .poll_until_ready(&client, "/account", "nonce".to_owned(), &account_key())
+            .unwrap();
+        assert_eq!(order.status, "ready");
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn poll_until_ready_does_not_wait_after_the_last_poll() {
+        let (mut order, polls) = polled_order(&["pending"]);
+        let client = polling_client(1, Duration::from_millis(300));
+
+        let started = Instant::now();
+        let result =
+            order.poll_until_ready(&client, "/account", "nonce".to_owned(), &account_key());
+        assert!(matches!(result, Err(Error::OrderNotReady)));
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+        // one wait between the two polls and none after the last one
+        assert!(started.elapsed() < Duration::from_millis(550));
+    }
+
+    #[test]
+    fn the_cleanup_hook_runs_when_the_challenges_are_abandoned() {
+        let marker = std::env::temp_dir().join(format!("acme-cleanup-{}", std::process::id()));
+        let cleanup_hook = format!("echo \"$ACME_DOMAIN\" >> {}", marker.display());
+        let hooked = Hooked {
+            cleanup_hook: Some(&cleanup_hook),
+            variables: vec![vec![("ACME_DOMAIN", "example.org".to_owned())]],
+        };
+
+        // e.g. when the run is cancelled before the challenges are validated
+        drop(hooked);
+        let cleaned = fs::read_to_string(&marker).unwrap();
+        let _ = fs::remove_file(&marker);
+        assert_eq!(cleaned, "example.org\n");
+    }
+
+    #[test]
+    fn a_draft_server_issues_through_new_authz_and_new_cert() {
+        let (root, root_key) = mock::certificate("Root", None);
+        let (intermediate, intermediate_key) =
+            mock::certificate("Intermediate", Some((&root, &root_key)));
+        let (leaf, _) = mock::certificate("example.org", Some((&intermediate, &intermediate_key)));
+        let expected = [leaf.to_pem().unwrap(), intermediate.to_pem().unwrap()].concat();
+
+        // the signed requests, as endpoint, protected header and payload
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let signed = requests.clone();
+        let server = MockServer::start(move |request| {
+            let origin = &request.origin;
+            if request.method == "POST" {
+                let jws = decode_jws(&serde_json::from_slice(&request.body).unwrap()).unwrap();
+                signed
+                    .lock()
+                    .unwrap()
+                    .push((request.path.clone(), jws.header, jws.payload));
+            }
+            let der = |certificate: &X509| {
+                let mut response = MockResponse::new(200);
+                response.body = certificate.to_der().unwrap();
+                response
+            };
+            match request.path.as_str() {
+                "/directory" => MockResponse::json(
+                    200,
+                    json!({
+                        "new-reg": format!("{}/new-reg", origin),
+                        "new-authz": format!("{}/new-authz", origin),
+                        "new-cert": format!("{}/new-cert", origin),
+                        "revoke-cert": format!("{}/revoke-cert", origin),
+                        "meta": { "terms-of-service": "https://example.org/terms" },
+                    }),
+                ),
+                "/new-reg" => MockResponse::json(201, json!({ "contact": [] }))
+                    .header("Location", &format!("{}/reg/1", origin)),
+                "/new-authz" => MockResponse::json(
+                    201,
+                    json!({
+                        "identifier": { "type": "dns", "value": "example.org" },
+                        "status": "valid",
+                        "expires": "2030-01-01T00:00:00Z",
+                        "challenges": [{
+                            "type": "http-01",
+                            "status": "valid",
+                            "token": "token",
+                            "uri": format!("{}/challenge/1", origin),
+                        }],
+                    }),
+                )
+                .header("Location", &format!("{}/authz/1", origin)),
+                "/new-cert" => {
+                    let mut response = der(&leaf);
+                    response.status = 201;
+                    response
+                        .header("Replay-Nonce", "nonce")
+                        .header("Location", &format!("{}/cert/1", origin))
+                        .header("Link", "</issuer>;rel=\"up\"")
+                }
+                // relative to the URL of the issuer
+                "/issuer" => der(&intermediate).header("Link", "<root>;rel=\"up\""),
+                "/root" => der(&root),
+                _ => MockResponse::new(404),
+            }
+        });
+        let client = Client::builder()
+            .acme_draft(true)
+            .retry_policy(RetryPolicy::new(0, Duration::ZERO, Duration::ZERO))
+            .build();
+        let key = account_key();
+        let csr_keypair = generate_keypair(KeyType::Rsa).unwrap();
+
+        let directory =
+            Directory::fetch_dir(&client, &format!("{}/directory", server.url)).unwrap();
+        assert!(!directory.has_orders());
+        let mut account = directory.create_account(&client, &key, &[]).unwrap();
+        let authorisation = account
+            .new_authz(&client, &directory.new_authz, &key, "example.org")
+            .unwrap();
+        let challenge_url = format!("{}/challenge/1", server.url);
+        assert_eq!(authorisation.challenges[0].url, challenge_url);
+        let csr = request_csr(&csr_keypair, &["example.org"], CsrDigest::Sha256, &[], &[]).unwrap();
+        let nonce = authorisation.nonce;
+        let chain = account
+            .new_cert(&client, &directory.new_cert, nonce, &key, &csr)
+            .unwrap();
+        assert_eq!(chain.as_bytes(), expected);
+
+        let requests = requests.lock().unwrap();
+        let resources: Vec<(&str, &serde_json::Value)> = requests
+            .iter()
+            .map(|(path, _, payload)| (path.as_str(), &payload.as_ref().unwrap()["resource"]))
+            .collect();
+        assert_eq!(
+            resources,
+            [
+                ("/new-reg", &json!("new-reg")),
+                ("/new-authz", &json!("new-authz")),
+                ("/new-cert", &json!("new-cert")),
+            ]
+        );
+        assert_eq!(
+            requests[0].2.as_ref().unwrap()["agreement"],
+            "https://example.org/terms"
+        );
+        // the server knows the account by its key only
+        for (_, header, _) in requests.iter() {
+            assert!(header.get("jwk").is_some() && header.get("kid").is_none());
+        }
+    }
+
+    #[test]
+    fn a_revocation_is_signed_by_the_account_or_the_certificate_key() {
+        let (server, requests) = recording_ca(|_| MockResponse::new(200));
+        let client = mock::test_client();
+        let directory =
+            Directory::fetch_dir(&client, &format!("{}/directory", server.url)).unwrap();
+        let (leaf, leaf_key) = mock::certificate("example.org", None);
+        let pem = String::from_utf8(leaf.to_pem().unwrap()).unwrap();
+        let (account_key, account_public_key) = generate_keypair(KeyType::Ed25519).unwrap();
+
+        let by_account = RevocationKey::Account {
+            p_key: &account_key,
+            account_url: "https://example.org/account/1",
+        };
+        directory
+            .revoke_certificate(&client, &pem, Some(1), by_account)
+            .unwrap();
+        let by_certificate = RevocationKey::Certificate(&leaf_key);
+        directory
+            .revoke_certificate(&client, &pem, None, by_certificate)
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let certificate = b64(&leaf.to_der().unwrap());
+        let by_account = &requests[0].jws;
+        assert_eq!(requests[0].path, "/revoke-cert");
+        assert_eq!(by_account.header["kid"], "https://example.org/account/1");
+        assert!(by_account.header.get("jwk").is_none());
+        assert_eq!(
+            by_account.payload,
+            Some(json!({ "certificate": certificate, "reason": 1 }))
+        );
+        assert!(by_account.verify(&account_public_key).unwrap());
+
+        // the certificate key proves the control of the certificate without an account
+        let by_certificate = &requests[1].jws;
+        let leaf_public_key = leaf.public_key().unwrap();
+        assert_eq!(by_certificate.header["jwk"], jwk(&leaf_key).unwrap());
+        assert!(by_certificate.header.get("kid").is_none());
+        assert_eq!(
+            by_certificate.payload,
+            Some(json!({ "certificate": certificate }))
+        );
+        assert!(by_certificate.verify(&leaf_public_key).unwrap());
+    }
+
+    // The endpoints of the signed requests in the order they were received.
+    fn paths(requests: &Mutex<Vec<Signed>>) -> Vec<String> {
+        let requests = requests.lock().unwrap();
+        requests
+            .iter()
+            .map(|request| request.path.clone())
+            .collect()
+    }
+
+    // An order at the recording CA that's ready to be finalized.
+    fn ready_order(server: &MockServer) -> Order {
+        let mut order: Order = serde_json::from_value(order_json("ready")).unwrap();
+        order.order_location = format!("{}/order/1", server.url);
+        order.finalize = format!("{}/finalize", server.url);
+        order
+    }
+
+    fn finalized(origin: &str, status: &str) -> MockResponse {
+        let mut order = order_json(status);
+        if status == "valid" {
+            order["certificate"] = json!(format!("{}/certificate/1", origin));
+        }
+        MockResponse::json(200, order)
+    }
+
+    #[test]
+    fn finalize_order_takes_the_certificate_url_it_returns_right_away() {
+        let (server, requests) = recording_ca(|_| finalized("https://example.org", "valid"));
+        let cert_keypair = generate_keypair(KeyType::Rsa).unwrap();
+
+        let updated_order = ready_order(&server)
+            .finalize_order(
+                &mock::test_client(),
+                "https://example.org/account/1",
+                "nonce".to_owned(),
+                &account_key(),
+                &cert_keypair,
+                &["example.org"],
+                CsrDigest::Sha256,
+                &[],
+                &[],
+            )
+            .unwrap();
+        assert_eq!(
+            updated_order.certificate.as_deref(),
+            Some("https://example.org/certificate/1")
+        );
+        // no poll of the order
+        assert_eq!(paths(&requests), ["/finalize"]);
+    }
+
+    #[test]
+    fn finalize_order_polls_an_order_that_is_still_processing() {
+        let (server, requests) = recording_ca(|path| match path {
+            "/finalize" => finalized("https://example.org", "processing"),
+            _ => finalized("https://example.org", "valid"),
+        });
+        let cert_keypair = generate_keypair(KeyType::Rsa).unwrap();
+
+        let updated_order = ready_order(&server)
+            .finalize_order(
+                &polling_client(1, Duration::ZERO),
+                "https://example.org/account/1",
+                "nonce".to_owned(),
+                &account_key(),
+                &cert_keypair,
+                &["example.org"],
+                CsrDigest::Sha256,
+                &[],
+                &[],
+            )
+            .unwrap();
+        assert!(updated_order.is_issued());
+        assert_eq!(paths(&requests), ["/finalize", "/order/1"]);
+    }
+
+    #[test]
+    fn finalize_order_sends_the_given_csr_whatever_the_account_key() {
+        let (server, requests) = recording_ca(|_| finalized("https://example.org", "valid"));
+        let cert_keypair = generate_keypair(KeyType::Rsa).unwrap();
+        let csr = request_csr(&cert_keypair, &["example.org"], CsrDigest::Sha384, &[], &[]);
+        let csr_der = csr.as_ref().unwrap().to_der().unwrap();
+        let mut order = ready_order(&server);
+        order.optional_csr = csr.ok();
+
+        // the generated keypair is ignored in favour of the CSR's
+        let unused_keypair = generate_keypair(KeyType::Ed25519).unwrap();
+        order
+            .finalize_order(
+                &mock::test_client(),
+                "https://example.org/account/1",
+                "nonce".to_owned(),
+                &account_key(),
+                &unused_keypair,
+                &["example.org"],
+                CsrDigest::Sha256,
+                &[],
+                &[],
+            )
+            .unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].jws.header["alg"], "EdDSA");
+        assert_eq!(
+            requests[0].jws.payload,
+            Some(json!({ "csr": b64(&csr_der) }))
+        );
+    }
+
+    #[test]
+    fn a_rejected_ed25519_csr_is_a_bad_signature_algorithm() {
+        let (server, _) = recording_ca(|_| {
+            let problem = json!({
+                "type": "urn:ietf:params:acme:error:badCSR",
+                "detail": "unsupported key type",
+            });
+            MockResponse::json(400, problem)
+        });
+        let cert_keypair = generate_keypair(KeyType::Ed25519).unwrap();
+
+        let result = ready_order(&server).finalize_order(
+            &mock::test_client(),
+            "https://example.org/account/1",
+            "nonce".to_owned(),
+            &account_key(),
+            &cert_keypair,
+            &["example.org"],
+            CsrDigest::Sha256,
+            &[],
+            &[],
+        );
+        assert!(matches!(result, Err(Error::BadSignatureAlgorithm)));
+    }
+
+    // An issued order at the recording CA.
+    fn issued_order(server: &MockServer) -> UpdatedOrder {
+        let mut order = order_json("valid");
+        order["certificate"] = json!(format!("{}/certificate/1", server.url));
+        serde_json::from_value(order).unwrap()
+    }
+
+    #[test]
+    fn a_download_that_broke_off_is_retried() {
+        let (root, root_key) = mock::certificate("Mock Root", None);
+        let (leaf, _) = mock::certificate("example.org", Some((&root, &root_key)));
+        let mut chain = String::from_utf8(leaf.to_pem().unwrap()).unwrap();
+        chain.push_str(std::str::from_utf8(&root.to_pem().unwrap()).unwrap());
+
+        let downloads = AtomicUsize::new(0);
+        let served = chain.clone();
+        let (server, requests) = recording_ca(move |_| {
+            let mut response = MockResponse::new(200).header("Replay-Nonce", "nonce");
+            response.body = served.clone().into_bytes();
+            // the first transfer ends in the middle of the root
+            if downloads.fetch_add(1, Ordering::SeqCst) == 0 {
+                response.body.truncate(served.len() - 100);
+            }
+            response
+        });
+
+        // the retry signs with a fresh nonce
+        let client = polling_client(1, Duration::ZERO);
+        client.set_new_nonce_url(&format!("{}/new-nonce", server.url));
+
+        let certificate = issued_order(&server)
+            .download_certificate(
+                &client,
+                "https://example.org/account/1",
+                &account_key(),
+                None,
+            )
+            .unwrap();
+        assert_eq!(certificate, chain);
+        assert_eq!(paths(&requests), ["/certificate/1", "/certificate/1"]);
+    }
+
+    #[test]
+    fn a_rejected_nonce_is_replaced_by_the_one_of_the_rejection() {
+        let rejections = AtomicUsize::new(0);
+        let (server, requests) = recording_ca(move |_| {
+            if rejections.fetch_add(1, Ordering::SeqCst) > 0 {
+                return finalized("https://example.org", "valid");
+            }
+            let problem = json!({ "type": "urn:ietf:params:acme:error:badNonce" });
+            let mut response = MockResponse::new(400)
+                .header("Content-Type", "application/problem+json")
+                .header("Replay-Nonce", "fresh-nonce");
+            response.body = problem.to_string().into_bytes();
+            response
+        });
+        let cert_keypair = generate_keypair(KeyType::Rsa).unwrap();
+
+        let updated_order = ready_order(&server)
+            .finalize_order(
+                &polling_client(1, Duration::ZERO),
+                "https://example.org/account/1",
+                "stale-nonce".to_owned(),
+                &account_key(),
+                &cert_keypair,
+                &["example.org"],
+                CsrDigest::Sha256,
+                &[],
+                &[],
+            )
+            .unwrap();
+        assert!(updated_order.is_issued());
+        let requests = requests.lock().unwrap();
+        let nonces: Vec<_> = requests
+            .iter()
+            .map(|request| request.jws.header["nonce"].clone())
+            .collect();
+        assert_eq!(nonces, ["stale-nonce", "fresh-nonce"]);
+    }
+
+    // A backoff that doesn't wait and records the delays the server asked for.
+    #[derive(Debug, Default)]
+    struct Recorded(Mutex<Vec<Option<Duration>>>);
+
+    impl Backoff for Recorded {
+        fn delay(&self, _attempt: usize, retry_after: Option<Duration>) -> Duration {
+            self.0.lock().unwrap().push(retry_after);
+            Duration::ZERO
+        }
+    }
+
+    #[test]
+    fn the_challenges_backoff_gets_the_delay_the_server_asked_for() {
+        let polls = AtomicUsize::new(0);
+        let (server, _) = recording_ca(move |_| {
+            let status = match polls.fetch_add(1, Ordering::SeqCst) {
+                0 => "pending",
+                _ => "valid",
+            };
+            let authorisation = json!({
+                "identifier": {"type": "dns", "value": "example.org"},
+                "status": status,
+                "expires": "2026-10-23T00:00:00Z",
+                "challenges": [],
+            });
+            MockResponse::json(200, authorisation).header("Retry-After", "3")
+        });
+        let recorded = Arc::new(Recorded::default());
+        let mut retry_policy = RetryPolicy::new(1, Duration::ZERO, Duration::ZERO);
+        retry_policy.challenges = recorded.clone();
+        let client = Client::builder().retry_policy(retry_policy).build();
+
+        ChallengeAuthorisation::poll_until_valid(
+            &client,
+            &format!("{}/authz/1", server.url),
+            "https://example.org/account/1",
+            "nonce".to_owned(),
+            &account_key(),
+        )
+        .unwrap();
+        assert_eq!(*recorded.0.lock().unwrap(), [Some(Duration::from_secs(3))]);
+    }
+
+    #[test]
+    fn the_authorisations_by_domain_keep_the_order_they_were_fetched_in() {
+        let (server, _) = recording_ca(|path| {
+            let (value, wildcard) = match path {
+                "/authz/1" => ("www.example.org", false),
+                _ => ("example.org", true),
+            };
+            let authorisation = json!({
+                "identifier": {"type": "dns", "value": value},
+                "status": "pending",
+                "expires": "2026-10-23T00:00:00Z",
+                "challenges": [],
+                "wildcard": wildcard,
+            });
+            MockResponse::json(200, authorisation)
+        });
+        let mut order: Order = serde_json::from_value(order_json("pending")).unwrap();
+        order.authorizations = vec![
+            format!("{}/authz/1", server.url),
+            format!("{}/authz/2", server.url),
+        ];
+        order.nonce = "nonce".to_owned();
+
+        let authorisations = order
+            .fetch_auth_challenges_by_domain(
+                &mock::test_client(),
+                "https://example.org/account/1",
+                &account_key(),
+            )
+            .unwrap();
+        let domains: Vec<&str> = authorisations
+            .iter()
+            .map(|(domain, _)| domain.as_str())
+            .collect();
+        assert_eq!(domains, ["www.example.org", "*.example.org"]);
+    }
+
+    // A prompt that records the steps it is given instead of waiting for a user.
+    #[derive(Debug, Default)]
+    struct RecordingPrompt(Mutex<Vec<ManualStep>>);
+
+    impl ManualPrompt for RecordingPrompt {
+        fn present(&self, steps: &[ManualStep]) -> Result<()> {
+            self.0.lock().unwrap().extend_from_slice(steps);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn the_manual_prompt_gets_the_steps_of_the_challenges() {
+        let authorisation: ChallengeAuthorisation = serde_json::from_value(json!({
+            "identifier": {"type": "dns", "value": "example.org"},
+            "status": "pending",
+            "expires": "2026-10-23T00:00:00Z",
+            "challenges": [],
+        }))
+        .unwrap();
+        let challenge: Challenge = serde_json::from_value(json!({
+            "status": "pending",
+            "token": "token",
+            "type": "http-01",
+            "url": "https://example.org/challenge/1",
+        }))
+        .unwrap();
+        let challenges = [(challenge, "token.thumbprint".to_owned())];
+        let prompt = RecordingPrompt::default();
+
+        ChallengeAuthorisation::present_manually(
+            &mock::test_client(),
+            &[&authorisation],
+            &challenges,
+            ChallengeType::Http01,
+            &prompt,
+            false,
+        )
+        .unwrap();
+        ChallengeAuthorisation::present_manually(
+            &mock::test_client(),
+            &[&authorisation],
+            &challenges,
+            ChallengeType::Dns01,
+            &prompt,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            *prompt.0.lock().unwrap(),
+            [
+                ManualStep::ServeFile {
+                    domain: "example.org".to_owned(),
+                    url: "http://example.org/.well-known/acme-challenge/token".to_owned(),
+                    contents: "token.thumbprint".to_owned(),
+                },
+                ManualStep::TxtRecord {
+                    domain: "example.org".to_owned(),
+                    name: "_acme-challenge.example.org".to_owned(),
+                    value: dns_txt_value("token.thumbprint"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_gzipped_directory_is_decompressed() {
+        let server = MockServer::start(|request| {
+            let directory = json!({
+                "newNonce": format!("{}/new-nonce", request.origin),
+                "newAccount": format!("{}/new-account", request.origin),
+                "newOrder": format!("{}/new-order", request.origin),
+                "revokeCert": format!("{}/revoke-cert", request.origin),
+                "keyChange": format!("{}/key-change", request.origin),
+            });
+            MockResponse::json(200, directory).gzipped()
+        });
+        let directory_url = format!("{}/directory", server.url);
+
+        let directory = Directory::fetch_dir(&mock::test_client(), &directory_url).unwrap();
+        assert_eq!(directory.new_order, format!("{}/new-order", server.url));
+    }
+
+    #[test]
+    fn a_gzipped_problem_is_decompressed() {
+        let (server, _) = recording_ca(|_| {
+            let problem = json!({
+                "type": "urn:ietf:params:acme:error:badCSR",
+                "detail": "unsupported key type",
+            });
+            MockResponse::json(400, problem).gzipped()
+        });
+        let cert_keypair = generate_keypair(KeyType::Ed25519).unwrap();
+
+        let result = ready_order(&server).finalize_order(
+            &mock::test_client(),
+            "https://example.org/account/1",
+            "nonce".to_owned(),
+            &account_key(),
+            &cert_keypair,
+            &["example.org"],
+            CsrDigest::Sha256,
+            &[],
+            &[],
+        );
+        assert!(matches!(result, Err(Error::BadSignatureAlgorithm)));
+    }
+
+    // The PEM encoded certificates, in the given order.
+    fn pem_chain(certificates: &[&X509]) -> String {
+        certificates
+            .iter()
+            .map(|certificate| String::from_utf8(certificate.to_pem().unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn a_shuffled_alternate_chain_comes_out_leaf_first() {
+        let (root, root_key) = mock::certificate("Mock Root", None);
+        let (intermediate, intermediate_key) =
+            mock::certificate("Mock Intermediate", Some((&root, &root_key)));
+        let (leaf, _) = mock::certificate("example.org", Some((&intermediate, &intermediate_key)));
+        let (other_root, other_root_key) = mock::certificate("Other Root", None);
+        let (other_leaf, _) =
+            mock::certificate("example.org", Some((&other_root, &other_root_key)));
+
+        let default_chain = pem_chain(&[&other_leaf]);
+        let alternate_chain = pem_chain(&[&root, &leaf, &intermediate]);
+        // the link to the alternate needs the address the server gets
+        let origin = Arc::new(std::sync::OnceLock::<String>::new());
+        let alternate_origin = origin.clone();
+        let (server, _) = recording_ca(move |path| {
+            let mut response = MockResponse::new(200).header("Replay-Nonce", "nonce");
+            if path == "/certificate/1" {
+                let link = format!(
+                    "<{}/certificate/1/1>;rel=\"alternate\"",
+                    alternate_origin.get().unwrap()
+                );
+                response = response.header("Link", &link);
+                response.body = default_chain.clone().into_bytes();
+            } else {
+                response.body = alternate_chain.clone().into_bytes();
+            }
+            response
+        });
+        origin.set(server.url.clone()).unwrap();
+        let client = mock::test_client();
+        client.set_new_nonce_url(&format!("{}/new-nonce", server.url));
+
+        let certificate = issued_order(&server)
+            .download_certificate(
+                &client,
+                "https://example.org/account/1",
+                &account_key(),
+                Some("Mock Root"),
+            )
+            .unwrap();
+        assert_eq!(certificate, pem_chain(&[&leaf, &intermediate, &root]));
+    }
+
+    #[test]
+    fn contact_urls_adds_the_mailto_scheme_to_bare_addresses() {
+        let contacts = [
+            "admin@example.org",
+            "mailto:ops@example.org",
+            "tel:+15550100",
+        ];
+        assert_eq!(
+            contact_urls(&contacts).unwrap(),
+            [
+                "mailto:admin@example.org",
+                "mailto:ops@example.org",
+                "tel:+15550100"
+            ]
+        );
+    }
+
+    #[test]
+    fn contact_urls_reports_every_invalid_contact() {
+        let contacts = [
+            "admin@example",
+            "admin@example.org",
+            "https://example.org",
+            "mailto:",
+        ];
+
+        match contact_urls(&contacts) {
+            Err(Error::InvalidContacts(invalid)) => {
+                let invalid: Vec<(&str, &Error)> = invalid
+                    .iter()
+                    .map(|(contact, e)| (contact.as_str(), e))
+                    .collect();
+                assert!(matches!(
+                    invalid[..],
+                    [
+                        ("admin@example", Error::InvalidContact),
+                        ("https://example.org", Error::UnsupportedContact),
+                        ("mailto:", Error::InvalidContact),
+                    ]
+                ));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
@@ -1,31 +1,95 @@
 use acme::{
+    acc::{
+        check_web_root, contact_urls, ChallengeStrategy, ChallengeType, Directory, ManualPrompt,
+        ManualStep, Presentation, WEB_ROOT,
+    },
+    dns,
+    error::Error,
     generate_certificate_for_domain,
+    http::{
+        Client, RetryPolicy, DEFAULT_MAX_RETRIES, DEFAULT_POLL_INTERVAL, DEFAULT_RETRY_BASE_DELAY,
+    },
+    offered_challenges, register_account,
     util::{
-        check_for_existing_server, generate_rsa_keypair, load_csr_from_file, load_keys_from_file,
-        save_certificates, save_keypair,
+        check_for_existing_server, check_for_existing_server_on, compute_key_authorization,
+        decode_jws, describe_certificate, describe_csr, dns_txt_value, generate_keypair_of_size,
+        jwk, key_info, keypair_to_pem, load_account_key, load_csr_from_file, load_keys_from_file,
+        load_openssl_provider, load_public_key_from_file, run_hook, save_bundle, save_certificates,
+        save_certificates_der, save_keypair, thumbprint, to_ascii_domain, CsrDigest, CsrExtension,
+        KeyType, OutputFormat, SubjectField,
     },
+    state::State,
+    verify_reachability, IssuanceOptions, MAX_CLOCK_SKEW_SECS,
 };
-use clap::{IntoApp, Parser};
+use clap::{IntoApp, Parser, Subcommand};
 use flexi_logger::Logger;
-use log::info;
+use log::{info, warn};
+use openssl::pkey::{PKey, Private};
+use reqwest::Url;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
 const LETS_ENCRYPT_SERVER: &str = "https://acme-v02.api.letsencrypt.org/directory";
 const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
 
+// RSA account keys of at most this many bits are reported as due for a rotation.
+const WEAK_RSA_KEY_BITS: u32 = 2048;
+
+// Exit codes that let orchestrators react to the cause of a failure.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_RATE_LIMITED: i32 = 2;
+const EXIT_UNAUTHORIZED: i32 = 3;
+const EXIT_NETWORK: i32 = 4;
+const EXIT_USAGE: i32 = 64;
+
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0     Success
+    1     Any other error
+    2     Rate limited by the ACME server, back off before retrying
+    3     Authorization or challenge validation failed
+    4     Network error or the ACME server is temporarily unavailable
+    64    Invalid configuration or arguments";
+
 // An acme client (RFC8555) written in Rust.
 #[derive(Parser, Debug)]
 #[clap(
     version = "0.1.0",
-    author = "Siddhant Ray <siddhant.r98@gmail.com>"
+    author = "Siddhant Ray <siddhant.r98@gmail.com>",
+    after_help = EXIT_CODES_HELP,
+    subcommand_negates_reqs = true
 )]
 
 struct Args {
-    // The email associated with the domain
-    #[clap(short, long)]
-    email: String,
+    // The contacts of the account, email addresses or mailto: or tel: URLs, repeat the
+    // option for several contacts
+    #[clap(
+        short,
+        long,
+        required_unless_present_any = &[
+            "print-thumbprint",
+            "account-info",
+            "print-jwk",
+            "print-directory",
+            "self-test",
+        ]
+    )]
+    email: Vec<String>,
     // The domain to register the certificate for
-    #[clap(short, long)]
-    domain: String,
+    #[clap(
+        short,
+        long,
+        required_unless_present_any = &[
+            "print-thumbprint",
+            "account-info",
+            "print-jwk",
+            "print-directory",
+            "register-only",
+        ]
+    )]
+    domain: Option<String>,
     // An optional private key file to load the keys
     #[clap(long)]
     private_key: Option<String>,
@@ -35,99 +99,1062 @@ struct Args {
     // The ACME server's URL
     #[clap(short, long)]
     server: Option<String>,
+    // Uses the staging server of Let's Encrypt, whose rate limits are much higher, e.g. to
+    // test the setup
+    #[clap(long, conflicts_with = "server")]
+    staging: bool,
+    // Talks to a server of the pre-RFC 8555 ACME drafts, e.g. an older internal CA, whose
+    // directory uses names like new-reg and which expects the resource in the payloads.
+    // Without a new-order endpoint the certificate is requested through new-authz and new-cert
+    #[clap(long)]
+    acme_draft: bool,
+    // Allows a plain http server URL and a server certificate that doesn't verify, e.g. the
+    // self-signed one of a local test CA
+    #[clap(long)]
+    insecure: bool,
+    // Fails instead of registering an account if none is registered for the account key yet
+    #[clap(long)]
+    only_existing: bool,
+    // Registers the account, saves its key to the state file, prints its URL and exits
+    // without placing an order, e.g. to provision accounts in a separate job
+    #[clap(long, requires = "state")]
+    register_only: bool,
+    // Opens an order for the domain, prints the challenges the CA offers for it, then
+    // abandons the order, e.g. to check whether dns-01 is available before issuing
+    #[clap(long)]
+    list_challenges: bool,
     // Initialize a standalone web server if there is not one already using port 80.
     #[clap(long)]
     standalone: bool,
+    // Hands the http-01 challenges to an external web server through this Unix socket,
+    // e.g. a sidecar serving port 80, and waits for it to acknowledge them
+    #[clap(long)]
+    challenge_socket: Option<PathBuf>,
+    // Prints what has to be done to present the challenges (the file for http-01, the TXT
+    // record for dns-01) and waits for enter before the CA validates them
+    #[clap(long, conflicts_with_all = &["standalone", "challenge-socket"])]
+    manual: bool,
+    // Waits until the manually created dns-01 TXT records are visible before the CA checks,
+    // fails if they aren't after 10 minutes
+    #[clap(long, requires = "manual")]
+    wait_for_propagation: bool,
+    // A shell command that presents each challenge instead of the printed instructions,
+    // with ACME_DOMAIN, ACME_CHALLENGE_TYPE, ACME_TOKEN, ACME_KEY_AUTHORIZATION and
+    // ACME_VALIDATION (the file contents or TXT value) in its environment
+    #[clap(long, requires = "manual")]
+    manual_auth_hook: Option<String>,
+    // A shell command that removes each challenge once the CA is done with it, even if the
+    // validation failed, with the same environment as the auth hook
+    #[clap(long, requires = "manual-auth-hook")]
+    manual_cleanup_hook: Option<String>,
+    // Only notifies the CA, the http-01 responses were already placed out of band
+    #[clap(long, conflicts_with_all = &["standalone", "challenge-socket", "manual"])]
+    pre_placed: bool,
+    // Fetches the pre-placed responses before notifying the CA to catch mistakes early
+    #[clap(long, requires = "pre-placed")]
+    self_check: bool,
+    // The seconds the self-check keeps fetching the responses until they're served, e.g. to
+    // give a reverse proxy that serves them time to reload its configuration
+    #[clap(long, default_value_t = 0, requires = "self-check")]
+    self_check_grace: u64,
+    // The challenge to solve: auto, http-01, tls-alpn-01 or dns-01 (only with --manual).
+    // With auto a standalone run prefers tls-alpn-01 on port 443 and falls back to http-01,
+    // a manual one prefers http-01 and falls back to dns-01.
+    #[clap(long, default_value = "auto")]
+    challenge: ChallengeStrategy,
+    // The type of the generated certificate key (rsa or ed25519), not every CA accepts ed25519
+    #[clap(long, default_value = "rsa")]
+    key_type: KeyType,
+    // The size of the generated RSA keys in bits, at least 2048
+    #[clap(long, default_value_t = 2048)]
+    key_bits: u32,
+    // The type of a newly generated account key (rsa or ed25519), the --key-type by default.
+    // The account and certificate keys are independent and may differ on purpose. A CA that
+    // rejects an ed25519 account key, like Let's Encrypt, gets a RSA one instead.
+    #[clap(long)]
+    account_key_type: Option<KeyType>,
     // An optional path to a PEM formatted Certificate Signing Request (CSR)
     #[clap(long)]
     csr_path: Option<String>,
+    // The digest used to sign the generated CSR (sha256, sha384 or sha512)
+    #[clap(long, default_value = "sha256")]
+    csr_digest: CsrDigest,
+    // Additional fields of the subject of the generated CSR like O=Example Inc. or C=DE, for
+    // internal PKIs. Public CAs usually ignore them for DV certificates.
+    #[clap(long)]
+    csr_subject: Vec<SubjectField>,
+    // Additional extensions of the generated CSR as OID=HEX or OID=critical,HEX with the DER
+    // encoded value, e.g. a certificate policy an internal CA requires. Can't replace the SANs.
+    #[clap(long)]
+    csr_extension: Vec<CsrExtension>,
+    // The certificate profile to request, e.g. shortlived for 6-day certificates
+    #[clap(long)]
+    profile: Option<String>,
+    // Checks locally that the CAA records of the domain permit this CA identity to issue,
+    // e.g. letsencrypt.org, and fails if they don't. Without it the identities the CA
+    // declares in its directory are checked and only warned about
+    #[clap(long)]
+    caa_identity: Option<String>,
+    // Fails instead of warning if the local clock is more than a minute off the CA's
+    #[clap(long)]
+    strict_clock: bool,
+    // Fails instead of warning if the certificate would be for the account key
+    #[clap(long)]
+    strict_keys: bool,
+    // Picks the alternate chain that leads to the root with this common name, e.g.
+    // "ISRG Root X1", and falls back to the default chain if the CA offers none
+    #[clap(long)]
+    preferred_chain: Option<String>,
+    // Fails if the downloaded chain doesn't hold together, i.e. the leaf isn't for the key
+    // or a certificate isn't issued by the next one
+    #[clap(long)]
+    verify_chain: bool,
+    // Also writes the certificate as DER to this directory, for devices that can't read PEM:
+    // the leaf to my_cert.der and the rest of the chain to chain_1.der, chain_2.der, ...
+    #[clap(long)]
+    der_out: Option<PathBuf>,
+    // Saves the certificate and key in the layout a web server expects, to the --key-out
+    // directory: nginx for fullchain.pem and privkey.pem, apache for cert.pem, chain.pem
+    // and privkey.pem
+    #[clap(long)]
+    format: Option<OutputFormat>,
+    // The directory the generated keypair is saved to as priv.pem and pub.pem, or - to
+    // print it to stdout instead, e.g. to pipe it into a secret store on a CI runner
+    #[clap(long)]
+    key_out: Option<String>,
+    // How the issued certificate is reported on stdout, json prints its domains, serial,
+    // authority key id and expiry, e.g. for an inventory system
+    #[clap(long, arg_enum, default_value = "text")]
+    output: Output,
+    // A shell command to run once the certificate is saved, e.g. to reload the web server.
+    // ACME_CERT_PATH, ACME_KEY_PATH, ACME_DOMAINS, ACME_SERIAL and ACME_EXPIRY describe the
+    // certificate, a failing hook fails the run
+    #[clap(long)]
+    deploy_hook: Option<String>,
+    // A state file to reuse the account, resume interrupted orders and skip fresh certificates
+    #[clap(long)]
+    state: Option<PathBuf>,
+    // The PEM encoded account key to use instead of a new one, read from `env:NAME`, from
+    // stdin with `-` (not with --manual) or from a path, e.g. to keep the key of a secret
+    // manager off the disk
+    #[clap(long, conflicts_with = "state")]
+    account_key: Option<String>,
+    // Issues even if the certificate in the state file is still fresh, e.g. after its key
+    // was compromised, the account of the state file is still reused
+    #[clap(long, requires = "state")]
+    force_renew: bool,
+    // How often failed requests are retried and pending orders are polled again
+    #[clap(long, default_value_t = DEFAULT_MAX_RETRIES)]
+    max_retries: usize,
+    // The seconds before the first retry of a failed request, doubled for every further one
+    #[clap(long, default_value_t = DEFAULT_RETRY_BASE_DELAY.as_secs())]
+    retry_base_delay: u64,
+    // The seconds between two polls if the CA doesn't ask for a specific delay
+    #[clap(long, default_value_t = DEFAULT_POLL_INTERVAL.as_secs())]
+    poll_interval: u64,
+    // Generates keys and signs only with these OpenSSL providers, e.g. fips and base in
+    // FIPS-validated environments
+    #[clap(long)]
+    openssl_provider: Vec<String>,
+    // Writes every signed request and response to numbered files in this directory, with the
+    // signatures redacted, e.g. to attach to a support ticket of the CA
+    #[clap(long)]
+    debug_dump: Option<PathBuf>,
+    // Prints the thumbprint of the PEM encoded account key at the given path, `env:NAME` or
+    // `-` and exits, e.g. to configure an external challenge responder
+    #[clap(long)]
+    print_thumbprint: Option<String>,
+    // Prints the algorithm, size and thumbprint of the PEM encoded account key at the given
+    // path, `env:NAME` or `-` and exits, e.g. to find accounts still on a 2048-bit RSA key
+    #[clap(long)]
+    account_info: Option<String>,
+    // Prints the public JWK of the PEM encoded account key at the given path, `env:NAME` or
+    // `-` and exits, e.g. to have the account registered by a central system
+    #[clap(long)]
+    print_jwk: Option<String>,
+    // Prints the directory of the ACME server as JSON and exits, e.g. to see which
+    // endpoints and profiles an unknown CA offers
+    #[clap(long)]
+    print_directory: bool,
+    // Checks everything short of the issuance for the domain and challenge, prints a
+    // pass/fail checklist and exits with 1 if any check failed
+    #[clap(long)]
+    self_test: bool,
     // Enables debug output.
     #[clap(short, long)]
     verbose: bool,
+    // Suppresses all output except for errors.
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    // Prints the value of the `_acme-challenge` TXT record of a dns-01 token and exits, e.g.
+    // to create the record with a DNS provider managed out of band
+    TxtValue {
+        // The domain the record is for
+        #[clap(long)]
+        domain: String,
+        // The token of the dns-01 challenge
+        #[clap(long)]
+        token: String,
+        // The PEM encoded account key the order was created with, at a path, `env:NAME` or `-`
+        #[clap(long)]
+        account_key: String,
+    },
+    // Decodes the header and payload of a JWS the client signed, given as JSON in a file,
+    // and checks its signature if a key is given, e.g. to debug why the CA rejects requests
+    #[clap(hide = true)]
+    DecodeJws {
+        jws: PathBuf,
+        // The PEM encoded public key or account key to check the signature with
+        #[clap(long)]
+        key: Option<PathBuf>,
+    },
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Output {
+    Text,
+    Json,
+}
+
+// Maps an error to the exit code of its category.
+fn exit_code(error: &Error) -> i32 {
+    match error.root() {
+        Error::RateLimited(_) => EXIT_RATE_LIMITED,
+        Error::Unauthorized
+        | Error::AccountDeactivated(_)
+        | Error::IncorrectResponse
+        | Error::ChallengesFailed(_)
+        | Error::Connection
+        | Error::CaaError
+        | Error::DnsError
+        | Error::TlsError
+        | Error::RejectedIdentifier
+        | Error::UnsupportedIdentifier
+        | Error::NoHttpChallengePresent
+        | Error::NoSupportedChallengePresent => EXIT_UNAUTHORIZED,
+        Error::FromReqwestError(_) | Error::ServiceUnavailable(_) => EXIT_NETWORK,
+        Error::BadCSR
+        | Error::CsrExtensionConflict(_)
+        | Error::BadPublicKey
+        | Error::BadSignatureAlgorithm
+        | Error::InvalidContact
+        | Error::InvalidContacts(_)
+        | Error::UnsupportedContact
+        | Error::NoWebServer
+        | Error::PrivilegedPort(_)
+        | Error::NoDirectory
+        | Error::InvalidProfile(_)
+        | Error::ProviderUnavailable(_)
+        | Error::AccountKeyReused
+        | Error::ClockSkew(_) => EXIT_USAGE,
+        _ => EXIT_FAILURE,
+    }
+}
+
+// Prints the error and exits with the exit code of its category instead of panicking.
+fn exit_on_error<T>(result: Result<T, Error>, context: &str) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("Error! {}: {}", context, e);
+        if let Some(hint) = hint(&e) {
+            eprintln!("Hint: {}", hint);
+        }
+        process::exit(exit_code(&e))
+    })
+}
+
+// Suggests what to do next about the most common errors.
+fn hint(error: &Error) -> Option<&'static str> {
+    match error.root() {
+        Error::AccountDeactivated(_) => Some("register a new account with a new account key"),
+        Error::AccountDoesNotExist => {
+            Some("run without --only-existing to register an account for the key")
+        }
+        Error::RateLimited(_) => {
+            Some("try the setup against --staging, its rate limits are higher")
+        }
+        Error::Unauthorized
+        | Error::IncorrectResponse
+        | Error::Connection
+        | Error::ChallengesFailed(_) => Some(
+            "check that the DNS records of the domain point to this host and that it's \
+             reachable from the internet",
+        ),
+        Error::DnsError => Some("check that the DNS records of the domain have propagated"),
+        Error::CaaError => Some("allow the CA in the CAA records of the domain"),
+        Error::ClockSkew(_) => Some("synchronize the clock of this host, e.g. with NTP"),
+        Error::PrivilegedPort(_) => Some(
+            "or serve the challenges from a web server on a high port behind a redirect \
+             and hand them over with --challenge-socket",
+        ),
+        _ => None,
+    }
+}
+
+// The options of the modes that only use the account, without an issuance.
+fn account_options(args: &Args, account_key: Option<PKey<Private>>) -> IssuanceOptions {
+    IssuanceOptions {
+        verbose: args.verbose,
+        state_path: args.state.clone(),
+        profile: args.profile.clone(),
+        only_existing: args.only_existing,
+        account_key,
+        account_key_type: args.account_key_type.unwrap_or(args.key_type),
+        account_key_bits: Some(args.key_bits),
+        client: build_client(args),
+        ..Default::default()
+    }
+}
+
+// The directory URL of the CA to use.
+fn server(args: &Args) -> &str {
+    match (&args.server, args.staging) {
+        (Some(server), _) => server,
+        (None, true) => LETS_ENCRYPT_STAGING,
+        (None, false) => LETS_ENCRYPT_SERVER,
+    }
+}
+
+// Prints the steps of --manual and waits for enter on stdin.
+#[derive(Debug)]
+struct TerminalPrompt;
+
+impl ManualPrompt for TerminalPrompt {
+    fn present(&self, steps: &[ManualStep]) -> Result<(), Error> {
+        for step in steps {
+            match step {
+                ManualStep::ServeFile {
+                    domain,
+                    url,
+                    contents,
+                } => {
+                    println!("Serve a file for {}:", domain);
+                    println!("  URL:      {}", url);
+                    println!("  Contents: {}", contents);
+                }
+                ManualStep::TxtRecord {
+                    domain,
+                    name,
+                    value,
+                } => {
+                    println!("Create a TXT record for {}:", domain);
+                    println!("  Name:  {}", name);
+                    println!("  Value: {}", value);
+                }
+            }
+        }
+
+        println!("Press enter once everything is in place to let the CA validate it");
+        std::io::stdin().read_line(&mut String::new())?;
+        Ok(())
+    }
+}
+
+// Prints the decoded header and payload of the JWS in the file and whether its signature
+// verifies against the key, exits with 1 if it doesn't.
+fn decode_jws_file(path: &Path, key: Option<&Path>) {
+    let jws = exit_on_error(
+        std::fs::read(path)
+            .map_err(Error::from)
+            .and_then(|json| serde_json::from_slice(&json).map_err(Error::from))
+            .and_then(|jws| decode_jws(&jws)),
+        "Unable to decode the JWS",
+    );
+
+    let pretty = |json: &serde_json::Value| serde_json::to_string_pretty(json).unwrap_or_default();
+    println!("Header:\n{}", pretty(&jws.header));
+    match &jws.payload {
+        Some(payload) => println!("Payload:\n{}", pretty(payload)),
+        None => println!("Payload: empty (POST-as-GET)"),
+    }
+
+    if let Some(key) = key {
+        let valid = exit_on_error(
+            load_public_key_from_file(key).and_then(|key| jws.verify(&key)),
+            "Unable to check the signature",
+        );
+        println!("Signature: {}", if valid { "valid" } else { "invalid" });
+        if !valid {
+            process::exit(EXIT_FAILURE);
+        }
+    }
+}
+
+// Builds the client with the retry policy, debug dump, draft mode and TLS verification of the
+// arguments.
+fn build_client(args: &Args) -> Client {
+    let builder = Client::builder()
+        .retry_policy(RetryPolicy::new(
+            args.max_retries,
+            Duration::from_secs(args.retry_base_delay),
+            Duration::from_secs(args.poll_interval),
+        ))
+        .acme_draft(args.acme_draft)
+        .accept_invalid_certs(args.insecure);
+    match &args.debug_dump {
+        Some(dir) => builder.debug_dump(dir),
+        None => builder,
+    }
+    .build()
+}
+
+// The outcome of a single check of `--self-test`.
+enum Check {
+    Pass(String),
+    Fail(String),
+    Skip(String),
+}
+
+impl Check {
+    fn from_result(result: Result<String, Error>) -> Self {
+        result.map_or_else(|e| Check::Fail(e.to_string()), Check::Pass)
+    }
+}
+
+// Checks that the challenge can be presented the way the arguments ask for.
+fn check_challenge_environment(args: &Args) -> Check {
+    let port_free = |port: u16, purpose: &str| {
+        if check_for_existing_server_on(port) {
+            Check::Fail(format!("port {} is already in use", port))
+        } else {
+            Check::Pass(format!("port {} is free for {}", port, purpose))
+        }
+    };
+
+    if args.manual {
+        return Check::Skip("the challenges are presented manually".to_owned());
+    }
+    if args.pre_placed {
+        return Check::Skip("the challenge responses are placed out of band".to_owned());
+    }
+
+    match args.challenge.candidates(args.standalone)[0] {
+        ChallengeType::TlsAlpn01 => port_free(443, "the tls-alpn-01 responder"),
+        ChallengeType::Dns01 => Check::Fail("dns-01 can only be solved with --manual".to_owned()),
+        ChallengeType::Http01 => match &args.challenge_socket {
+            Some(path) if path.exists() => Check::Pass(format!("{:?} exists", path)),
+            Some(path) => Check::Fail(format!("{:?} does not exist", path)),
+            None if args.standalone => port_free(80, "the standalone server"),
+            None if !check_for_existing_server() => {
+                Check::Fail("no web server listens on port 80".to_owned())
+            }
+            None => Check::from_result(
+                check_web_root().map(|_| format!("the web root {} is writable", WEB_ROOT)),
+            ),
+        },
+    }
+}
+
+// Checks that the CA will be able to reach the domain for the challenge. The standalone
+// responders only listen during the issuance, so their ports can't be probed yet.
+fn check_reachability(args: &Args, domain: &str) -> Check {
+    let challenge_type = if args.manual {
+        args.challenge.manual_candidates()[0]
+    } else {
+        args.challenge.candidates(args.standalone)[0]
+    };
+    if args.standalone && challenge_type != ChallengeType::Dns01 {
+        return Check::Skip("the standalone responder only listens during the issuance".to_owned());
+    }
+
+    match verify_reachability(&[domain], challenge_type).remove(0) {
+        (_, Ok(())) => Check::Pass(format!(
+            "{} is reachable for {}",
+            domain,
+            challenge_type.as_str()
+        )),
+        (_, Err(e)) => Check::Fail(e.to_string()),
+    }
+}
+
+// Checks everything short of an issuance, prints the checklist and returns whether all
+// checks passed.
+fn self_test(args: &Args, domain: &str, server: &str) -> bool {
+    let client = build_client(args);
+    let mut checks = Vec::new();
+
+    let directory = Directory::fetch_dir(&client, server);
+    checks.push((
+        "ACME directory is reachable",
+        match &directory {
+            Ok(_) => Check::Pass(server.to_owned()),
+            Err(e) => Check::Fail(e.to_string()),
+        },
+    ));
+
+    checks.push((
+        "Clock is in sync with the CA",
+        match directory.as_ref().map(|directory| directory.clock_skew) {
+            Ok(Some(skew)) if skew.num_seconds().abs() > MAX_CLOCK_SKEW_SECS => {
+                Check::Fail(Error::ClockSkew(skew.num_seconds()).to_string())
+            }
+            Ok(Some(skew)) => Check::Pass(format!("{} seconds off", skew.num_seconds())),
+            Ok(None) => Check::Skip("the CA sent no Date header".to_owned()),
+            Err(_) => Check::Skip("the directory is unreachable".to_owned()),
+        },
+    ));
+
+    checks.push(("Challenge can be presented", check_challenge_environment(args)));
+
+    let domain = to_ascii_domain(domain);
+    checks.push((
+        "Domain resolves",
+        match &domain {
+            Ok(domain) => match dns::resolves(domain) {
+                Ok(true) => Check::Pass(format!("{} has an A or AAAA record", domain)),
+                Ok(false) => Check::Fail(format!("{} has no A or AAAA record", domain)),
+                Err(e) => Check::Fail(e.to_string()),
+            },
+            Err(e) => Check::Fail(e.to_string()),
+        },
+    ));
+    checks.push((
+        "Domain is reachable for the challenge",
+        match &domain {
+            Ok(domain) => check_reachability(args, domain),
+            Err(e) => Check::Fail(e.to_string()),
+        },
+    ));
+
+    // fall back to the identities the CA declares in its directory
+    let identities = match (&args.caa_identity, &directory) {
+        (Some(identity), _) => vec![identity.clone()],
+        (None, Ok(directory)) => directory.caa_identities(),
+        (None, Err(_)) => Vec::new(),
+    };
+    checks.push((
+        "CAA records permit the CA",
+        match &domain {
+            Ok(_) if identities.is_empty() => {
+                Check::Skip("unknown CA identity, pass --caa-identity".to_owned())
+            }
+            Ok(domain) => match dns::check_caa(domain, &identities) {
+                Ok(true) => Check::Pass(format!("{} may issue", identities.join(", "))),
+                Ok(false) => Check::Fail(format!(
+                    "{} is not permitted to issue",
+                    identities.join(", ")
+                )),
+                Err(e) => Check::Fail(e.to_string()),
+            },
+            Err(_) => Check::Skip("the domain is invalid".to_owned()),
+        },
+    ));
+
+    checks.push((
+        "Account key loads",
+        match (&args.account_key, &args.state) {
+            (Some(source), _) => match load_account_key(source) {
+                Ok(_) => Check::Pass(format!("loaded from {}", source)),
+                Err(e) => Check::Fail(e.to_string()),
+            },
+            (None, Some(path)) => {
+                match State::load(path).and_then(|state| state.account_key(server)) {
+                    Ok(Some(_)) => Check::Pass(format!("loaded from {:?}", path)),
+                    Ok(None) => Check::Skip("no account for this server yet".to_owned()),
+                    Err(e) => Check::Fail(e.to_string()),
+                }
+            }
+            (None, None) => {
+                Check::Skip("no --state, a new account key will be generated".to_owned())
+            }
+        },
+    ));
+
+    if let (Some(priv_path), Some(pub_path)) = (&args.private_key, &args.public_key) {
+        checks.push((
+            "Certificate keys load",
+            Check::from_result(
+                load_keys_from_file(priv_path, pub_path).map(|_| priv_path.clone()),
+            ),
+        ));
+    }
+
+    let mut passed = true;
+    for (name, check) in checks {
+        match check {
+            Check::Pass(detail) => println!("[PASS] {}: {}", name, detail),
+            Check::Skip(reason) => println!("[SKIP] {}: {}", name, reason),
+            Check::Fail(reason) => {
+                passed = false;
+                println!("[FAIL] {}: {}", name, reason);
+            }
+        }
+    }
+
+    passed
+}
+
+// Catches server URLs that can't be an ACME directory before any request is sent.
+fn check_server_url(server: &str, insecure: bool) -> Result<(), String> {
+    let url = Url::parse(server).map_err(|e| format!("{} is not a valid URL: {}", server, e))?;
+    match url.scheme() {
+        "https" => {}
+        "http" if insecure => {}
+        "http" => {
+            return Err(format!(
+                "{} doesn't use https, pass --insecure for a local test CA",
+                server
+            ))
+        }
+        scheme => return Err(format!("{} uses the unsupported scheme {}", server, scheme)),
+    }
+    if url.path() == "/" {
+        return Err(format!(
+            "{} has no path, directories live at a path like /directory",
+            server
+        ));
+    }
+
+    Ok(())
+}
+
+// Prints a usage error and exits with `EXIT_USAGE`.
+fn usage_error(app: &mut clap::App, kind: clap::ErrorKind, message: &str) -> ! {
+    let _ = app.error(kind, message).print();
+    process::exit(EXIT_USAGE)
 }
 
 fn main() {
     // parse the cmd arguments
-    let args: Args = Args::parse();
+    let args: Args = Args::try_parse().unwrap_or_else(|e| match e.kind() {
+        clap::ErrorKind::DisplayHelp | clap::ErrorKind::DisplayVersion => e.exit(),
+        _ => {
+            let _ = e.print();
+            process::exit(EXIT_USAGE)
+        }
+    });
     let mut app = Args::into_app();
 
-    if args.verbose {
-        // setup the logger if necessary
-        Logger::with_str("info")
-            .log_target(flexi_logger::LogTarget::StdOut)
-            .start()
-            .unwrap_or_else(|e| panic!("Logger initialization failed with {}", e));
+    if let Some(server) = args.server.as_deref() {
+        if let Err(message) = check_server_url(server, args.insecure) {
+            usage_error(
+                &mut app,
+                clap::ErrorKind::InvalidValue,
+                &format!("Error! {}", message),
+            );
+        }
+    }
+
+    // setup the logger, everything is logged to stderr to keep stdout clean for scripts
+    let log_level = if args.quiet {
+        "error"
+    } else if args.verbose {
+        "info, acme=debug"
+    } else {
+        "info"
+    };
+    if let Err(e) = Logger::with_str(log_level)
+        .log_target(flexi_logger::LogTarget::StdErr)
+        .start()
+    {
+        eprintln!("Error! Logger initialization failed: {}", e);
+        process::exit(1);
+    }
+
+    for name in &args.openssl_provider {
+        exit_on_error(
+            load_openssl_provider(name),
+            "Unable to load the OpenSSL provider",
+        );
+    }
+
+    if let Some(Command::TxtValue {
+        domain,
+        token,
+        account_key,
+    }) = args.command.as_ref()
+    {
+        let domain = exit_on_error(to_ascii_domain(domain), "Invalid domain");
+        let value = load_account_key(account_key)
+            .and_then(|key| compute_key_authorization(token, &key))
+            .map(|key_authorisation| dns_txt_value(&key_authorisation));
+        let value = exit_on_error(value, "Unable to compute the TXT value");
+        // the record name goes to stderr to keep stdout to the bare value for scripts
+        info!(
+            "TXT record _acme-challenge.{}",
+            domain.trim_start_matches("*.")
+        );
+        println!("{}", value);
+        return;
+    }
+    if let Some(Command::DecodeJws { jws, key }) = args.command.as_ref() {
+        decode_jws_file(jws, key.as_deref());
+        return;
+    }
+    // print the thumbprint for external challenge responders without issuing anything
+    if let Some(path) = args.print_thumbprint.as_ref() {
+        let thumbprint = load_account_key(path).and_then(|key| thumbprint(&key));
+        println!("{}", exit_on_error(thumbprint, "Unable to compute the thumbprint"));
+        return;
+    }
+    // describe the account key for audits without issuing anything
+    if let Some(path) = args.account_info.as_ref() {
+        let info = exit_on_error(
+            load_account_key(path).and_then(|key| key_info(&key)),
+            "Unable to read the account key",
+        );
+        println!("Algorithm:  {}", info.algorithm);
+        println!("Size:       {} bits", info.bits);
+        if let Some(curve) = &info.curve {
+            println!("Curve:      {}", curve);
+        }
+        println!("Thumbprint: {}", info.thumbprint);
+        if info.algorithm == "RSA" && info.bits <= WEAK_RSA_KEY_BITS {
+            println!("The key is due for a rotation to a stronger one");
+        }
+        return;
+    }
+    // share the public key of the account without issuing anything
+    if let Some(path) = args.print_jwk.as_ref() {
+        let jwk = exit_on_error(
+            load_account_key(path).and_then(|key| jwk(&key)),
+            "Unable to compute the JWK",
+        );
+        println!("{}", jwk);
+        return;
+    }
+    // look at the CA without creating an account
+    if args.print_directory {
+        let server = server(&args);
+        let directory = exit_on_error(
+            Directory::fetch_dir(&build_client(&args), server),
+            "Unable to fetch the directory",
+        );
+        let json = exit_on_error(
+            serde_json::to_string_pretty(&directory).map_err(Error::from),
+            "Unable to print the directory",
+        );
+        println!("{}", json);
+        return;
+    }
+    // clap requires the domain without `--print-thumbprint`
+    let domain = args.domain.clone().unwrap_or_default();
+
+    // diagnose the environment without issuing anything
+    if args.self_test {
+        let server = server(&args);
+        if !self_test(&args, &domain, server) {
+            process::exit(EXIT_FAILURE);
+        }
+        return;
+    }
+    // check all the contacts before anything is sent to the CA
+    let contacts: Vec<&str> = args.email.iter().map(String::as_str).collect();
+    exit_on_error(contact_urls(&contacts), "Unable to use the contacts");
+    // --manual waits for enter on stdin, which reading the account key from it used up
+    if args.manual && args.account_key.as_deref() == Some("-") {
+        usage_error(
+            &mut app,
+            clap::ErrorKind::ArgumentConflict,
+            "Error! The account key can't be read from stdin with --manual",
+        );
+    }
+    // read the account key once, stdin can't be read again for a later request
+    let account_key = args
+        .account_key
+        .as_deref()
+        .map(|source| exit_on_error(load_account_key(source), "Unable to load the account key"));
+
+    // provision the account for later issuances without placing an order
+    if args.register_only {
+        let account = exit_on_error(
+            register_account(
+                server(&args),
+                &contacts,
+                &account_options(&args, account_key.clone()),
+            ),
+            "Unable to register the account",
+        );
+        println!("{}", account.account_location);
+        return;
+    }
+    // show which challenges the CA offers without issuing anything
+    if args.list_challenges {
+        let authorisations = exit_on_error(
+            offered_challenges(
+                &[&domain],
+                server(&args),
+                &contacts,
+                &account_options(&args, account_key.clone()),
+            ),
+            "Unable to list the challenges",
+        );
+        let authorisations: BTreeMap<_, _> = authorisations.into_iter().collect();
+        for (domain, authorisation) in authorisations {
+            let challenges: Vec<String> = authorisation
+                .challenges
+                .iter()
+                .map(|challenge| format!("{} ({:?})", challenge.challenge_type, challenge.status))
+                .collect();
+            println!("{}: {}", domain, challenges.join(", ").to_lowercase());
+        }
+        return;
     }
 
     if args.csr_path.is_some() && (args.private_key.is_none() || args.public_key.is_none()) {
-        app.error(
+        usage_error(
+            &mut app,
             clap::ErrorKind::ArgumentConflict,
             r#"Error! If you provide a CSR you must also specify the keypair
                         that signed the CSR via --private-key and --public-key"#,
-        )
-        .exit();
+        );
+    }
+
+    let client = build_client(&args);
+    // skip the issuance if the certificate from a previous run is still fresh
+    if let Some(path) = args.state.as_ref().filter(|_| !args.force_renew) {
+        let state = exit_on_error(State::load(path), "Unable to load the state file");
+        if state.is_fresh(&domain, client.clock()) {
+            info!("Certificate for {} is still fresh, skipping", domain);
+            return;
+        }
     }
 
     // create a new key pair or otherwise read from a file
     let keypair_for_cert = match (args.private_key.as_ref(), args.public_key.as_ref()) {
         (Some(priv_path), Some(pub_path)) => load_keys_from_file(priv_path, pub_path),
-        (Some(_), None) | (None, Some(_)) => app
-            .error(
-                clap::ErrorKind::ArgumentConflict,
-                "Error! Provide both a public and a private key!",
-            )
-            .exit(),
+        (Some(_), None) | (None, Some(_)) => usage_error(
+            &mut app,
+            clap::ErrorKind::ArgumentConflict,
+            "Error! Provide both a public and a private key!",
+        ),
 
-        (None, None) => generate_rsa_keypair(),
-    }
-    .expect("Could not generate keypair");
+        (None, None) => generate_keypair_of_size(args.key_type, args.key_bits),
+    };
+    let keypair_for_cert = exit_on_error(keypair_for_cert, "Could not generate keypair");
 
     let optional_csr = args
         .csr_path
-        .map(|path| load_csr_from_file(&path).expect("Error loading the CSR"));
+        .as_ref()
+        .map(|path| exit_on_error(load_csr_from_file(path), "Error loading the CSR"));
 
-    if args.verbose && optional_csr.is_some() {
-        info!("Successfully loaded CSR");
+    if let Some(csr) = &optional_csr {
+        let csr_info = exit_on_error(describe_csr(csr), "Unable to read the CSR");
+        info!("Successfully loaded CSR: {:#?}", csr_info);
+        // the keypair is saved with the certificate, so it has to be the one of the CSR
+        let public_key = exit_on_error(
+            csr.public_key().map_err(Error::from),
+            "Unable to read the CSR",
+        );
+        if !public_key.public_eq(&keypair_for_cert.1) {
+            usage_error(
+                &mut app,
+                clap::ErrorKind::ArgumentConflict,
+                "Error! The CSR wasn't created for the keypair of --private-key and --public-key",
+            );
+        }
     }
 
-    if args.standalone && check_for_existing_server() {
-        app.error(
-            clap::ErrorKind::DisplayHelp,
-            "Error! Provided the standalone option with a process already listening on port 80",
-        )
-        .exit();
+    // also when http-01 is picked automatically, e.g. because port 443 is taken
+    let candidates = args.challenge.candidates(true);
+    let needs_port_80 = args.standalone && candidates.contains(&ChallengeType::Http01);
+    if needs_port_80 && check_for_existing_server() {
+        if candidates.len() == 1 {
+            usage_error(
+                &mut app,
+                clap::ErrorKind::DisplayHelp,
+                "Error! Provided the standalone option with a process already listening on port 80",
+            );
+        }
+        warn!("A process is already listening on port 80, http-01 can't be used as a fallback");
+    }
+    if !args.manual && args.challenge == ChallengeStrategy::Only(ChallengeType::Dns01) {
+        usage_error(
+            &mut app,
+            clap::ErrorKind::ArgumentConflict,
+            "Error! The dns-01 challenge can only be solved with --manual",
+        );
+    }
+    // nobody could answer the TLS handshakes of the CA for the user
+    if args.manual && args.challenge == ChallengeStrategy::Only(ChallengeType::TlsAlpn01) {
+        usage_error(
+            &mut app,
+            clap::ErrorKind::ArgumentConflict,
+            "Error! The tls-alpn-01 challenge can't be solved with --manual",
+        );
     }
 
+    let server = server(&args).to_owned();
+    let options = IssuanceOptions {
+        standalone: args.standalone,
+        challenge_socket: args.challenge_socket,
+        presentation: if let Some(auth_hook) = args.manual_auth_hook {
+            Presentation::Hooks {
+                auth_hook,
+                cleanup_hook: args.manual_cleanup_hook,
+                wait_for_propagation: args.wait_for_propagation,
+            }
+        } else if args.manual {
+            Presentation::Manual {
+                prompt: Arc::new(TerminalPrompt),
+                wait_for_propagation: args.wait_for_propagation,
+            }
+        } else if args.pre_placed {
+            Presentation::PrePlaced {
+                self_check: args.self_check,
+                grace_period: Duration::from_secs(args.self_check_grace),
+            }
+        } else {
+            Presentation::Automatic
+        },
+        challenge: args.challenge,
+        verbose: args.verbose,
+        csr_digest: args.csr_digest,
+        csr_subject: args.csr_subject,
+        csr_extensions: args.csr_extension,
+        state_path: args.state,
+        profile: args.profile,
+        caa_identity: args.caa_identity,
+        only_existing: args.only_existing,
+        account_key,
+        account_key_type: args.account_key_type.unwrap_or(args.key_type),
+        account_key_bits: Some(args.key_bits),
+        strict_clock: args.strict_clock,
+        strict_keys: args.strict_keys,
+        preferred_chain: args.preferred_chain,
+        verify_chain: args.verify_chain,
+        client,
+    };
+
     // Get the certificate for the domain.
-    let cert_chain = match args.server {
-        Some(url) => generate_certificate_for_domain(
-            &keypair_for_cert,
-            optional_csr,
-            args.domain,
-            url,
-            args.email,
-            args.standalone,
-            args.verbose,
-        ),
-        None => generate_certificate_for_domain(
-            &keypair_for_cert,
-            optional_csr,
-            args.domain,
-            LETS_ENCRYPT_SERVER.to_owned(),
-            args.email,
-            args.standalone,
-            args.verbose,
-        ),
-    }
-    .expect("Error during creation");
+    info!("Requesting a certificate for {}", domain);
+    let cert_chain = generate_certificate_for_domain(
+        &keypair_for_cert,
+        optional_csr,
+        domain.as_str(),
+        server.as_str(),
+        &contacts,
+        &options,
+    );
+    let cert_chain = exit_on_error(cert_chain, "Error during creation");
 
     // save the certificate and keypair to files
-    save_certificates(cert_chain).expect("Unable to save certificate");
+    if let Some(dir) = args.der_out.as_ref() {
+        let paths = exit_on_error(
+            save_certificates_der(&cert_chain, dir),
+            "Unable to save the DER certificates",
+        );
+        info!("Saved the certificate chain as DER to {:?}", paths);
+    }
+    // where the certificate and its key end up, for the deploy hook
+    let (cert_path, mut key_path) = if let Some(format) = args.format {
+        // the generated key goes next to the certificates unless it's printed
+        let (dir, private_key) = match args.key_out.as_deref() {
+            Some("-") => (".", None),
+            dir => (
+                dir.unwrap_or("."),
+                args.public_key.is_none().then_some(&keypair_for_cert.0),
+            ),
+        };
+        let paths = exit_on_error(
+            save_bundle(&cert_chain, private_key, format, Path::new(dir)),
+            "Unable to save the certificate",
+        );
+        info!("Saved the certificate for {:?} to {:?}", format, paths);
+        let key_path = paths.iter().find(|path| path.ends_with("privkey.pem"));
+        (paths[0].clone(), key_path.cloned())
+    } else {
+        exit_on_error(
+            save_certificates(cert_chain.clone()),
+            "Unable to save certificate",
+        );
+        info!("Saved the certificate to my_cert.crt and the chain to cert_chain.crt");
+        (PathBuf::from("my_cert.crt"), None)
+    };
+    if let Some(path) = args.private_key.as_ref() {
+        key_path = Some(PathBuf::from(path));
+    }
     if args.public_key.as_ref().is_none() {
-        save_keypair(&keypair_for_cert).expect("Unable to save keypair");
+        match args.key_out.as_deref() {
+            Some("-") => {
+                let (private_key, public_key) =
+                    exit_on_error(keypair_to_pem(&keypair_for_cert), "Unable to encode keypair");
+                print!("{}{}", private_key, public_key);
+            }
+            _ if args.format.is_some() => {}
+            dir => {
+                let dir = Path::new(dir.unwrap_or("."));
+                exit_on_error(save_keypair(&keypair_for_cert, dir), "Unable to save keypair");
+                info!("Saved the keypair to priv.pem and pub.pem in {:?}", dir);
+                key_path = Some(dir.join("priv.pem"));
+            }
+        }
+    }
+    // only the deploy hook and the JSON output need the details, a certificate that can't be
+    // read back is still saved
+    let cert_info = if args.deploy_hook.is_some() || args.output == Output::Json {
+        match describe_certificate(&cert_chain) {
+            Ok(cert_info) => {
+                info!(
+                    "Issued the certificate with serial {} expiring at {}",
+                    cert_info.serial, cert_info.not_after
+                );
+                Some(cert_info)
+            }
+            Err(e) => {
+                warn!("Unable to read the certificate: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(command) = args.deploy_hook.as_deref() {
+        let mut variables = vec![("ACME_CERT_PATH", cert_path.display().to_string())];
+        if let Some(cert_info) = &cert_info {
+            variables.push(("ACME_DOMAINS", cert_info.domains.join(" ")));
+            variables.push(("ACME_SERIAL", cert_info.serial.clone()));
+            variables.push(("ACME_EXPIRY", cert_info.not_after.to_rfc3339()));
+        }
+        if let Some(path) = &key_path {
+            variables.push(("ACME_KEY_PATH", path.display().to_string()));
+        }
+        exit_on_error(run_hook(command, &variables), "The deploy hook failed");
+        info!("The deploy hook succeeded");
+    }
+    if let Some(cert_info) = cert_info.filter(|_| args.output == Output::Json) {
+        let json = exit_on_error(
+            serde_json::to_string_pretty(&cert_info).map_err(Error::from),
+            "Unable to print the certificate",
+        );
+        println!("{}", json);
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
 
+    #[test]
+    fn a_rate_limit_of_the_ca_exits_with_its_code() {
+        // a CA that answers the first request with a rate-limit problem document
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/directory", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]);
+            let problem = r#"{"type": "urn:ietf:params:acme:error:rateLimited"}"#;
+            let response = format!(
+                "HTTP/1.1 429 Too Many Requests\r\nContent-Type: application/problem+json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                problem.len(),
+                problem
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        let client = Client::builder()
+            .retry_policy(RetryPolicy::new(0, Duration::ZERO, Duration::ZERO))
+            .build();
 
-
+        let error = Directory::fetch_dir(&client, &url).unwrap_err();
+        assert!(matches!(error, Error::RateLimited(_)));
+        assert_eq!(exit_code(&error), EXIT_RATE_LIMITED);
+    }
+}
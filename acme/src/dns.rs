@@ -1,42 +1,192 @@
-use trust_dns_proto::DnsStreamHandle;
-use trust_dns_client::client::{Client, ClientConnection, SyncClient};
+use trust_dns_client::client::{Client, SyncClient};
 use trust_dns_client::udp::UdpClientConnection;
 
-use std::net::Ipv4Addr;
+use log::info;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use trust_dns_client::op::DnsResponse;
+use trust_dns_client::rr::rdata::caa::{Value, CAA};
 use trust_dns_client::rr::{DNSClass, Name, RData, Record, RecordType};
 
-// Wrap this in a function
-fn main(){
-
-    let address = "8.8.8.8:53".parse().unwrap();
-    let conn = UdpClientConnection::new(address).unwrap();
-    
-    // and then create the Client
-    let client = SyncClient::new(conn);    
-
-    // Specify the name, note the final '.' which specifies it's an FQDN
-    let name = Name::from_str("www.example.com.").unwrap();
-
-    // NOTE: see 'Setup a connection' example above
-    // Send the query and get a message response, see RecordType for all supported options
-    let response: DnsResponse = client.query(&name, DNSClass::IN, RecordType::A).unwrap();
-
-    // Messages are the packets sent between client and server in DNS.
-    //  there are many fields to a Message, DnsResponse can be dereferenced into
-    //  a Message. It's beyond the scope of these examples
-    //  to explain all the details of a Message. See trust_dns_client::op::message::Message for more details.
-    //  generally we will be interested in the Message::answers
-    let answers: &[Record] = response.answers();
-
-    // Records are generic objects which can contain any data.
-    //  In order to access it we need to first check what type of record it is
-    //  In this case we are interested in A, IPv4 address
-    if let Some(RData::A(ref ip)) = answers[0].data() {
-        assert_eq!(*ip, Ipv4Addr::new(93, 184, 216, 34))
+use crate::error::{Error, Result};
+use crate::http;
+
+// The resolver that answers the lookups of the client.
+const DEFAULT_RESOLVER: &str = "8.8.8.8:53";
+// How long to look for a new TXT record, the `dns_propagation` backoff of the client says
+// how often.
+const PROPAGATION_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Queries the records of a type for a name, e.g. the CAA records of a domain.
+pub fn lookup(name: &Name, record_type: RecordType) -> Result<Vec<Record>> {
+    let address = DEFAULT_RESOLVER.parse().map_err(|_| Error::DnsError)?;
+    let conn = UdpClientConnection::new(address).map_err(|_| Error::DnsError)?;
+    let client = SyncClient::new(conn);
+
+    let response: DnsResponse = client
+        .query(name, DNSClass::IN, record_type)
+        .map_err(|_| Error::DnsError)?;
+
+    Ok(response.answers().to_vec())
+}
+
+// Checks whether a domain has an A or AAAA record, i.e. whether the CA can reach it.
+pub fn resolves(domain: &str) -> Result<bool> {
+    let mut name = Name::from_str(domain).map_err(|_| Error::DnsError)?;
+    name.set_fqdn(true);
+
+    for record_type in [RecordType::A, RecordType::AAAA] {
+        if !lookup(&name, record_type)?.is_empty() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// Checks whether the zone of a domain has a nameserver that resolves, the CA asks it for
+// the `_acme-challenge` record of a dns-01 challenge. The zone is the closest ancestor of
+// the domain that has NS records.
+pub fn has_nameservers(domain: &str) -> Result<bool> {
+    let mut name = Name::from_str(domain).map_err(|_| Error::DnsError)?;
+    name.set_fqdn(true);
+
+    loop {
+        let nameservers: Vec<String> = lookup(&name, RecordType::NS)?
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::NS(nameserver)) => Some(nameserver.to_string()),
+                _ => None,
+            })
+            .collect();
+        if !nameservers.is_empty() {
+            for nameserver in nameservers {
+                if resolves(&nameserver)? {
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
+        if name.is_root() {
+            return Ok(false);
+        }
+        name = name.base_name();
+    }
+}
+
+// Checks whether a TXT record with the value is visible under the name, e.g. the
+// `_acme-challenge` record of a dns-01 challenge.
+pub fn verify_txt_record(name: &str, value: &str) -> Result<bool> {
+    let mut name = Name::from_str(name).map_err(|_| Error::DnsError)?;
+    name.set_fqdn(true);
+
+    Ok(lookup(&name, RecordType::TXT)?
+        .iter()
+        .any(|record| match record.data() {
+            Some(RData::TXT(txt)) => {
+                let data: Vec<u8> = txt
+                    .txt_data()
+                    .iter()
+                    .flat_map(|data| data.to_vec())
+                    .collect();
+                data == value.as_bytes()
+            }
+            _ => false,
+        }))
+}
+
+// Polls the resolver until the TXT record is visible, telling the user about every
+// miss. Fails after `PROPAGATION_TIMEOUT` or once the issuance gets cancelled, the CA
+// would only see the same missing record.
+pub fn wait_for_txt_record(client: &http::Client, name: &str, value: &str) -> Result<()> {
+    let deadline = Instant::now() + PROPAGATION_TIMEOUT;
+    let mut attempt = 0;
+    loop {
+        client.check_cancelled()?;
+        if verify_txt_record(name, value)? {
+            info!("{} is visible at {}", name, DEFAULT_RESOLVER);
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::WithDetail(
+                Box::new(Error::DnsError),
+                format!(
+                    "TXT record {} not visible after {} seconds",
+                    name,
+                    PROPAGATION_TIMEOUT.as_secs()
+                ),
+            ));
+        }
+
+        info!(
+            "{} not yet visible at {}, retrying...",
+            name, DEFAULT_RESOLVER
+        );
+        let delay = client.retry_policy().dns_propagation.delay(attempt, None);
+        client.sleep(delay.min(deadline - now))?;
+        attempt += 1;
+    }
+}
+
+// Checks whether the CAA records of a domain permit the CA with any of the given
+// identities (e.g. `letsencrypt.org`) to issue a certificate for it (RFC 8659). The
+// records of the closest ancestor that has any decide, without records every CA is
+// permitted.
+pub fn check_caa(domain: &str, ca_identities: &[String]) -> Result<bool> {
+    let wildcard = domain.starts_with("*.");
+    let mut name = Name::from_str(domain.trim_start_matches("*.")).map_err(|_| Error::DnsError)?;
+    name.set_fqdn(true);
+
+    loop {
+        let records: Vec<CAA> = lookup(&name, RecordType::CAA)?
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::CAA(caa)) => Some(caa.clone()),
+                _ => None,
+            })
+            .collect();
+        if !records.is_empty() {
+            return Ok(ca_identities
+                .iter()
+                .any(|identity| caa_permits(&records, identity, wildcard)));
+        }
+        if name.is_root() {
+            return Ok(true);
+        }
+        name = name.base_name();
+    }
+}
+
+// Evaluates a CAA record set for a CA. Wildcard domains use the `issuewild` records if
+// there are any and the `issue` records otherwise.
+fn caa_permits(records: &[CAA], ca_identity: &str, wildcard: bool) -> bool {
+    // a critical property we don't understand forbids the issuance altogether
+    if records
+        .iter()
+        .any(|caa| caa.issuer_critical() && caa.tag().is_unknown())
+    {
+        return false;
+    }
+
+    let issue_wild: Vec<&CAA> = records
+        .iter()
+        .filter(|caa| caa.tag().is_issuewild())
+        .collect();
+    let relevant = if wildcard && !issue_wild.is_empty() {
+        issue_wild
     } else {
-        assert!(false, "unexpected result")
+        records.iter().filter(|caa| caa.tag().is_issue()).collect()
+    };
+    if relevant.is_empty() {
+        return true;
     }
 
+    relevant.iter().any(|caa| match caa.value() {
+        Value::Issuer(Some(issuer), _) => issuer
+            .to_string()
+            .trim_end_matches('.')
+            .eq_ignore_ascii_case(ca_identity),
+        _ => false,
+    })
 }
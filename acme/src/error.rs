@@ -1,7 +1,9 @@
 use std::io;
+use std::time::Duration;
 use thiserror::Error;
 
 use openssl::error::ErrorStack;
+#[cfg(feature = "client")]
 use reqwest::header::ToStrError;
 
 #[derive(Error, Debug)]
@@ -9,6 +11,8 @@ use reqwest::header::ToStrError;
 pub enum Error {
     #[error("Account does not exist")]
     AccountDoesNotExist,
+    #[error("The account is {0}, which can't be undone, register a new account with a new key")]
+    AccountDeactivated(String),
     #[error("Certificate thas already been revoked")]
     AlreadyRevokedCertificate,
     #[error("The CSR is unacceptable")]
@@ -27,24 +31,34 @@ pub enum Error {
     Compound,
     #[error("Server could not connect to validation target")]
     Connection,
+    #[error("The CSR extension {0} is given twice or conflicts with the SANs of the client")]
+    CsrExtensionConflict(String),
     #[error("Problem with a DNS query")]
     DnsError,
     #[error("The request must include a value for the \"externalAccountBinding\" field")]
     ExternalAccountRequired,
     #[error("Response received didn't match the challenge's requirements")]
     IncorrectResponse,
+    #[error("{0} is not a valid domain name")]
+    InvalidDomain(String),
     #[error("Invalid contact URL for account")]
     InvalidContact,
+    #[error("Invalid contacts: {}", describe_failures(.0))]
+    InvalidContacts(Vec<(String, Error)>),
     #[error("The request message was malformed")]
     MalformedRequest,
+    #[error("The order has no authorizations, it may already be ready to be finalized")]
+    NoAuthorizations,
     #[error("Finalize an order that is not ready to be finalized")]
     OrderNotReady,
-    #[error("Exceeds rate limit")]
-    RateLimited,
+    #[error("Exceeds rate limit, {}", retry_guidance(.0))]
+    RateLimited(Option<Duration>),
     #[error("Not issue certificates for the identifier")]
     RejectedIdentifier,
     #[error("Internal error")]
     InternalServerError,
+    #[error("The ACME server is temporarily unavailable, {}", retry_guidance(.0))]
+    ServiceUnavailable(Option<Duration>),
     #[error("TLS error during validation")]
     TlsError,
     #[error("Insufficient authorization")]
@@ -57,20 +71,82 @@ pub enum Error {
     UserActionRequired,
     #[error("Error reading the string: {0}")]
     FromUtf8Error(#[from] std::str::Utf8Error),
+    #[cfg(feature = "client")]
     #[error("Error in reqwest: {0}")]
     FromReqwestError(#[from] reqwest::Error),
     #[error("Error in openssl: {0}")]
     FromRsaError(#[from] ErrorStack),
     #[error("Error while de/encoding json: {0}")]
     FromSerdeError(#[from] serde_json::Error),
+    #[cfg(feature = "client")]
     #[error("Error writing header value: {0}")]
     FromToStrError(#[from] ToStrError),
+    #[cfg(feature = "client")]
+    #[error("Error rebuilding the response: {0}")]
+    FromHttpError(#[from] http::Error),
     #[error("IO error {0}")]
     FromIoError(#[from] io::Error),
     #[error("Currently just http challenges are allowed, so this error is raised if no http challenge is present")]
     NoHttpChallengePresent,
     #[error("There was no web server found")]
     NoWebServer,
+    #[error("Binding port {0} needs privileges, grant CAP_NET_BIND_SERVICE or run as root")]
+    PrivilegedPort(u16),
+    #[error("None of the offered challenges can be solved")]
+    NoSupportedChallengePresent,
+    #[error("No ACME directory found at the given URL")]
+    NoDirectory,
+    #[error("The certificate profile {0} is not offered by the server")]
+    InvalidProfile(String),
+    #[error("{0} is not a known output format, use nginx or apache")]
+    InvalidFormat(String),
+    #[error("The OpenSSL provider {0} is not available")]
+    ProviderUnavailable(String),
+    #[error("The downloaded certificate chain doesn't validate: {0}")]
+    InvalidChain(String),
+    #[error("The certificate is for the account key, use a separate key for TLS")]
+    AccountKeyReused,
+    #[error("The issuance was cancelled")]
+    Cancelled,
+    #[error("The hook `{0}` failed with {1}")]
+    HookFailed(String, String),
+    #[error("The local clock is {0} seconds off the ACME server's")]
+    ClockSkew(i64),
+    #[error("The challenges of some identifiers failed: {}", describe_failures(.0))]
+    ChallengesFailed(Vec<(String, Error)>),
+    #[error("None of the CAs issued the certificate: {}", describe_failures(.0))]
+    AllServersFailed(Vec<(String, Error)>),
+    #[error("{0} (CA request id: {1})")]
+    WithRequestId(Box<Error>, String),
+    #[error("{0}: {1}")]
+    WithDetail(Box<Error>, String),
+}
+
+impl Error {
+    // The error without the request id or detail that might be attached to it.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::WithRequestId(error, _) | Error::WithDetail(error, _) => error.root(),
+            error => error,
+        }
+    }
+}
+
+// Tells the user when to try again after the server was unavailable.
+fn retry_guidance(retry_after: &Option<Duration>) -> String {
+    match retry_after {
+        Some(delay) => format!("retry after {} seconds", delay.as_secs()),
+        None => "retry later".to_owned(),
+    }
+}
+
+// Lists why each of the CAs or identifiers failed.
+fn describe_failures(failures: &[(String, Error)]) -> String {
+    failures
+        .iter()
+        .map(|(server, error)| format!("{}: {}", server, error))
+        .collect::<Vec<_>>()
+        .join("; ")
 }
 
 pub(crate) type Result<T> = std::result::Result<T, Error>;
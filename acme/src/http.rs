@@ -0,0 +1,633 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::HeaderMap;
+use reqwest::{ResponseBuilderExt, StatusCode};
+
+use crate::error::{Error, Result};
+use crate::util::{check_problem, retry_after, Clock, SystemClock};
+
+// The content type of JWS requests as required by RFC 8555.
+pub const JOSE_CONTENT_TYPE: &str = "application/jose+json";
+// The default format of a downloaded certificate chain.
+pub const PEM_CERTIFICATE_CHAIN: &str = "application/pem-certificate-chain";
+
+// Setting the flag aborts a running issuance at the next poll or retry.
+pub type CancellationToken = Arc<AtomicBool>;
+
+// How often a sleeping poll loop checks whether it got cancelled.
+const CANCELLATION_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// The defaults of the `RetryPolicy`.
+pub const DEFAULT_MAX_RETRIES: usize = 10;
+pub const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// The longest backoff between two retries of a request.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+// How often the self-check fetches a pre-placed response again during its grace period.
+const SELF_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+// How often the resolver is asked again for a TXT record that isn't visible yet.
+const PROPAGATION_POLL_INTERVAL: Duration = Duration::from_secs(10);
+// How long an idle connection to the CA is kept for the next request. The requests of a
+// flow are at most a few poll intervals apart, so they all share one TLS handshake.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+// Keeps idle pooled connections from being dropped by NAT gateways and load balancers.
+const TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+
+// How long a step of the flow waits before its next attempt, e.g. before the next poll of
+// an order. `attempt` counts from 0, `retry_after` is the delay the server asked for if it
+// sent a `Retry-After` header.
+pub trait Backoff: fmt::Debug + Send + Sync {
+    fn delay(&self, attempt: usize, retry_after: Option<Duration>) -> Duration;
+}
+
+// Waits the same time before every attempt, `Fixed(Duration::ZERO)` doesn't wait at all,
+// e.g. in tests against a local CA.
+#[derive(Clone, Copy, Debug)]
+pub struct Fixed(pub Duration);
+
+impl Backoff for Fixed {
+    fn delay(&self, _attempt: usize, _retry_after: Option<Duration>) -> Duration {
+        self.0
+    }
+}
+
+// Waits `base` before the first attempt and doubles the delay for every further one, up
+// to `max`.
+#[derive(Clone, Copy, Debug)]
+pub struct Exponential {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Backoff for Exponential {
+    fn delay(&self, attempt: usize, _retry_after: Option<Duration>) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.min(u32::MAX as usize) as u32);
+        self.base
+            .checked_mul(factor)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+// Waits as long as the server asked for and falls back to the inner backoff if it didn't.
+#[derive(Clone, Copy, Debug)]
+pub struct RespectRetryAfter<B>(pub B);
+
+impl<B: Backoff> Backoff for RespectRetryAfter<B> {
+    fn delay(&self, attempt: usize, retry_after: Option<Duration>) -> Duration {
+        retry_after.unwrap_or_else(|| self.0.delay(attempt, None))
+    }
+}
+
+// Controls how often failed requests are retried and how orders and authorizations are
+// polled, e.g. to be more patient in flaky networks or with slow CAs. Every step has its
+// own backoff, so each can be tuned without affecting the others.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // How often a request is retried and how often a pending resource is polled.
+    pub max_retries: usize,
+    // Between two attempts of an unsigned request that failed in transit or on the server.
+    pub requests: Arc<dyn Backoff>,
+    // Between two attempts of an unsigned request the server refused with its rate limit.
+    pub rate_limits: Arc<dyn Backoff>,
+    // Between a signed request the server rejected as `badNonce` and its retry.
+    pub nonces: Arc<dyn Backoff>,
+    // Between two polls of an authorization whose challenge is being validated.
+    pub challenges: Arc<dyn Backoff>,
+    // Between two polls of an order that's being processed.
+    pub orders: Arc<dyn Backoff>,
+    // Between two attempts of a certificate download that broke off.
+    pub downloads: Arc<dyn Backoff>,
+    // Between two fetches of a pre-placed challenge response by the self-check.
+    pub self_checks: Arc<dyn Backoff>,
+    // Between two lookups of a dns-01 TXT record that hasn't propagated yet.
+    pub dns_propagation: Arc<dyn Backoff>,
+}
+
+impl RetryPolicy {
+    // Retries with an exponential backoff starting at `base_delay` and polls every
+    // `poll_interval`, both unless the server asks for a specific delay. The self-check and
+    // the DNS lookups keep their own intervals.
+    pub fn new(max_retries: usize, base_delay: Duration, poll_interval: Duration) -> Self {
+        let exponential = Exponential {
+            base: base_delay,
+            max: MAX_RETRY_DELAY,
+        };
+        RetryPolicy {
+            max_retries,
+            requests: Arc::new(RespectRetryAfter(exponential)),
+            rate_limits: Arc::new(RespectRetryAfter(exponential)),
+            nonces: Arc::new(exponential),
+            challenges: Arc::new(RespectRetryAfter(Fixed(poll_interval))),
+            orders: Arc::new(RespectRetryAfter(Fixed(poll_interval))),
+            downloads: Arc::new(exponential),
+            self_checks: Arc::new(Fixed(SELF_CHECK_INTERVAL)),
+            dns_propagation: Arc::new(Fixed(PROPAGATION_POLL_INTERVAL)),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(
+            DEFAULT_MAX_RETRIES,
+            DEFAULT_RETRY_BASE_DELAY,
+            DEFAULT_POLL_INTERVAL,
+        )
+    }
+}
+
+// What the server reported about its rate limit in the `RateLimit-*` or `X-RateLimit-*`
+// headers of a response, not every CA sends them. See `schedule::rate_limit_status` for a
+// fallback that counts the past issuances instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    // How many requests the limit allows within its window.
+    pub limit: Option<u64>,
+    // How many of them are left.
+    pub remaining: Option<u64>,
+    // How long until the window resets.
+    pub reset: Option<Duration>,
+}
+
+// The HTTP client that talks to the ACME server. The headers default to what RFC 8555
+// requires, but can be overridden through the builder to work around CA quirks.
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::blocking::Client,
+    content_type: String,
+    accept_certificate: String,
+    cancellation: CancellationToken,
+    retry: RetryPolicy,
+    // The `newNonce` endpoint of the directory, used when a response carried no nonce.
+    new_nonce_url: Arc<Mutex<Option<String>>>,
+    // The rate limit reported by the last response that carried one.
+    rate_limit: Arc<Mutex<Option<RateLimitStatus>>>,
+    debug_dump: Option<DebugDump>,
+    acme_draft: bool,
+    // The `resource` names of the directory's endpoints, for servers of the ACME drafts.
+    resources: Arc<Mutex<HashMap<String, String>>>,
+    clock: Arc<dyn Clock>,
+}
+
+// Writes the signed requests and their responses to numbered files in a directory, e.g.
+// `001-request.txt` and `001-response.txt`, to attach to a bug report for the CA.
+#[derive(Clone, Debug)]
+struct DebugDump {
+    dir: PathBuf,
+    // Shared by the clones of a client, so their files don't overwrite each other.
+    count: Arc<AtomicUsize>,
+}
+
+// Builds a `Client` with custom settings.
+#[derive(Debug, Default)]
+pub struct ClientBuilder {
+    http: Option<reqwest::blocking::Client>,
+    content_type: Option<String>,
+    accept_certificate: Option<String>,
+    cancellation: Option<CancellationToken>,
+    retry: Option<RetryPolicy>,
+    debug_dump: Option<PathBuf>,
+    acme_draft: bool,
+    accept_invalid_certs: bool,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl ClientBuilder {
+    // Uses a preconfigured reqwest client, e.g. with a proxy or custom root certificates.
+    pub fn http_client(mut self, http: reqwest::blocking::Client) -> Self {
+        self.http = Some(http);
+        self
+    }
+
+    // Overrides the `Content-Type` of JWS requests, e.g. to add a charset.
+    pub fn content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    // Overrides the `Accept` header used when downloading the certificate.
+    pub fn accept_certificate(mut self, accept: impl Into<String>) -> Self {
+        self.accept_certificate = Some(accept.into());
+        self
+    }
+
+    // Lets a supervising application cancel the issuance by setting the token.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    // Overrides how requests are retried and resources are polled.
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    // Writes every signed request and its response to a file in the directory, with the
+    // signatures redacted.
+    pub fn debug_dump(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.debug_dump = Some(dir.into());
+        self
+    }
+
+    // Talks to a server of the pre-RFC 8555 ACME drafts, which expects the `resource` that
+    // a request is meant for in its payload and, in the early drafts, has no orders.
+    pub fn acme_draft(mut self, acme_draft: bool) -> Self {
+        self.acme_draft = acme_draft;
+        self
+    }
+
+    // Skips the verification of the server's certificate, e.g. for a local test CA with a
+    // self-signed one. Has no effect on a preconfigured `http_client`.
+    pub fn accept_invalid_certs(mut self, accept_invalid_certs: bool) -> Self {
+        self.accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    // Takes the current time from the clock, e.g. to detect a skew against the server at a
+    // fixed time.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn build(self) -> Client {
+        Client {
+            http: self
+                .http
+                .unwrap_or_else(|| pooled_http_client(self.accept_invalid_certs)),
+            content_type: self
+                .content_type
+                .unwrap_or_else(|| JOSE_CONTENT_TYPE.to_owned()),
+            accept_certificate: self
+                .accept_certificate
+                .unwrap_or_else(|| PEM_CERTIFICATE_CHAIN.to_owned()),
+            cancellation: self.cancellation.unwrap_or_default(),
+            retry: self.retry.unwrap_or_default(),
+            new_nonce_url: Arc::default(),
+            rate_limit: Arc::default(),
+            debug_dump: self.debug_dump.map(|dir| DebugDump {
+                dir,
+                count: Arc::default(),
+            }),
+            acme_draft: self.acme_draft,
+            resources: Arc::default(),
+            clock: self.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+        }
+    }
+}
+
+// The reqwest client used unless the builder got a preconfigured one. A single `Client` is
+// meant to be passed through the whole flow, so its pool can reuse the connection. It
+// decompresses the responses, some CDNs in front of CAs compress the JSON.
+fn pooled_http_client(accept_invalid_certs: bool) -> reqwest::blocking::Client {
+    reqwest::blocking::Client::builder()
+        .danger_accept_invalid_certs(accept_invalid_certs)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE)
+        .gzip(true)
+        .deflate(true)
+        .brotli(true)
+        .build()
+        .unwrap_or_default()
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::builder().build()
+    }
+}
+
+impl Client {
+    // Creates a client with the default settings.
+    pub fn new() -> Self {
+        Client::default()
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    // Fails with `Cancelled` if the cancellation token was set.
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.cancellation.load(Ordering::Relaxed) {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Waits between two attempts of a poll or retry, returning early if the
+    // cancellation token gets set in the meantime.
+    pub fn sleep(&self, delay: Duration) -> Result<()> {
+        let deadline = Instant::now() + delay;
+        loop {
+            self.check_cancelled()?;
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            std::thread::sleep(CANCELLATION_CHECK_INTERVAL.min(deadline - now));
+        }
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    // The current time of the client's clock.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    // The clock the client takes the current time from, e.g. for the renewal check.
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    // The rate limit the server reported last, `None` if none of its responses had
+    // rate-limit headers. Lets a long-running daemon slow down before it's `RateLimited`.
+    pub fn rate_limit_status(&self) -> Option<RateLimitStatus> {
+        *self.rate_limit.lock().unwrap()
+    }
+
+    // Whether the server follows the ACME drafts instead of RFC 8555.
+    pub fn is_acme_draft(&self) -> bool {
+        self.acme_draft
+    }
+
+    // Remembers the `resource` name of an endpoint of a draft server, e.g. `new-reg`.
+    pub fn set_resource(&self, url: &str, resource: &str) {
+        self.resources
+            .lock()
+            .unwrap()
+            .insert(url.to_owned(), resource.to_owned());
+    }
+
+    // The `resource` name of the endpoint, if it's one of the directory of a draft server.
+    pub fn resource(&self, url: &str) -> Option<String> {
+        self.resources.lock().unwrap().get(url).cloned()
+    }
+
+    // Remembers the `newNonce` endpoint of the directory the client talks to.
+    pub fn set_new_nonce_url(&self, url: &str) {
+        *self.new_nonce_url.lock().unwrap() = Some(url.to_owned());
+    }
+
+    // Fetches a fresh nonce from the `newNonce` endpoint, fails with `BadNonce` if
+    // no directory was fetched yet or the server didn't send one.
+    pub fn fresh_nonce(&self) -> Result<String> {
+        let url = self
+            .new_nonce_url
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(Error::BadNonce)?;
+
+        Ok(self
+            .head(&url)?
+            .headers()
+            .get("replay-nonce")
+            .ok_or(Error::BadNonce)?
+            .to_str()?
+            .to_owned())
+    }
+
+    // Sends a plain GET request.
+    pub fn get(&self, url: &str) -> Result<Response> {
+        self.send_with_retry(|| self.http.get(url))
+    }
+
+    // Sends a plain HEAD request.
+    pub fn head(&self, url: &str) -> Result<Response> {
+        self.send_with_retry(|| self.http.head(url))
+    }
+
+    // Sends a request until it succeeds, backing off on network errors and on responses
+    // that ask to try again later. Only used for requests without a nonce, a signed
+    // request can't be replayed as is.
+    fn send_with_retry(&self, request: impl Fn() -> RequestBuilder) -> Result<Response> {
+        let mut retry = 0;
+        loop {
+            let result = request().send();
+            let retryable = match &result {
+                Ok(response) => {
+                    response.status().is_server_error()
+                        || response.status() == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(e) => e.is_connect() || e.is_timeout(),
+            };
+            if !retryable || retry >= self.retry.max_retries {
+                return self.observe(result?);
+            }
+
+            let retry_after = result.as_ref().ok().and_then(retry_after);
+            let backoff = match &result {
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    &self.retry.rate_limits
+                }
+                _ => &self.retry.requests,
+            };
+            let delay = backoff.delay(retry, retry_after);
+            self.sleep(delay)?;
+            retry += 1;
+        }
+    }
+
+    // POSTs a JWS to the server.
+    pub fn post_jws(&self, url: &str, jws: &serde_json::Value) -> Result<Response> {
+        let request = self
+            .http
+            .post(url)
+            .header("Content-Type", &self.content_type)
+            .body(serde_json::to_string_pretty(jws)?);
+        self.observe(self.send_dumped(request, url, jws)?)
+    }
+
+    // POSTs a JWS to download a certificate chain.
+    pub fn post_jws_for_certificate(
+        &self,
+        url: &str,
+        jws: &serde_json::Value,
+    ) -> Result<Response> {
+        let request = self
+            .http
+            .post(url)
+            .header("Content-Type", &self.content_type)
+            .header("Accept", &self.accept_certificate)
+            .body(serde_json::to_string_pretty(jws)?);
+        self.observe(self.send_dumped(request, url, jws)?)
+    }
+
+    // POSTs the JWS `sign` creates with the given nonce, or with a fresh one for `None`.
+    // While the server rejects the nonce as `badNonce` the request is signed again with the
+    // nonce that came with the rejection (RFC 8555 section 6.5), after waiting as long as
+    // the `nonces` backoff says.
+    pub fn post_signed(
+        &self,
+        url: &str,
+        sign: impl Fn(Option<String>) -> Result<serde_json::Value>,
+    ) -> Result<Response> {
+        self.retry_bad_nonce(|nonce| self.post_jws(url, &sign(nonce)?))
+    }
+
+    // Like `post_signed`, but to download a certificate chain.
+    pub fn post_signed_for_certificate(
+        &self,
+        url: &str,
+        sign: impl Fn(Option<String>) -> Result<serde_json::Value>,
+    ) -> Result<Response> {
+        self.retry_bad_nonce(|nonce| self.post_jws_for_certificate(url, &sign(nonce)?))
+    }
+
+    fn retry_bad_nonce(
+        &self,
+        post: impl Fn(Option<String>) -> Result<Response>,
+    ) -> Result<Response> {
+        let mut nonce = None;
+        let mut retry = 0;
+        loop {
+            let response = post(nonce.take())?;
+            if response.status() != StatusCode::BAD_REQUEST || retry >= self.retry.max_retries {
+                return Ok(response);
+            }
+            let (response, body) = buffered(response)?;
+            let problem: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+            // the drafts call it `urn:acme:error:badNonce`
+            let bad_nonce = problem["type"]
+                .as_str()
+                .is_some_and(|problem_type| problem_type.ends_with(":badNonce"));
+            if !bad_nonce {
+                return Ok(response);
+            }
+
+            warn!("The server rejected the nonce, retrying with a new one");
+            // an empty nonce is replaced by a fresh one when signing
+            let replay_nonce = response
+                .headers()
+                .get("replay-nonce")
+                .and_then(|nonce| nonce.to_str().ok());
+            nonce = Some(replay_nonce.unwrap_or_default().to_owned());
+            self.sleep(self.retry.nonces.delay(retry, retry_after(&response)))?;
+            retry += 1;
+        }
+    }
+
+    // Sends a signed request, writing it and its response to the debug dump if there is one.
+    fn send_dumped(
+        &self,
+        request: RequestBuilder,
+        url: &str,
+        jws: &serde_json::Value,
+    ) -> Result<Response> {
+        let dump = match &self.debug_dump {
+            Some(dump) => dump,
+            None => return Ok(request.send()?),
+        };
+        let number = dump.count.fetch_add(1, Ordering::Relaxed) + 1;
+        fs::create_dir_all(&dump.dir)?;
+
+        // the signature is all that's needed to replay the request while its nonce is fresh
+        let mut redacted = jws.clone();
+        redacted["signature"] = "REDACTED".into();
+        let request = request.build()?;
+        let mut transcript = format!("POST {}\n", url);
+        write_headers(&mut transcript, request.headers());
+        transcript.push('\n');
+        transcript.push_str(&serde_json::to_string_pretty(&redacted)?);
+        fs::write(
+            dump.dir.join(format!("{:03}-request.txt", number)),
+            transcript,
+        )?;
+
+        let (response, body) = buffered(self.http.execute(request)?)?;
+        let mut transcript = format!("{:?} {}\n", response.version(), response.status());
+        write_headers(&mut transcript, response.headers());
+        transcript.push('\n');
+        transcript.push_str(&String::from_utf8_lossy(&body));
+        fs::write(
+            dump.dir.join(format!("{:03}-response.txt", number)),
+            transcript,
+        )?;
+
+        Ok(response)
+    }
+
+    // Remembers the rate limit a response reports before passing it on.
+    fn observe(&self, response: Response) -> Result<Response> {
+        if let Some(status) = rate_limit_headers(&response) {
+            *self.rate_limit.lock().unwrap() = Some(status);
+        }
+
+        check_available(response)
+    }
+}
+
+// Reads the body of a response and rebuilds the response from its parts, the body can only
+// be read once.
+fn buffered(response: Response) -> Result<(Response, Vec<u8>)> {
+    let status = response.status();
+    let version = response.version();
+    let url = response.url().clone();
+    let headers = response.headers().clone();
+    let body = response.bytes()?;
+
+    let mut rebuilt = ::http::Response::builder()
+        .status(status)
+        .version(version)
+        .url(url)
+        .body(body.clone())?;
+    *rebuilt.headers_mut() = headers;
+    Ok((rebuilt.into(), body.to_vec()))
+}
+
+// Reads the rate-limit headers of a response, `None` if it has none. The values of the
+// IETF draft can carry parameters after the number, e.g. `100, 100;w=3600`, which are
+// ignored.
+fn rate_limit_headers(response: &Response) -> Option<RateLimitStatus> {
+    let header = |name: &str| {
+        [name.to_owned(), format!("x-{}", name)]
+            .iter()
+            .find_map(|name| response.headers().get(name.as_str())?.to_str().ok())
+            .and_then(|value| value.split([',', ';']).next()?.trim().parse::<u64>().ok())
+    };
+    let status = RateLimitStatus {
+        limit: header("ratelimit-limit"),
+        remaining: header("ratelimit-remaining"),
+        reset: header("ratelimit-reset").map(Duration::from_secs),
+    };
+
+    (status != RateLimitStatus::default()).then_some(status)
+}
+
+// Appends the headers to a transcript of the debug dump, one per line.
+fn write_headers(transcript: &mut String, headers: &HeaderMap) {
+    for (name, value) in headers {
+        let _ = writeln!(
+            transcript,
+            "{}: {}",
+            name,
+            String::from_utf8_lossy(value.as_bytes())
+        );
+    }
+}
+
+// Turns a 503 of a CA in maintenance into `ServiceUnavailable`, its body often isn't a
+// problem document that could be parsed, and a 429 into `RateLimited` whatever the request.
+fn check_available(response: Response) -> Result<Response> {
+    match response.status() {
+        StatusCode::SERVICE_UNAVAILABLE => Err(Error::ServiceUnavailable(retry_after(&response))),
+        StatusCode::TOO_MANY_REQUESTS => check_problem(response, |_| None),
+        _ => Ok(response),
+    }
+}
@@ -1,101 +1,991 @@
+#[cfg(feature = "client")]
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+#[cfg(feature = "client")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "client")]
+use std::time::Duration;
+
+#[cfg(feature = "client")]
 use error::Error;
-use log::info;
+#[cfg(feature = "client")]
+use log::{info, trace, warn};
+#[cfg(feature = "client")]
 use openssl::{
-    pkey::{Private, Public},
-    rsa::Rsa,
+    pkey::{Id, PKey, Private, Public},
     x509::X509Req,
 };
-use reqwest::blocking::Client;
-use acc::{Certificate, Directory};
-use util::generate_rsa_key;
+#[cfg(feature = "client")]
+use http::Client;
+#[cfg(feature = "client")]
+use acc::{
+    Account, Certificate, ChallengeAuthorisation, ChallengeStrategy, ChallengeType, Directory,
+    Order, Presentation,
+};
+#[cfg(feature = "client")]
+use state::State;
+#[cfg(feature = "client")]
+use util::{
+    certificate_domains, certificate_expiry, certificate_subjects, generate_account_key, has_scts,
+    identifier_type, request_csr, to_ascii_domain, verify_chain, CsrDigest, CsrExtension,
+    DomainName, KeyType, SubjectField,
+};
 
 // Common error module
 pub mod error;
 // All account creation and management
-mod acc;
+#[cfg(feature = "client")]
+pub mod acc;
 // Contains utility methods used in the acme context. 
 pub mod util;
+// Persistent state for idempotent re-runs
+pub mod state;
+// Standalone responder for the tls-alpn-01 challenge
+pub mod tls_alpn;
+// The HTTP client used to talk to the ACME server
+#[cfg(feature = "client")]
+pub mod http;
+
+// Batch issuance that paces orders within the rate limits of the CA
+#[cfg(feature = "client")]
+pub mod schedule;
 
-// Use the dns mod
+// DNS lookups, e.g. to check the CAA records of a domain
+#[cfg(feature = "client")]
 pub mod dns;
 
+// A local HTTP server that plays the CA in the tests
+#[cfg(all(test, feature = "client"))]
+mod mock;
+
 const KEY_WIDTH: u32 = 2048;
 
+// How many seconds the local clock may be off the server's before it's reported.
+#[cfg(feature = "client")]
+pub const MAX_CLOCK_SKEW_SECS: i64 = 60;
+
+// How long the reachability preflight waits for a port to accept a connection.
+#[cfg(feature = "client")]
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Settings that control how a certificate gets issued.
+#[cfg(feature = "client")]
+#[derive(Debug, Clone, Default)]
+pub struct IssuanceOptions {
+    // Start a standalone server to answer the challenge.
+    pub standalone: bool,
+    // Hand the http-01 challenges to an external web server through this Unix socket.
+    pub challenge_socket: Option<PathBuf>,
+    // Who presents the challenges, e.g. the user following printed instructions.
+    pub presentation: Presentation,
+    // Which challenge type to solve.
+    pub challenge: ChallengeStrategy,
+    // Log every step of the issuance.
+    pub verbose: bool,
+    // The digest used to sign the generated CSR.
+    pub csr_digest: CsrDigest,
+    // Additional fields of the subject of the generated CSR, e.g. `O=Example Inc.`.
+    pub csr_subject: Vec<SubjectField>,
+    // Additional extensions of the generated CSR, e.g. a certificate policy OID.
+    pub csr_extensions: Vec<CsrExtension>,
+    // A state file to reuse the account and resume interrupted orders across runs.
+    pub state_path: Option<PathBuf>,
+    // The certificate profile to request, e.g. `shortlived`.
+    pub profile: Option<String>,
+    // Check the CAA records of the domain for this CA identity, e.g. `letsencrypt.org`,
+    // instead of the identities the directory declares, before an order is created. Records
+    // that don't permit it fail the issuance, the check of the declared ones only warns.
+    pub caa_identity: Option<String>,
+    // Only use the account that's already registered for the key instead of creating one.
+    pub only_existing: bool,
+    // The account key to use instead of the one of the state file or a new one.
+    pub account_key: Option<PKey<Private>>,
+    // The type of a newly generated account key. Account and certificate keys are
+    // independent and may differ on purpose, e.g. an RSA account for an Ed25519 certificate.
+    // A CA that rejects a generated key of another type gets a RSA key instead.
+    pub account_key_type: KeyType,
+    // The RSA modulus of a newly generated account key in bits, `KEY_WIDTH` if not given.
+    pub account_key_bits: Option<u32>,
+    // Fail instead of warn if the local clock is too far off the server's.
+    pub strict_clock: bool,
+    // Fail instead of warn if the certificate is for the account key.
+    pub strict_keys: bool,
+    // Prefer the alternate chain that leads to the root with this common name, e.g.
+    // `ISRG Root X1`.
+    pub preferred_chain: Option<String>,
+    // Check that the downloaded chain holds together before it's returned.
+    pub verify_chain: bool,
+    // The client used for all requests, see `Client::builder` to work around CA quirks.
+    pub client: Client,
+}
+
 // Generate certificate for a given domain.
+#[cfg(feature = "client")]
 pub fn generate_certificate_for_domain<T: AsRef<str>>(
-    keypair_for_cert: &(Rsa<Private>, Rsa<Public>),
+    keypair_for_cert: &(PKey<Private>, PKey<Public>),
     optional_csr: Option<X509Req>,
     domain: T,
     server: T,
-    email: T,
-    standalone: bool,
-    verbose: bool,
+    contacts: &[T],
+    options: &IssuanceOptions,
 ) -> Result<Certificate, Error> {
-    let keypair = generate_rsa_key()?;
-    // create a new client 
-    let client = Client::new();
+    let contacts: Vec<&str> = contacts.iter().map(AsRef::as_ref).collect();
+    issue_certificate(
+        keypair_for_cert,
+        optional_csr,
+        &[domain.as_ref()],
+        server.as_ref(),
+        &contacts,
+        None,
+        options,
+    )
+}
 
-    // fetch the directory and create a new account
-    let dir_infos = Directory::fetch_dir(&client, server.as_ref())?;
-    let new_acc = dir_infos.create_account(&client, &keypair, email.as_ref())?;
-    if verbose {
-        info!("Created account: {:#?}", new_acc);
+// A certificate and the directory URL of the CA that issued it.
+#[cfg(feature = "client")]
+#[derive(Debug)]
+pub struct FallbackIssuance {
+    pub server: String,
+    pub certificate: Certificate,
+}
+
+// Tries to issue a certificate for the domains at each of the CAs in order, e.g. Let's
+// Encrypt first and then Buypass, until one of them succeeds. Accounts aren't portable
+// between CAs, so every CA gets its own, which the state file of the options keeps apart
+// by directory URL.
+#[cfg(feature = "client")]
+pub fn issue_with_fallback(
+    keypair_for_cert: &(PKey<Private>, PKey<Public>),
+    domains: &[&str],
+    servers: &[&str],
+    email: &str,
+    options: &IssuanceOptions,
+) -> Result<FallbackIssuance, Error> {
+    let mut failures = Vec::new();
+    for server in servers {
+        match issue_certificate(
+            keypair_for_cert,
+            None,
+            domains,
+            server,
+            &[email],
+            None,
+            options,
+        ) {
+            Ok(certificate) => {
+                if !failures.is_empty() {
+                    info!("Issued the certificate at {}", server);
+                }
+                return Ok(FallbackIssuance {
+                    server: server.to_string(),
+                    certificate,
+                });
+            }
+            Err(e) => {
+                warn!("Issuance at {} failed: {}", server, e);
+                failures.push((server.to_string(), e));
+            }
+        }
     }
 
-    // create a new order
-    let order = new_acc.create_new_order(
-        &client,
+    Err(Error::AllServersFailed(failures))
+}
+
+// Renews an existing certificate for exactly the domains in its SANs, signed by an
+// account key that's already registered at the CA.
+#[cfg(feature = "client")]
+pub fn renew_certificate(
+    cert_path: &Path,
+    keypair_for_cert: &(PKey<Private>, PKey<Public>),
+    account_key: &PKey<Private>,
+    server: &str,
+    options: &IssuanceOptions,
+) -> Result<Certificate, Error> {
+    let domains = certificate_domains(&std::fs::read_to_string(cert_path)?)?;
+    let domains: Vec<&str> = domains.iter().map(String::as_str).collect();
+    if options.verbose {
+        info!("Renewing the certificate for {:?}", domains);
+    }
+
+    issue_certificate(
+        keypair_for_cert,
+        None,
+        &domains,
+        server,
+        &[],
+        Some(account_key.clone()),
+        options,
+    )
+}
+
+// Registers an account at the CA, or only looks it up with `only_existing`, without
+// placing an order. The account key and URL are saved to the state file of the options,
+// from which a later issuance picks them up.
+#[cfg(feature = "client")]
+pub fn register_account(
+    server: &str,
+    contacts: &[&str],
+    options: &IssuanceOptions,
+) -> Result<Account, Error> {
+    let mut state = match &options.state_path {
+        Some(path) => Some(State::load(path)?),
+        None => None,
+    };
+    let (mut keypair, generated) = account_key_of(&state, server, options)?;
+
+    let dir_infos = Directory::fetch_dir(&options.client, server)?;
+    open_account(
+        &dir_infos,
+        &mut keypair,
+        generated,
+        server,
+        contacts,
+        &mut state,
+        options,
+    )
+}
+
+// Opens an order for the domains only to see which challenges the CA offers for each of
+// them and abandons it right away, e.g. to find out whether a wildcard leaves dns-01 as the
+// only option. The authorisations come with the domain they are for.
+#[cfg(feature = "client")]
+pub fn offered_challenges(
+    domains: &[&str],
+    server: &str,
+    contacts: &[&str],
+    options: &IssuanceOptions,
+) -> Result<Vec<(String, ChallengeAuthorisation)>, Error> {
+    let names = domains
+        .iter()
+        .map(|domain| domain.parse())
+        .collect::<Result<Vec<DomainName>, Error>>()?;
+    let domains: Vec<&str> = names.iter().map(|name| name.ascii.as_str()).collect();
+    let mut state = match &options.state_path {
+        Some(path) => Some(State::load(path)?),
+        None => None,
+    };
+    let (mut keypair, generated) = account_key_of(&state, server, options)?;
+    let client = &options.client;
+
+    let dir_infos = Directory::fetch_dir(client, server)?;
+    let account = open_account(
+        &dir_infos,
+        &mut keypair,
+        generated,
+        server,
+        contacts,
+        &mut state,
+        options,
+    )?;
+    let order = account.create_new_order(
+        client,
         &dir_infos.new_order,
         &keypair,
-        domain.as_ref(),
-        optional_csr,
+        &domains,
+        None,
+        options.profile.as_deref(),
     )?;
-    if verbose {
-        info!(
-            "Opened new order for domain {}: {:#?}",
-            domain.as_ref(),
-            &order
-        );
+    let mut opened = Abandoned {
+        order,
+        client,
+        account_url: &account.account_location,
+        p_key: &keypair,
+    };
+    let authorisations = opened.order.fetch_auth_challenges_by_domain(
+        client,
+        &account.account_location,
+        &keypair,
+    )?;
+    if let Some((_, authorisation)) = authorisations.last() {
+        opened.order.nonce = authorisation.nonce.clone();
+    }
+
+    Ok(authorisations)
+}
+
+// Abandons an order that was only opened to look at it when it goes out of scope, also if
+// looking at it failed, so its authorizations don't count against the limits of the CA.
+#[cfg(feature = "client")]
+struct Abandoned<'a> {
+    order: Order,
+    client: &'a Client,
+    account_url: &'a str,
+    p_key: &'a PKey<Private>,
+}
+
+#[cfg(feature = "client")]
+impl Drop for Abandoned<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self
+            .order
+            .abandon(self.client, self.account_url, self.p_key)
+        {
+            warn!(
+                "Unable to abandon the order {}: {}",
+                self.order.order_location, e
+            );
+        }
+    }
+}
+
+// Checks up front whether the CA will be able to reach each identifier of an order for
+// the challenge type: port 80 for http-01 and 443 for tls-alpn-01 have to accept
+// connections, dns-01 needs a nameserver of the zone that resolves and IP addresses have to
+// be publicly routable. Every identifier gets its own result, so all the ones that would
+// fail the validation show up at once.
+#[cfg(feature = "client")]
+pub fn verify_reachability(
+    identifiers: &[&str],
+    challenge_type: ChallengeType,
+) -> Vec<(String, Result<(), Error>)> {
+    identifiers
+        .iter()
+        .map(|identifier| {
+            let result = reachability(identifier, challenge_type);
+            (identifier.to_string(), result)
+        })
+        .collect()
+}
+
+#[cfg(feature = "client")]
+fn reachability(identifier: &str, challenge_type: ChallengeType) -> Result<(), Error> {
+    let wildcard = identifier.starts_with("*.");
+    if !challenge_type.supports(identifier_type(identifier))
+        || (wildcard && challenge_type != ChallengeType::Dns01)
+    {
+        return Err(Error::UnsupportedIdentifier);
     }
+    let unreachable =
+        |error: Error, detail: String| Err(Error::WithDetail(Box::new(error), detail));
 
-    // fetch the auth challenges
-    let challenge = order.fetch_auth_challenges(&client, &new_acc.account_location, &keypair)?;
-    if verbose {
-        info!(
-            "Got the following authorization challenges: {:#?}",
-            &challenge
+    if let Ok(ip) = identifier.parse::<IpAddr>() {
+        if is_publicly_routable(ip) {
+            return Ok(());
+        }
+        return unreachable(
+            Error::RejectedIdentifier,
+            format!("{} isn't publicly routable", ip),
         );
     }
 
-    // complete the challenge and save the nonce that's needed for further authentification
-    let new_nonce = challenge.solve_http_challenge(
-        &client,
-        &new_acc.account_location,
-        &keypair,
-        standalone,
+    let domain = to_ascii_domain(identifier)?;
+    let port = match challenge_type {
+        ChallengeType::Http01 => 80,
+        ChallengeType::TlsAlpn01 => 443,
+        ChallengeType::Dns01 => {
+            return match dns::has_nameservers(domain.trim_start_matches("*."))? {
+                true => Ok(()),
+                false => unreachable(
+                    Error::DnsError,
+                    format!("no nameserver of the zone of {} resolves", domain),
+                ),
+            }
+        }
+    };
+    let addresses = match (domain.as_str(), port).to_socket_addrs() {
+        Ok(addresses) => addresses,
+        Err(_) => return unreachable(Error::DnsError, format!("{} doesn't resolve", domain)),
+    };
+    for address in addresses {
+        if TcpStream::connect_timeout(&address, REACHABILITY_TIMEOUT).is_ok() {
+            return Ok(());
+        }
+    }
+    unreachable(
+        Error::Connection,
+        format!("port {} of {} doesn't accept connections", port, domain),
+    )
+}
+
+// Whether the CA can reach an IP address, i.e. it isn't private, loopback, link-local or
+// otherwise reserved.
+#[cfg(feature = "client")]
+fn is_publicly_routable(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let shared = ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64;
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || shared)
+        }
+        IpAddr::V6(ip) => {
+            let unique_local = ip.segments()[0] & 0xfe00 == 0xfc00;
+            let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+            !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+        }
+    }
+}
+
+// The account key of the options, else the one of a previous run at the CA if the state file
+// has one, a new key otherwise, and whether it's a new one.
+#[cfg(feature = "client")]
+fn account_key_of(
+    state: &Option<State>,
+    server: &str,
+    options: &IssuanceOptions,
+) -> Result<(PKey<Private>, bool), Error> {
+    let keypair = match (&options.account_key, state) {
+        (Some(account_key), _) => Some(account_key.clone()),
+        (None, Some(state)) => state.account_key(server)?,
+        (None, None) => None,
+    };
+    match keypair {
+        Some(keypair) => Ok((keypair, false)),
+        None => {
+            let bits = account_key_bits(options);
+            Ok((generate_account_key(options.account_key_type, bits)?, true))
+        }
+    }
+}
+
+// The RSA modulus of a new account key.
+#[cfg(feature = "client")]
+fn account_key_bits(options: &IssuanceOptions) -> u32 {
+    options.account_key_bits.unwrap_or(KEY_WIDTH)
+}
+
+// Creates the account of the key, or only looks it up with `only_existing`, and makes sure
+// it's the account the state file knows for the CA before the state file gets updated. A
+// `generated` key the CA rejects is replaced with a new RSA key.
+#[cfg(feature = "client")]
+fn open_account(
+    dir_infos: &Directory,
+    keypair: &mut PKey<Private>,
+    generated: bool,
+    server: &str,
+    contacts: &[&str],
+    state: &mut Option<State>,
+    options: &IssuanceOptions,
+) -> Result<Account, Error> {
+    let client = &options.client;
+    let mut account = if options.only_existing {
+        dir_infos.find_account(client, keypair)?
+    } else {
+        match dir_infos.create_account(client, keypair, contacts) {
+            Err(e)
+                if generated
+                    && keypair.id() != Id::RSA
+                    && matches!(e.root(), Error::BadSignatureAlgorithm) =>
+            {
+                warn!("{}, retrying with a RSA account key", e);
+                *keypair = generate_account_key(KeyType::Rsa, account_key_bits(options))?;
+                // the rejected request used up the nonce of the directory
+                Directory::fetch_dir(client, server)?.create_account(client, keypair, contacts)?
+            }
+            account => account?,
+        }
+    };
+    if options.verbose {
+        info!("Using account: {:#?}", account);
+    }
+
+    // make sure the key still controls the persisted account, the state file might have
+    // been mixed up with the one of another account
+    if let Some(url) = state.as_ref().and_then(|state| state.account_url(server)) {
+        if url != account.account_location {
+            return Err(Error::Unauthorized);
+        }
+        account.verify(client, keypair)?;
+    }
+
+    if let (Some(state), Some(path)) = (state.as_mut(), &options.state_path) {
+        state.set_account(server, &account.account_location, keypair)?;
+        state.save(path)?;
+    }
+
+    Ok(account)
+}
+
+// Runs the whole issuance for the given domains. The first domain is the common name
+// and identifies the order in the state file.
+#[cfg(feature = "client")]
+fn issue_certificate(
+    keypair_for_cert: &(PKey<Private>, PKey<Public>),
+    optional_csr: Option<X509Req>,
+    domains: &[&str],
+    server: &str,
+    contacts: &[&str],
+    account_key: Option<PKey<Private>>,
+    options: &IssuanceOptions,
+) -> Result<Certificate, Error> {
+    // the protocol uses the A-labels of internationalized domains, the log shows their U-labels
+    let names = domains
+        .iter()
+        .map(|domain| domain.parse())
+        .collect::<Result<Vec<DomainName>, Error>>()?;
+    let domains: Vec<&str> = names.iter().map(|name| name.ascii.as_str()).collect();
+    let domains = &domains[..];
+    let display_names: Vec<&str> = names.iter().map(|name| name.unicode.as_str()).collect();
+    let domain = *domains.first().ok_or(Error::MalformedRequest)?;
+    let verbose = options.verbose;
+    let mut state = match &options.state_path {
+        Some(path) => Some(State::load(path)?),
+        None => None,
+    };
+
+    // reuse the given account key or the one of a previous run if there is one
+    let (mut keypair, generated) = match account_key {
+        Some(account_key) => (account_key, false),
+        None => account_key_of(&state, server, options)?,
+    };
+    let client = &options.client;
+    // the certificate is for the key of the CSR if there is one
+    let cert_public_key = match &optional_csr {
+        Some(csr) => csr.public_key()?,
+        None => keypair_for_cert.1.clone(),
+    };
+    // a leaked TLS key would give away the account as well
+    if cert_public_key.public_eq(&keypair) {
+        if options.strict_keys {
+            return Err(Error::AccountKeyReused);
+        }
+        warn!("{}", Error::AccountKeyReused);
+    }
+
+    // fetch the directory and create a new account
+    let dir_infos = Directory::fetch_dir(client, server)?;
+    // a skewed clock is a common cause of otherwise mysterious badNonce or signature errors
+    if let Some(skew) = dir_infos
+        .clock_skew
+        .filter(|skew| skew.num_seconds().abs() > MAX_CLOCK_SKEW_SECS)
+    {
+        if options.strict_clock {
+            return Err(Error::ClockSkew(skew.num_seconds()));
+        }
+        warn!("{}", Error::ClockSkew(skew.num_seconds()));
+    }
+    if let Some(profile) = &options.profile {
+        dir_infos.check_profile(profile)?;
+    }
+    // check the CAA records for the identity the user gave or the ones the CA declares
+    let caa_identities = match &options.caa_identity {
+        Some(identity) => vec![identity.clone()],
+        None => dir_infos.caa_identities(),
+    };
+    if !caa_identities.is_empty() {
+        for domain in domains {
+            // CAA records only exist for domain names, not for IP addresses
+            if identifier_type(domain) == "ip" {
+                continue;
+            }
+            // the CA checks the records anyway, so only a pre-check the user asked for with
+            // `caa_identity` fails the issuance, e.g. the identities of the directory might
+            // not be the ones the records name
+            match dns::check_caa(domain, &caa_identities) {
+                Ok(true) => {}
+                Err(e) if options.caa_identity.is_some() => return Err(e),
+                Ok(false) if options.caa_identity.is_some() => return Err(Error::CaaError),
+                Ok(false) => warn!("{}: {}", domain, Error::CaaError),
+                Err(e) => warn!("Skipping the CAA check of {}: {}", domain, e),
+            }
+        }
+    }
+    let mut new_acc = open_account(
+        &dir_infos,
+        &mut keypair,
+        generated,
+        server,
+        contacts,
+        &mut state,
+        options,
     )?;
-    if verbose {
-        info!("Succesfully completed the http challenge");
+
+    if client.is_acme_draft() && !dir_infos.has_orders() {
+        let cert_chain = issue_without_order(
+            &dir_infos,
+            &mut new_acc,
+            &keypair,
+            keypair_for_cert,
+            optional_csr,
+            domains,
+            options,
+        )?;
+        return record_certificate(cert_chain, &cert_public_key, domain, &mut state, options);
     }
 
+    // resume an order that was interrupted in a previous run
+    let pending_order = state
+        .as_mut()
+        .and_then(|state| state.domain(domain).pending_order.clone());
+    let resumed_order = match pending_order {
+        Some(order_url) => match new_acc.fetch_order(client, &order_url, &keypair) {
+            Ok(order)
+                if !order.is_expired(client.clock())
+                    && (order.status == "pending" || order.status == "ready") =>
+            {
+                if order.has_identifiers(domains) {
+                    Some(order)
+                } else {
+                    // the domains changed since the order was opened, so its pending
+                    // authorizations would only count against the limits of the CA
+                    info!("Abandoning the pending order {}", order_url);
+                    new_acc.nonce = order.abandon(client, &new_acc.account_location, &keypair)?;
+                    None
+                }
+            }
+            _ => {
+                // the nonce might have been used up by the failed attempt
+                new_acc.nonce = dir_infos.fetch_nonce(client)?;
+                None
+            }
+        },
+        None => None,
+    };
+    let order = match resumed_order {
+        Some(mut order) => {
+            order.optional_csr = optional_csr;
+            if verbose {
+                info!(
+                    "Resumed order for domains {:?}: {:#?}",
+                    display_names, &order
+                );
+            }
+            order
+        }
+        None => {
+            // create a new order
+            let new_order = |account: &Account| {
+                account.create_new_order(
+                    client,
+                    &dir_infos.new_order,
+                    &keypair,
+                    domains,
+                    None,
+                    options.profile.as_deref(),
+                )
+            };
+            let mut order = match new_order(&new_acc) {
+                // the terms of service changed since the account agreed to them
+                Err(e) if matches!(e.root(), Error::UserActionRequired) => {
+                    new_acc.nonce = dir_infos.fetch_nonce(client)?;
+                    new_acc.agree_to_terms(client, &keypair)?;
+                    new_order(&new_acc)?
+                }
+                // the order might have been opened before its response got lost, it's
+                // among the open ones of the account then and opening another one would
+                // only count against the limits of the CA
+                Err(e) if outcome_unknown(&e) => {
+                    new_acc.nonce = dir_infos.fetch_nonce(client)?;
+                    let profile = options.profile.as_deref();
+                    match new_acc.find_open_order(client, &keypair, domains, profile) {
+                        Ok(Some(order)) => {
+                            info!("Reusing the order {}", order.order_location);
+                            order
+                        }
+                        Ok(None) => return Err(e),
+                        Err(search_error) => {
+                            warn!(
+                                "Unable to look through the orders of the account: {}",
+                                search_error
+                            );
+                            return Err(e);
+                        }
+                    }
+                }
+                order => order?,
+            };
+            order.optional_csr = optional_csr;
+            if verbose {
+                info!(
+                    "Opened new order for domains {:?}: {:#?}",
+                    display_names, &order
+                );
+            }
+            order
+        }
+    };
+
+    if let (Some(state), Some(path)) = (state.as_mut(), &options.state_path) {
+        state.domain(domain).pending_order = Some(order.order_location.clone());
+        state.save(path)?;
+    }
+
+    // an order whose authorizations are already valid can be finalized right away, some
+    // CAs even leave out the authorizations that were cached for the account
+    let new_nonce = if order.status == "ready" || order.authorizations.is_empty() {
+        order.nonce.clone()
+    } else {
+        // fetch the auth challenges
+        let challenges =
+            order.fetch_all_auth_challenges(client, &new_acc.account_location, &keypair)?;
+        if verbose {
+            for authorisation in &challenges {
+                info!(
+                    "Got the following authorization challenges for {}: {:#?}",
+                    authorisation
+                        .domain()
+                        .unwrap_or_else(|_| authorisation.identifier.to_string()),
+                    authorisation
+                );
+            }
+        }
+
+        // complete the challenges and save the nonce that's needed for further authentification
+        let new_nonce = ChallengeAuthorisation::solve_challenges(
+            challenges,
+            options.challenge,
+            client,
+            &new_acc,
+            &keypair,
+            options.standalone,
+            options.challenge_socket.as_deref(),
+            &options.presentation,
+        )?;
+        if verbose {
+            info!("Succesfully completed the challenges");
+        }
+        new_nonce
+    };
+
     // finalize the order to retrieve location of the final cert
-    let updated_order = order.finalize_order(
-        &client,
+    let mut updated_order = order.finalize_order(
+        client,
         &new_acc.account_location,
         new_nonce,
         &keypair,
         keypair_for_cert,
-        domain.as_ref(),
+        domains,
+        options.csr_digest,
+        &options.csr_subject,
+        &options.csr_extensions,
     )?;
 
     // download the certificate
-    let cert_chain =
-        updated_order.download_certificate(&client, &new_acc.account_location, &keypair)?;
-    if verbose {
-        info!("Received the following certificate chain: {}", cert_chain);
+    let cert_chain = updated_order.download_certificate(
+        client,
+        &new_acc.account_location,
+        &keypair,
+        options.preferred_chain.as_deref(),
+    )?;
+
+    record_certificate(cert_chain, &cert_public_key, domain, &mut state, options)
+}
+
+// Issues the certificate at a server of the early ACME drafts, which have no orders: the
+// account is authorized for every identifier on its own and then requests the certificate.
+#[cfg(feature = "client")]
+fn issue_without_order(
+    dir_infos: &Directory,
+    account: &mut Account,
+    keypair: &PKey<Private>,
+    keypair_for_cert: &(PKey<Private>, PKey<Public>),
+    optional_csr: Option<X509Req>,
+    domains: &[&str],
+    options: &IssuanceOptions,
+) -> Result<Certificate, Error> {
+    let client = &options.client;
+    let mut authorisations = Vec::new();
+    for domain in domains {
+        authorisations.push(account.new_authz(client, &dir_infos.new_authz, keypair, domain)?);
+    }
+    let nonce = ChallengeAuthorisation::solve_challenges(
+        authorisations,
+        options.challenge,
+        client,
+        account,
+        keypair,
+        options.standalone,
+        options.challenge_socket.as_deref(),
+        &options.presentation,
+    )?;
+
+    let csr = match optional_csr {
+        Some(csr) => csr,
+        None => request_csr(
+            keypair_for_cert,
+            domains,
+            options.csr_digest,
+            &options.csr_subject,
+            &options.csr_extensions,
+        )?,
+    };
+    account.new_cert(client, &dir_infos.new_cert, nonce, keypair, &csr)
+}
+
+// Whether a request might have been processed even though it failed, e.g. because the
+// connection broke off before the response arrived or the server failed while answering.
+#[cfg(feature = "client")]
+fn outcome_unknown(error: &Error) -> bool {
+    match error.root() {
+        Error::FromReqwestError(e) => e.status().is_none_or(|status| status.is_server_error()),
+        Error::InternalServerError | Error::ServiceUnavailable(_) => true,
+        _ => false,
+    }
+}
+
+// Logs and checks the issued certificate chain and records the issuance in the state file.
+#[cfg(feature = "client")]
+fn record_certificate(
+    cert_chain: Certificate,
+    cert_public_key: &PKey<Public>,
+    domain: &str,
+    state: &mut Option<State>,
+    options: &IssuanceOptions,
+) -> Result<Certificate, Error> {
+    if options.verbose {
+        // only logged, the chain is checked below if it should be
+        match certificate_subjects(&cert_chain) {
+            Ok(subjects) => info!(
+                "Received a certificate chain of {} certificates: {:#?}",
+                subjects.len(),
+                subjects
+            ),
+            Err(e) => warn!("Unable to read the subjects of the chain: {}", e),
+        }
+        match has_scts(&cert_chain) {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("The certificate embeds no SCTs, browsers enforcing CT may reject it")
+            }
+            Err(e) => warn!("Unable to look for the SCTs of the certificate: {}", e),
+        }
+    }
+    trace!("Received the following certificate chain: {}", cert_chain);
+    if options.verify_chain {
+        verify_chain(&cert_chain, cert_public_key)?;
+    }
+
+    if let (Some(state), Some(path)) = (state.as_mut(), &options.state_path) {
+        let domain_state = state.domain(domain);
+        domain_state.last_issued = Some(options.client.now());
+        domain_state.expires = Some(certificate_expiry(&cert_chain)?);
+        domain_state.pending_order = None;
+        state.save(path)?;
     }
 
     Ok(cert_chain)
 }
 
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockResponse, MockServer};
+    use crate::util::{check_problem, decode_jws, generate_keypair};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
 
+    // The error of a GET from a server that answers with the status.
+    fn error_of(status: u16) -> Error {
+        let server = MockServer::start(move |_| MockResponse::new(status));
+        mock::test_client()
+            .get(&server.url)
+            .and_then(|response| check_problem(response, |_| None))
+            .unwrap_err()
+    }
+
+    #[test]
+    fn outcome_unknown_only_for_requests_the_server_might_have_processed() {
+        assert!(outcome_unknown(&error_of(500)));
+        assert!(outcome_unknown(&error_of(503)));
+        assert!(!outcome_unknown(&error_of(400)));
+        assert!(!outcome_unknown(&Error::RateLimited(None)));
+
+        // a connection that couldn't be made, nothing listens on the discard port
+        let refused = mock::test_client().get("http://127.0.0.1:9/").unwrap_err();
+        assert!(outcome_unknown(&refused));
+    }
+
+    #[test]
+    fn an_order_that_was_only_looked_at_is_abandoned_when_dropped() {
+        let payloads = Arc::new(Mutex::new(Vec::new()));
+        let recorded = payloads.clone();
+        let server = MockServer::start(move |request| {
+            let jws = serde_json::from_slice(&request.body).unwrap();
+            recorded
+                .lock()
+                .unwrap()
+                .push(decode_jws(&jws).unwrap().payload);
+            let authorisation = json!({
+                "identifier": {"type": "dns", "value": "example.org"},
+                "status": "pending",
+                "expires": "2026-10-23T00:00:00Z",
+                "challenges": [],
+            });
+            MockResponse::json(200, authorisation)
+        });
+        let mut order: Order = serde_json::from_value(json!({
+            "status": "pending",
+            "identifiers": [{"type": "dns", "value": "example.org"}],
+            "authorizations": [format!("{}/authz/1", server.url)],
+            "finalize": format!("{}/finalize", server.url),
+        }))
+        .unwrap();
+        order.nonce = "nonce".to_owned();
+        let client = mock::test_client();
+        let (p_key, _) = generate_keypair(KeyType::Ed25519).unwrap();
+
+        // e.g. when fetching its authorisations failed
+        drop(Abandoned {
+            order,
+            client: &client,
+            account_url: "https://example.org/account/1",
+            p_key: &p_key,
+        });
+        assert_eq!(
+            *payloads.lock().unwrap(),
+            [None, Some(json!({"status": "deactivated"}))]
+        );
+    }
+
+    #[test]
+    fn a_generated_account_key_the_ca_rejects_is_replaced_with_a_rsa_key() {
+        let algorithms = Arc::new(Mutex::new(Vec::new()));
+        let recorded = algorithms.clone();
+        let server = MockServer::start(move |request| {
+            let endpoint = |path: &str| format!("{}{}", request.origin, path);
+            match request.path.as_str() {
+                "/directory" => MockResponse::json(
+                    200,
+                    json!({
+                        "newNonce": endpoint("/new-nonce"),
+                        "newAccount": endpoint("/new-account"),
+                        "newOrder": endpoint("/new-order"),
+                        "revokeCert": endpoint("/revoke-cert"),
+                        "keyChange": endpoint("/key-change"),
+                    }),
+                ),
+                "/new-nonce" => MockResponse::new(200).header("Replay-Nonce", "nonce"),
+                _ => {
+                    let jws = serde_json::from_slice(&request.body).unwrap();
+                    let algorithm = decode_jws(&jws).unwrap().header["alg"].clone();
+                    recorded.lock().unwrap().push(algorithm.clone());
+                    // like Let's Encrypt, which only takes RSA and ECDSA signed requests
+                    if algorithm == "EdDSA" {
+                        let problem = json!({
+                            "type": "urn:ietf:params:acme:error:badSignatureAlgorithm",
+                            "detail": "JWS signature header contains unsupported algorithm",
+                        });
+                        return MockResponse::json(400, problem);
+                    }
+                    MockResponse::json(201, json!({"status": "valid"}))
+                        .header("Location", &endpoint("/account/1"))
+                }
+            }
+        });
+        let options = IssuanceOptions {
+            account_key_type: KeyType::Ed25519,
+            client: mock::test_client(),
+            ..Default::default()
+        };
+
+        let directory_url = format!("{}/directory", server.url);
+        let account = register_account(&directory_url, &[], &options).unwrap();
+        assert_eq!(
+            account.account_location,
+            format!("{}/account/1", server.url)
+        );
+        assert_eq!(*algorithms.lock().unwrap(), ["EdDSA", "RS256"]);
+
+        // a key that was given is the user's choice and isn't replaced
+        let (account_key, _) = generate_keypair(KeyType::Ed25519).unwrap();
+        let options = IssuanceOptions {
+            account_key: Some(account_key),
+            ..options
+        };
+        let result = register_account(&directory_url, &[], &options);
+        assert!(matches!(result, Err(Error::BadSignatureAlgorithm)));
+    }
+}
@@ -0,0 +1,197 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use openssl::{
+    asn1::Asn1Time,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    x509::{X509NameBuilder, X509},
+};
+
+use crate::http::{Client, RetryPolicy};
+use crate::util::{generate_keypair, KeyType};
+
+pub(crate) struct MockRequest {
+    // The URL of the server without the path, e.g. for the endpoints of a directory.
+    pub origin: String,
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+pub(crate) struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockResponse {
+    pub fn new(status: u16) -> Self {
+        MockResponse {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    // A JSON body with a fresh nonce, like every response of a CA carries.
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        let mut response = MockResponse::new(status)
+            .header("Content-Type", "application/json")
+            .header("Replay-Nonce", "nonce");
+        response.body = body.to_string().into_bytes();
+        response
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    // Encodes the body with gzip like some CDNs in front of CAs do. The deflate stream is made
+    // of stored blocks, the test only needs a valid encoding and not a small one.
+    pub fn gzipped(mut self) -> Self {
+        let mut gzip = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        let mut blocks = self.body.chunks(u16::MAX as usize).peekable();
+        if blocks.peek().is_none() {
+            gzip.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(block) = blocks.next() {
+            let length = block.len() as u16;
+            gzip.push(blocks.peek().is_none() as u8);
+            gzip.extend_from_slice(&length.to_le_bytes());
+            gzip.extend_from_slice(&(!length).to_le_bytes());
+            gzip.extend_from_slice(block);
+        }
+        gzip.extend_from_slice(&crc32(&self.body).to_le_bytes());
+        gzip.extend_from_slice(&(self.body.len() as u32).to_le_bytes());
+
+        self.body = gzip;
+        self.header("Content-Encoding", "gzip")
+    }
+}
+
+// The CRC-32 of gzip, bit by bit.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+// Answers every request with the response the handler returns for it.
+pub(crate) struct MockServer {
+    pub url: String,
+}
+
+impl MockServer {
+    // Serves on a free port of the loopback interface until the test process exits.
+    pub fn start(handler: impl Fn(&MockRequest) -> MockResponse + Send + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let origin = url.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Some(request) = read_request(&stream, &origin) {
+                    write_response(stream, handler(&request));
+                }
+            }
+        });
+
+        MockServer { url }
+    }
+}
+
+// A client that neither retries nor waits between polls, so the tests run instantly.
+pub(crate) fn test_client() -> Client {
+    Client::builder()
+        .retry_policy(RetryPolicy::new(0, Duration::ZERO, Duration::ZERO))
+        .build()
+}
+
+// A certificate for the common name, issued by the given certificate and its key or
+// self-signed, and its key.
+pub(crate) fn certificate(
+    common_name: &str,
+    issuer: Option<(&X509, &PKey<Private>)>,
+) -> (X509, PKey<Private>) {
+    let (key, _) = generate_keypair(KeyType::Ed25519).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+        .unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(90).unwrap())
+        .unwrap();
+    match issuer {
+        Some((issuer, issuer_key)) => {
+            builder.set_issuer_name(issuer.subject_name()).unwrap();
+            builder.sign(issuer_key, MessageDigest::null()).unwrap();
+        }
+        None => {
+            builder.set_issuer_name(&name).unwrap();
+            builder.sign(&key, MessageDigest::null()).unwrap();
+        }
+    }
+
+    (builder.build(), key)
+}
+
+fn read_request(stream: &TcpStream, origin: &str) -> Option<MockRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_owned();
+    let path = parts.next()?.to_owned();
+
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok()?;
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(MockRequest {
+        origin: origin.to_owned(),
+        method,
+        path,
+        body,
+    })
+}
+
+fn write_response(mut stream: TcpStream, response: MockResponse) {
+    let mut head = format!("HTTP/1.1 {} Mock\r\n", response.status);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    ));
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(&response.body);
+}
@@ -0,0 +1,253 @@
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+use openssl::pkey::{PKey, Private, Public};
+
+use crate::{
+    acc::Certificate,
+    error::{Error, Result},
+    generate_certificate_for_domain,
+    http::{Client, RateLimitStatus},
+    state::{IssuanceHistory, State},
+    util::to_ascii_domain,
+    IssuanceOptions,
+};
+
+// The rate limits a batch stays within, the defaults are the ones of Let's Encrypt.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimits {
+    // How many certificates may be issued per registered domain within the window.
+    pub certificates_per_domain: usize,
+    pub certificate_window: Duration,
+    // How many orders an account may create within the window.
+    pub orders_per_account: usize,
+    pub order_window: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            certificates_per_domain: 50,
+            certificate_window: Duration::weeks(1),
+            orders_per_account: 300,
+            order_window: Duration::hours(3),
+        }
+    }
+}
+
+// A domain whose issuance was postponed to the next window of the rate limits.
+#[derive(Debug)]
+pub struct Deferred {
+    pub domain: String,
+    pub not_before: DateTime<Utc>,
+}
+
+// The outcome of a batch, every domain ends up in exactly one of the lists.
+#[derive(Debug, Default)]
+pub struct BatchResult {
+    pub issued: Vec<(String, Certificate)>,
+    pub failed: Vec<(String, Error)>,
+    pub deferred: Vec<Deferred>,
+}
+
+// Issues a certificate for each of the domains, one after another. Domains that would
+// exceed the rate limits are deferred instead of ordered, so they can be queued for the
+// next window. The history is kept in the state file of the options, without one only
+// the issuances of this batch are accounted for.
+pub fn issue_batch(
+    keypair_for_cert: &(PKey<Private>, PKey<Public>),
+    domains: &[&str],
+    server: &str,
+    email: &str,
+    limits: &RateLimits,
+    options: &IssuanceOptions,
+) -> Result<BatchResult> {
+    let mut result = BatchResult::default();
+    let mut history = match &options.state_path {
+        Some(path) => State::load(path)?.history,
+        None => IssuanceHistory::default(),
+    };
+
+    for domain in domains {
+        let registered_domain = match to_ascii_domain(domain) {
+            Ok(ascii) => registered_domain(&ascii),
+            Err(e) => {
+                result.failed.push((domain.to_string(), e));
+                continue;
+            }
+        };
+
+        let now = options.client.now();
+        prune(&mut history, now, limits);
+        if let Some(not_before) = next_slot(&history, &registered_domain, limits) {
+            info!(
+                "Deferring {} to {} to stay within the rate limits",
+                domain, not_before
+            );
+            result.deferred.push(Deferred {
+                domain: domain.to_string(),
+                not_before,
+            });
+            continue;
+        }
+
+        let issued = generate_certificate_for_domain(
+            keypair_for_cert,
+            None,
+            *domain,
+            server,
+            &[email],
+            options,
+        );
+        history.orders.push(now);
+        match issued {
+            Ok(certificate) => {
+                history
+                    .certificates
+                    .entry(registered_domain)
+                    .or_default()
+                    .push(options.client.now());
+                result.issued.push((domain.to_string(), certificate));
+            }
+            // the CA counts differently than we do, e.g. other clients share the account, and
+            // may say itself when to try again
+            Err(e) if matches!(e.root(), Error::RateLimited(_)) => {
+                let retry_after = match e.root() {
+                    Error::RateLimited(Some(delay)) => Duration::from_std(*delay).ok(),
+                    _ => None,
+                };
+                result.deferred.push(Deferred {
+                    domain: domain.to_string(),
+                    not_before: now + retry_after.unwrap_or(limits.order_window),
+                });
+            }
+            Err(e) => result.failed.push((domain.to_string(), e)),
+        }
+
+        // the issuance saves the state file itself, so the history goes into a fresh copy
+        if let Some(path) = &options.state_path {
+            let mut state = State::load(path)?;
+            state.history = history.clone();
+            state.save(path)?;
+        }
+    }
+
+    Ok(result)
+}
+
+// The budget left for new orders. The rate limit the server reported takes precedence,
+// without rate-limit headers the orders of the history are counted against the limits,
+// e.g. the history kept in the state file by `issue_batch`.
+pub fn rate_limit_status(
+    client: &Client,
+    history: &IssuanceHistory,
+    limits: &RateLimits,
+) -> RateLimitStatus {
+    if let Some(status) = client.rate_limit_status() {
+        return status;
+    }
+
+    let now = client.now();
+    let orders: Vec<&DateTime<Utc>> = history
+        .orders
+        .iter()
+        .filter(|ordered| now - **ordered < limits.order_window)
+        .collect();
+    RateLimitStatus {
+        limit: Some(limits.orders_per_account as u64),
+        remaining: Some(limits.orders_per_account.saturating_sub(orders.len()) as u64),
+        // a slot frees up once the oldest order leaves the window
+        reset: orders
+            .first()
+            .and_then(|oldest| (**oldest + limits.order_window - now).to_std().ok()),
+    }
+}
+
+// The domain a certificate counts against. Without the public suffix list it's
+// approximated by the last two labels, which undercounts e.g. `example.co.uk`.
+fn registered_domain(domain: &str) -> String {
+    let mut labels = domain.trim_start_matches("*.").rsplit('.');
+    match (labels.next(), labels.next()) {
+        (Some(tld), Some(name)) => format!("{}.{}", name, tld),
+        _ => domain.to_owned(),
+    }
+}
+
+// Forgets the orders and certificates that no longer count against a limit.
+fn prune(history: &mut IssuanceHistory, now: DateTime<Utc>, limits: &RateLimits) {
+    history
+        .orders
+        .retain(|ordered| now - *ordered < limits.order_window);
+    for issued in history.certificates.values_mut() {
+        issued.retain(|issued| now - *issued < limits.certificate_window);
+    }
+    history.certificates.retain(|_, issued| !issued.is_empty());
+}
+
+// When a certificate for the registered domain may be ordered again, `None` if right now.
+fn next_slot(
+    history: &IssuanceHistory,
+    registered_domain: &str,
+    limits: &RateLimits,
+) -> Option<DateTime<Utc>> {
+    let orders = window_opens(
+        &history.orders,
+        limits.orders_per_account,
+        limits.order_window,
+    );
+    let certificates = history
+        .certificates
+        .get(registered_domain)
+        .and_then(|issued| {
+            window_opens(
+                issued,
+                limits.certificates_per_domain,
+                limits.certificate_window,
+            )
+        });
+
+    orders.max(certificates)
+}
+
+// When the oldest of the chronological times leaves the window if all of its slots are
+// used, `None` if there's a free one.
+fn window_opens(times: &[DateTime<Utc>], limit: usize, window: Duration) -> Option<DateTime<Utc>> {
+    let oldest = times.len().checked_sub(limit)?;
+    times.get(oldest).map(|oldest| *oldest + window)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{test_client, MockResponse, MockServer};
+    use crate::util::{generate_keypair, KeyType};
+
+    #[test]
+    fn issue_batch_defers_a_domain_the_ca_rate_limits() {
+        let server = MockServer::start(|request| match (&*request.method, &*request.path) {
+            ("GET", "/directory") => MockResponse::new(429).header("Retry-After", "120"),
+            _ => MockResponse::new(404),
+        });
+        let options = IssuanceOptions {
+            client: test_client(),
+            ..Default::default()
+        };
+        let keypair = generate_keypair(KeyType::Ed25519).unwrap();
+        let limits = RateLimits::default();
+
+        let before = Utc::now();
+        let result = issue_batch(
+            &keypair,
+            &["example.org"],
+            &format!("{}/directory", server.url),
+            "admin@example.org",
+            &limits,
+            &options,
+        )
+        .unwrap();
+
+        assert!(result.failed.is_empty() && result.issued.is_empty());
+        let not_before = result.deferred[0].not_before;
+        assert!(not_before >= before + Duration::seconds(120));
+        assert!(not_before < before + limits.order_window);
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+use openssl::pkey::{PKey, Private};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::util::Clock;
+
+// Certificates are renewed once they expire within this many days.
+const RENEWAL_THRESHOLD_DAYS: i64 = 30;
+
+// Persistent state that makes re-running the client idempotent, e.g. from a cron job.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    pub account: Option<AccountState>,
+    #[serde(default)]
+    pub domains: HashMap<String, DomainState>,
+    #[serde(default)]
+    pub history: IssuanceHistory,
+}
+
+// The account that gets reused for every issuance against the same server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountState {
+    pub server: String,
+    pub url: String,
+    // The PEM encoded private key of the account.
+    pub key: String,
+}
+
+// What is known about the certificate of a single domain.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DomainState {
+    pub last_issued: Option<DateTime<Utc>>,
+    pub expires: Option<DateTime<Utc>>,
+    // The url of an order that was opened but not completed yet.
+    pub pending_order: Option<String>,
+}
+
+// When orders were created and certificates were issued, to pace batches within the
+// rate limits of the CA.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct IssuanceHistory {
+    pub orders: Vec<DateTime<Utc>>,
+    // The issued certificates per registered domain.
+    pub certificates: HashMap<String, Vec<DateTime<Utc>>>,
+}
+
+impl State {
+    // Loads the state from a file, a missing file results in an empty state.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(State::default());
+        }
+
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    // Writes the state to a file that's only readable by the current user,
+    // as it contains the account key. The mode only applies to a new file, so an
+    // existing one is restricted before the state gets written into it.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = options.open(path)?;
+        #[cfg(unix)]
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    // Returns the account key to reuse for the given server, if there is one.
+    pub fn account_key(&self, server: &str) -> Result<Option<PKey<Private>>> {
+        match &self.account {
+            Some(account) if account.server == server => {
+                Ok(Some(PKey::private_key_from_pem(account.key.as_bytes())?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Returns the url of the account to reuse for the given server, if there is one.
+    pub fn account_url(&self, server: &str) -> Option<&str> {
+        self.account
+            .as_ref()
+            .filter(|account| account.server == server)
+            .map(|account| account.url.as_str())
+    }
+
+    // Remembers the account that was used for the given server.
+    pub fn set_account(&mut self, server: &str, url: &str, key: &PKey<Private>) -> Result<()> {
+        self.account = Some(AccountState {
+            server: server.to_owned(),
+            url: url.to_owned(),
+            key: String::from_utf8_lossy(&key.private_key_to_pem_pkcs8()?).into_owned(),
+        });
+
+        Ok(())
+    }
+
+    // Returns the state of a domain, creating an empty one if necessary.
+    pub fn domain(&mut self, domain: &str) -> &mut DomainState {
+        self.domains.entry(domain.to_owned()).or_default()
+    }
+
+    // Whether the certificate of the domain is still valid for long enough to skip renewal
+    // at the time of the clock, usually the one of the client.
+    pub fn is_fresh(&self, domain: &str, clock: &dyn Clock) -> bool {
+        !self.needs_renewal(domain, clock)
+    }
+
+    // Whether the domain has no certificate yet or it expires within the renewal threshold
+    // at the time of the clock.
+    pub fn needs_renewal(&self, domain: &str, clock: &dyn Clock) -> bool {
+        let threshold = Duration::days(RENEWAL_THRESHOLD_DAYS);
+        self.domains
+            .get(domain)
+            .and_then(|domain| domain.expires)
+            .is_none_or(|expires| expires - clock.now() <= threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::FixedClock;
+    use chrono::TimeZone;
+
+    // A state with a certificate for example.org that expires at the returned time.
+    fn state_expiring() -> (State, DateTime<Utc>) {
+        let expires = Utc.with_ymd_and_hms(2026, 12, 31, 12, 0, 0).unwrap();
+        let mut state = State::default();
+        state.domain("example.org").expires = Some(expires);
+        (state, expires)
+    }
+
+    #[test]
+    fn needs_renewal_from_the_threshold_on() {
+        let (state, expires) = state_expiring();
+        let threshold = expires - Duration::days(RENEWAL_THRESHOLD_DAYS);
+        let second = Duration::seconds(1);
+
+        assert!(!state.needs_renewal("example.org", &FixedClock(threshold - second)));
+        assert!(state.needs_renewal("example.org", &FixedClock(threshold)));
+        assert!(state.needs_renewal("example.org", &FixedClock(threshold + second)));
+    }
+
+    #[test]
+    fn needs_renewal_around_the_expiry() {
+        let (state, expires) = state_expiring();
+        let second = Duration::seconds(1);
+
+        assert!(state.needs_renewal("example.org", &FixedClock(expires - second)));
+        assert!(state.needs_renewal("example.org", &FixedClock(expires + second)));
+        assert!(!state.is_fresh("example.org", &FixedClock(expires + second)));
+    }
+
+    #[test]
+    fn a_domain_without_a_certificate_needs_renewal() {
+        let (state, expires) = state_expiring();
+        let long_before = FixedClock(expires - Duration::days(365));
+
+        assert!(state.is_fresh("example.org", &long_before));
+        assert!(state.needs_renewal("example.net", &long_before));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn save_restricts_a_state_file_anyone_could_read() {
+        let path = std::env::temp_dir().join(format!("acme-state-{}.json", std::process::id()));
+        std::fs::write(&path, "{}").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        State::default().save(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(mode, 0o600);
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use openssl::{
+    asn1::{Asn1Object, Asn1OctetString, Asn1Time},
+    bn::{BigNum, MsbOption},
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    sha::sha256,
+    ssl::{select_next_proto, AlpnError, NameType, SniError, SslAcceptor, SslContext, SslMethod},
+    x509::{extension::SubjectAlternativeName, X509Extension, X509NameBuilder, X509},
+};
+
+use crate::error::{Error, Result};
+use crate::util::bind_both_families;
+
+// The ALPN protocol the ACME server negotiates during validation (RFC 8737), length prefixed.
+const ACME_TLS_PROTOCOL: &[u8] = b"\x0aacme-tls/1";
+// The id-pe-acmeIdentifier extension that carries the digest of the key authorisation.
+const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
+
+// Builds the self-signed certificate that answers the tls-alpn-01 challenge of a domain.
+pub fn validation_certificate(
+    domain: &str,
+    key_authorisation: &str,
+) -> Result<(X509, PKey<Private>)> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, domain)?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(7)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+
+    let san = SubjectAlternativeName::new()
+        .dns(domain)
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(san)?;
+
+    // the extension holds the DER encoded octet string of the SHA-256 digest
+    let mut digest = vec![0x04, 0x20];
+    digest.extend_from_slice(&sha256(key_authorisation.as_bytes()));
+    let oid = Asn1Object::from_str(ACME_IDENTIFIER_OID)?;
+    let digest = Asn1OctetString::new_from_bytes(&digest)?;
+    let acme_identifier = X509Extension::new_from_der(&oid, true, &digest)?;
+    builder.append_extension(acme_identifier)?;
+
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    Ok((builder.build(), key))
+}
+
+// Starts a TLS server on port 443 of IPv4 and IPv6 that presents the validation certificate
+// matching the requested server name. Sending on the returned channel stops the server.
+pub fn start_responder(
+    certificates: HashMap<String, (X509, PKey<Private>)>,
+) -> Result<(JoinHandle<()>, Sender<()>)> {
+    let mut contexts = HashMap::new();
+    for (domain, (certificate, key)) in certificates {
+        let mut context = SslContext::builder(SslMethod::tls_server())?;
+        context.set_certificate(&certificate)?;
+        context.set_private_key(&key)?;
+        context.set_alpn_select_callback(|_, client| {
+            select_next_proto(ACME_TLS_PROTOCOL, client).ok_or(AlpnError::ALERT_FATAL)
+        });
+        contexts.insert(domain, context.build());
+    }
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    acceptor.set_alpn_select_callback(|_, client| {
+        select_next_proto(ACME_TLS_PROTOCOL, client).ok_or(AlpnError::ALERT_FATAL)
+    });
+    acceptor.set_servername_callback(move |ssl, _| {
+        let context = ssl
+            .servername(NameType::HOST_NAME)
+            .and_then(|domain| contexts.get(domain))
+            .ok_or(SniError::ALERT_FATAL)?;
+        ssl.set_ssl_context(context).map_err(|_| SniError::ALERT_FATAL)
+    });
+    let acceptor = acceptor.build();
+
+    let listeners = bind_both_families(443, TcpListener::bind, |error| {
+        error.kind() == ErrorKind::AddrInUse
+    })
+    .map_err(|error| match error.kind() {
+        ErrorKind::PermissionDenied => Error::PrivilegedPort(443),
+        _ => Error::from(error),
+    })?;
+    for listener in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+
+    let (stop, stopped) = mpsc::channel();
+    let handle = std::thread::spawn(move || loop {
+        if !matches!(stopped.try_recv(), Err(TryRecvError::Empty)) {
+            break;
+        }
+
+        let mut idle = true;
+        for listener in &listeners {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    // the validation only needs the handshake, so the connection is dropped
+                    // after it
+                    let _ = stream.set_nonblocking(false);
+                    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                    let _ = acceptor.accept(stream);
+                    idle = false;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => return,
+            }
+        }
+        if idle {
+            std::thread::sleep(Duration::from_millis(50))
+        }
+    });
+
+    Ok((handle, stop))
+}
@@ -1,82 +1,466 @@
-use std::net::TcpStream;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+#[cfg(feature = "client")]
+use std::time::Duration;
 
-use base64::encode_config;
+use base64::{decode_config, encode_config};
+use chrono::{DateTime, Utc};
 use openssl::{
+    asn1::{Asn1Object, Asn1OctetString, Asn1Time},
+    bn::{BigNum, BigNumContext},
+    ecdsa::EcdsaSig,
     hash::MessageDigest,
-    pkey::{PKey, Private, Public},
+    nid::Nid,
+    pkey::{Id, PKey, Private, Public},
+    provider::Provider,
     rsa::{Padding, Rsa},
-    sign::Signer,
-    x509::X509Req,
+    sha::sha256,
+    sign::{Signer, Verifier},
+    stack::Stack,
+    x509::{
+        extension::SubjectAlternativeName, X509Extension, X509NameBuilder, X509Req, X509ReqBuilder,
+        X509VerifyResult, X509,
+    },
 };
-use reqwest::blocking::Response;
+#[cfg(feature = "client")]
+use reqwest::{blocking::Response, StatusCode};
+#[cfg(feature = "client")]
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::json;
 
+#[cfg(feature = "client")]
+use crate::acc::Nonce;
 use crate::{
     error::{Error, Result},
-    acc::{Certificate, Nonce},
     KEY_WIDTH,
 };
 
+// The headers CAs use to identify a request.
+#[cfg(feature = "client")]
+const REQUEST_ID_HEADERS: &[&str] = &["x-request-id", "request-id", "boulder-requester"];
+
+// The problem type of a request the CA refused because of one of its rate limits.
+#[cfg(feature = "client")]
+const RATE_LIMITED_PROBLEM: &str = "urn:ietf:params:acme:error:rateLimited";
+
+// The OpenSSL providers loaded with `load_openssl_provider`, unloading them would take
+// the algorithms away from every key and signer still using them.
+static PROVIDERS: Mutex<Vec<Provider>> = Mutex::new(Vec::new());
+
+// The size of the coordinates and signature halves of a P-256 key.
+const ES256_COORDINATE_LEN: i32 = 32;
+
+// The certificate extension that holds the embedded SCTs.
+const SCT_LIST_OID: &str = "1.3.6.1.4.1.11129.2.4.2";
+
+// The smallest RSA modulus accepted for a certificate key loaded from a file.
+const MIN_KEY_WIDTH: u32 = 2048;
+
+// Where the checks that depend on the current time get it from, so they can be run at a
+// fixed time, e.g. right at the renewal threshold.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+// The clock of the system, used unless another one is given.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// A clock that stands still at the given time.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+// The digest used to sign the CSR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CsrDigest {
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl CsrDigest {
+    pub fn message_digest(self) -> MessageDigest {
+        match self {
+            CsrDigest::Sha256 => MessageDigest::sha256(),
+            CsrDigest::Sha384 => MessageDigest::sha384(),
+            CsrDigest::Sha512 => MessageDigest::sha512(),
+        }
+    }
+
+    // Checks that the digest can be used to sign with the given key. RSA keys work with
+    // every digest, EC keys need the digest that matches the size of their curve.
+    pub fn check_key(self, key: &PKey<Private>) -> Result<()> {
+        match key.id() {
+            // Ed25519 signs without a separate digest
+            Id::RSA | Id::ED25519 => Ok(()),
+            Id::EC => {
+                let expected = match key.ec_key()?.group().curve_name() {
+                    Some(Nid::X9_62_PRIME256V1) => CsrDigest::Sha256,
+                    Some(Nid::SECP384R1) => CsrDigest::Sha384,
+                    Some(Nid::SECP521R1) => CsrDigest::Sha512,
+                    _ => return Err(Error::BadSignatureAlgorithm),
+                };
+                if self == expected {
+                    Ok(())
+                } else {
+                    Err(Error::BadSignatureAlgorithm)
+                }
+            }
+            _ => Err(Error::BadSignatureAlgorithm),
+        }
+    }
+}
+
+impl FromStr for CsrDigest {
+    type Err = Error;
+
+    fn from_str(digest: &str) -> Result<Self> {
+        match digest.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(CsrDigest::Sha256),
+            "sha384" => Ok(CsrDigest::Sha384),
+            "sha512" => Ok(CsrDigest::Sha512),
+            _ => Err(Error::BadSignatureAlgorithm),
+        }
+    }
+}
+
+// The type of the key the certificate is issued for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum KeyType {
+    #[default]
+    Rsa,
+    Ed25519,
+}
+
+impl FromStr for KeyType {
+    type Err = Error;
+
+    fn from_str(key_type: &str) -> Result<Self> {
+        match key_type.to_ascii_lowercase().as_str() {
+            "rsa" => Ok(KeyType::Rsa),
+            "ed25519" => Ok(KeyType::Ed25519),
+            _ => Err(Error::BadSignatureAlgorithm),
+        }
+    }
+}
+
+// The layout of the saved certificate and key, named after the web server that expects it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    // The whole chain in `fullchain.pem` and the key in `privkey.pem`.
+    Nginx,
+    // The leaf in `cert.pem`, the intermediates in `chain.pem` and the key in `privkey.pem`.
+    Apache,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format.to_ascii_lowercase().as_str() {
+            "nginx" => Ok(OutputFormat::Nginx),
+            "apache" => Ok(OutputFormat::Apache),
+            _ => Err(Error::InvalidFormat(format.to_owned())),
+        }
+    }
+}
+
+// Loads an OpenSSL provider, e.g. `fips` and `base` in FIPS-validated environments, for
+// the rest of the process. From then on key generation, signing and CSRs only use the
+// loaded providers, the built-in default one is no longer a fallback. Fails with
+// `ProviderUnavailable` if OpenSSL can't find or initialize the provider.
+pub fn load_openssl_provider(name: &str) -> Result<()> {
+    let provider =
+        Provider::load(None, name).map_err(|_| Error::ProviderUnavailable(name.to_owned()))?;
+    PROVIDERS.lock().unwrap().push(provider);
+
+    Ok(())
+}
+
 pub fn check_for_existing_server() -> bool {
-    // These will parse so it's okay to unwrap here.
+    check_for_existing_server_on(80)
+}
+
+// Checks whether some process is already listening on the given port, over IPv4 or IPv6.
+pub fn check_for_existing_server_on(port: u16) -> bool {
     let addrs = [
-        "0.0.0.0:80".parse().unwrap(),
-        "127.0.0.1:80".parse().unwrap(),
+        SocketAddr::from(([0, 0, 0, 0], port)),
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
     ];
 
     TcpStream::connect(&addrs[..]).is_ok()
 }
 
-// Generates a RSA private key.
-pub(crate) fn generate_rsa_key() -> Result<Rsa<Private>> {
-    Ok(Rsa::generate(KEY_WIDTH)?)
+// Where the standalone responders listen: all IPv6 and all IPv4 addresses.
+pub fn listen_addrs(port: u16) -> [SocketAddr; 2] {
+    [
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        SocketAddr::from(([0, 0, 0, 0], port)),
+    ]
 }
 
-// Generate a key pair.
-pub fn generate_rsa_keypair() -> Result<(Rsa<Private>, Rsa<Public>)> {
-    let rsa_key = generate_rsa_key()?;
-    Ok((
-        Rsa::private_key_from_pem(&rsa_key.private_key_to_pem()?)?,
-        Rsa::public_key_from_pem(&rsa_key.public_key_to_pem()?)?,
-    ))
+// Binds a standalone responder to both `listen_addrs` with a socket each, so IPv4 is
+// served whether the OS maps it onto IPv6 sockets or not. Where it does, like Linux by
+// default, the IPv6 socket already holds the IPv4 address and its bind fails with the
+// error `in_use` recognizes. Hosts without IPv6 only get the IPv4 socket.
+pub fn bind_both_families<T, E>(
+    port: u16,
+    bind: impl Fn(SocketAddr) -> std::result::Result<T, E>,
+    in_use: impl Fn(&E) -> bool,
+) -> std::result::Result<Vec<T>, E> {
+    let [ipv6, ipv4] = listen_addrs(port);
+    match (bind(ipv6), bind(ipv4)) {
+        (Ok(ipv6), Ok(ipv4)) => Ok(vec![ipv6, ipv4]),
+        (Ok(ipv6), Err(e)) if in_use(&e) => Ok(vec![ipv6]),
+        (Err(_), Ok(ipv4)) => Ok(vec![ipv4]),
+        (_, Err(e)) => Err(e),
+    }
 }
 
-pub fn jwk(private_key: &Rsa<Private>) -> Result<serde_json::Value> {
-    let e = b64(&private_key.e().to_vec());
-    let n = b64(&private_key.n().to_vec());
+// Generates a RSA private key with a modulus of `bits` bits.
+pub(crate) fn generate_rsa_key(bits: u32) -> Result<Rsa<Private>> {
+    Ok(Rsa::generate(bits)?)
+}
 
-    Ok(json!({
-        "e": e,
-        "n": n,
-        "kty": "RSA",
-    }))
+// Generate a key pair for the certificate.
+pub fn generate_keypair(key_type: KeyType) -> Result<(PKey<Private>, PKey<Public>)> {
+    generate_keypair_of_size(key_type, KEY_WIDTH)
+}
+
+// Generate a key pair for the certificate with a RSA modulus of `bits` bits, Ed25519 keys
+// have a fixed size. Fails with `BadPublicKey` for a modulus shorter than `MIN_KEY_WIDTH`.
+pub fn generate_keypair_of_size(
+    key_type: KeyType,
+    bits: u32,
+) -> Result<(PKey<Private>, PKey<Public>)> {
+    let private_key = match key_type {
+        KeyType::Rsa if bits < MIN_KEY_WIDTH => return Err(Error::BadPublicKey),
+        KeyType::Rsa => PKey::from_rsa(generate_rsa_key(bits)?)?,
+        KeyType::Ed25519 => PKey::generate_ed25519()?,
+    };
+    let public_key = PKey::public_key_from_pem(&private_key.public_key_to_pem()?)?;
+
+    Ok((private_key, public_key))
+}
+
+// Loads a PEM encoded account key, e.g. to compute its thumbprint.
+pub fn load_account_key_from_file(path: &Path) -> Result<PKey<Private>> {
+    Ok(PKey::private_key_from_pem(&std::fs::read(path)?)?)
+}
+
+// Loads a PEM encoded account key from `env:NAME` for an environment variable, `-` for stdin
+// or a path otherwise, e.g. to pass the key of a secret manager without writing it to disk.
+pub fn load_account_key(source: &str) -> Result<PKey<Private>> {
+    if let Some(name) = source.strip_prefix("env:") {
+        let pem = std::env::var(name).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("the environment variable {} is not set", name),
+            )
+        })?;
+        return load_key_from_str(&pem);
+    }
+    if source == "-" {
+        return load_key_from_reader(io::stdin().lock());
+    }
+    load_account_key_from_file(Path::new(source))
+}
+
+// Loads a PEM encoded private key from a reader, e.g. stdin.
+pub fn load_key_from_reader<R: Read>(mut reader: R) -> Result<PKey<Private>> {
+    let mut pem = String::new();
+    reader.read_to_string(&mut pem)?;
+    load_key_from_str(&pem)
+}
+
+// Loads a PEM encoded private key from a string.
+pub fn load_key_from_str(pem: &str) -> Result<PKey<Private>> {
+    Ok(PKey::private_key_from_pem(pem.as_bytes())?)
+}
+
+// Loads a PEM encoded public key, or the public half of a PEM encoded private key.
+pub fn load_public_key_from_file(path: &Path) -> Result<PKey<Public>> {
+    let pem = std::fs::read(path)?;
+    match PKey::public_key_from_pem(&pem) {
+        Ok(public_key) => Ok(public_key),
+        Err(_) => {
+            let public_pem = PKey::private_key_from_pem(&pem)?.public_key_to_pem()?;
+            Ok(PKey::public_key_from_pem(&public_pem)?)
+        }
+    }
+}
+
+// Generates the key that's used to sign the requests of an account.
+#[cfg(feature = "client")]
+pub(crate) fn generate_account_key(key_type: KeyType, bits: u32) -> Result<PKey<Private>> {
+    let (private_key, _) = generate_keypair_of_size(key_type, bits)?;
+    Ok(private_key)
 }
 
-// Construct a JSON Web Signature.
+// What kind of key an account uses, e.g. to audit for keys that are due for a rotation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyInfo {
+    // `RSA`, `EC` or `Ed25519`.
+    pub algorithm: String,
+    // The size of the key in bits, of the modulus for RSA.
+    pub bits: u32,
+    // The curve of an EC key, e.g. `prime256v1`.
+    pub curve: Option<String>,
+    // The JWK thumbprint (RFC 7638).
+    pub thumbprint: String,
+}
+
+// Describes an account key. Fails with `BadSignatureAlgorithm` for keys that can't sign
+// ACME requests.
+pub fn key_info(private_key: &PKey<Private>) -> Result<KeyInfo> {
+    let (algorithm, curve) = match private_key.id() {
+        Id::RSA => ("RSA", None),
+        Id::EC => {
+            let curve = private_key.ec_key()?.group().curve_name();
+            ("EC", curve.and_then(|nid| nid.short_name().ok()))
+        }
+        Id::ED25519 => ("Ed25519", None),
+        _ => return Err(Error::BadSignatureAlgorithm),
+    };
+
+    Ok(KeyInfo {
+        algorithm: algorithm.to_owned(),
+        bits: private_key.bits(),
+        curve: curve.map(str::to_owned),
+        thumbprint: thumbprint(private_key)?,
+    })
+}
+
+// Builds the JSON Web Key of the public part of a key.
+pub fn jwk(private_key: &PKey<Private>) -> Result<serde_json::Value> {
+    match jws_algorithm(private_key)? {
+        "ES256" => {
+            let ec_key = private_key.ec_key()?;
+            let mut ctx = BigNumContext::new()?;
+            let (mut x, mut y) = (BigNum::new()?, BigNum::new()?);
+            ec_key
+                .public_key()
+                .affine_coordinates(ec_key.group(), &mut x, &mut y, &mut ctx)?;
+
+            Ok(json!({
+                "crv": "P-256",
+                "kty": "EC",
+                "x": b64(&x.to_vec_padded(ES256_COORDINATE_LEN)?),
+                "y": b64(&y.to_vec_padded(ES256_COORDINATE_LEN)?),
+            }))
+        }
+        "EdDSA" => Ok(json!({
+            "crv": "Ed25519",
+            "kty": "OKP",
+            "x": b64(&private_key.raw_public_key()?),
+        })),
+        _ => {
+            let rsa_key = private_key.rsa().map_err(|_| Error::BadPublicKey)?;
+            let e = b64(&rsa_key.e().to_vec());
+            let n = b64(&rsa_key.n().to_vec());
+
+            Ok(json!({
+                "e": e,
+                "n": n,
+                "kty": "RSA",
+            }))
+        }
+    }
+}
+
+// The JWS algorithm a key signs with, which `jws` puts into the `alg` header.
+pub fn jws_algorithm(private_key: &PKey<Private>) -> Result<&'static str> {
+    match private_key.id() {
+        Id::RSA => Ok("RS256"),
+        Id::EC if private_key.ec_key()?.group().curve_name() == Some(Nid::X9_62_PRIME256V1) => {
+            Ok("ES256")
+        }
+        Id::ED25519 => Ok("EdDSA"),
+        _ => Err(Error::BadSignatureAlgorithm),
+    }
+}
+
+// Computes the base64url encoded JWK thumbprint (RFC 7638) that's part of every key
+// authorisation. The members of the JWK are serialized in lexicographic order.
+pub fn thumbprint(private_key: &PKey<Private>) -> Result<String> {
+    let jwk = jwk(private_key)?;
+    Ok(b64(&sha256(jwk.to_string().as_bytes())))
+}
+
+// Computes the key authorisation of a challenge token, the string the CA expects for
+// every challenge type. http-01 serves it verbatim, dns-01 publishes its digest instead,
+// see `dns_txt_value`.
+pub fn compute_key_authorization(token: &str, private_key: &PKey<Private>) -> Result<String> {
+    Ok(format!("{}.{}", token, thumbprint(private_key)?))
+}
+
+// Construct a JSON Web Signature. The `alg` header is set to match the key.
 pub fn jws(
     payload: serde_json::Value,
-    header: serde_json::Value,
-    private_key: &Rsa<Private>,
+    mut header: serde_json::Value,
+    private_key: &PKey<Private>,
 ) -> Result<serde_json::Value> {
     // edge case when the payload needs to be empty, e.g. for
     // fetching the challenges or downloading the certificate
     let empty_payload = payload == json!("");
 
+    let algorithm = jws_algorithm(private_key)?;
+    header["alg"] = json!(algorithm);
+
     let payload64 = b64(serde_json::to_string_pretty(&payload)?.as_bytes());
     let header64 = b64(serde_json::to_string_pretty(&header)?.as_bytes());
-
-    let p_key = PKey::private_key_from_pem(&private_key.private_key_to_pem()?)?;
-    let mut signer = Signer::new(MessageDigest::sha256(), &p_key)?;
-
-    signer.set_rsa_padding(Padding::PKCS1)?;
-    if empty_payload {
-        signer.update(format!("{}.", header64).as_bytes())?;
+    let signing_input = if empty_payload {
+        format!("{}.", header64)
     } else {
-        signer.update(format!("{}.{}", header64, payload64).as_bytes())?;
-    }
+        format!("{}.{}", header64, payload64)
+    };
 
-    let signature = b64(&signer.sign_to_vec()?);
+    let signature = match algorithm {
+        // Ed25519 hashes internally
+        "EdDSA" => Signer::new_without_digest(private_key)?
+            .sign_oneshot_to_vec(signing_input.as_bytes())?,
+        _ => {
+            let mut signer = Signer::new(MessageDigest::sha256(), private_key)?;
+            if algorithm == "RS256" {
+                signer.set_rsa_padding(Padding::PKCS1)?;
+            }
+            signer.update(signing_input.as_bytes())?;
+            signer.sign_to_vec()?
+        }
+    };
+    // JWS wants the raw r and s of an ECDSA signature instead of its DER encoding
+    let signature = if algorithm == "ES256" {
+        let signature = EcdsaSig::from_der(&signature)?;
+        let mut raw = signature.r().to_vec_padded(ES256_COORDINATE_LEN)?;
+        raw.extend(signature.s().to_vec_padded(ES256_COORDINATE_LEN)?);
+        raw
+    } else {
+        signature
+    };
+    let signature = b64(&signature);
 
     Ok(json!({
         "protected": header64,
@@ -85,28 +469,101 @@ pub fn jws(
     }))
 }
 
+// A JWS taken apart, e.g. to debug what a request signed.
+#[derive(Debug, Clone)]
+pub struct DecodedJws {
+    pub header: serde_json::Value,
+    // `None` for the empty payload of a POST-as-GET.
+    pub payload: Option<serde_json::Value>,
+    signing_input: String,
+    signature: Vec<u8>,
+}
+
+impl DecodedJws {
+    // Whether the signature verifies against the public key, with the algorithm of the
+    // `alg` header.
+    pub fn verify(&self, public_key: &PKey<Public>) -> Result<bool> {
+        let algorithm = self.header["alg"].as_str().unwrap_or_default();
+        let signing_input = self.signing_input.as_bytes();
+        match algorithm {
+            "EdDSA" => Ok(Verifier::new_without_digest(public_key)?
+                .verify_oneshot(&self.signature, signing_input)?),
+            "RS256" | "ES256" => {
+                // the raw r and s of an ECDSA signature go back into their DER encoding
+                let signature = if algorithm == "ES256" {
+                    let (r, s) = self.signature.split_at(self.signature.len() / 2);
+                    let (r, s) = (BigNum::from_slice(r)?, BigNum::from_slice(s)?);
+                    EcdsaSig::from_private_components(r, s)?.to_der()?
+                } else {
+                    self.signature.clone()
+                };
+                let mut verifier = Verifier::new(MessageDigest::sha256(), public_key)?;
+                if algorithm == "RS256" {
+                    verifier.set_rsa_padding(Padding::PKCS1)?;
+                }
+                verifier.update(signing_input)?;
+                Ok(verifier.verify(&signature)?)
+            }
+            _ => Err(Error::BadSignatureAlgorithm),
+        }
+    }
+}
+
+// Decodes the protected header and the payload of a JWS in the flattened JSON
+// serialization that `jws` produces. Fails with `MalformedRequest` if it isn't one.
+pub fn decode_jws(jws: &serde_json::Value) -> Result<DecodedJws> {
+    let part = |name: &str| jws[name].as_str().ok_or(Error::MalformedRequest);
+    let decode = |part: &str| {
+        decode_config(part, base64::URL_SAFE_NO_PAD).map_err(|_| Error::MalformedRequest)
+    };
+    let (header64, payload64) = (part("protected")?, part("payload")?);
+
+    let payload = match payload64 {
+        "" => None,
+        payload64 => Some(serde_json::from_slice(&decode(payload64)?)?),
+    };
+
+    Ok(DecodedJws {
+        header: serde_json::from_slice(&decode(header64)?)?,
+        payload,
+        signing_input: format!("{}.{}", header64, payload64),
+        signature: decode(part("signature")?)?,
+    })
+}
+
+// The value of the `_acme-challenge` TXT record that presents a dns-01 key authorisation,
+// `base64url(sha256(key_authorisation))`.
+pub fn dns_txt_value(key_authorisation: &str) -> String {
+    b64(&sha256(key_authorisation.as_bytes()))
+}
+
 // Create b64 encoding.
-pub(crate) fn b64(to_encode: &[u8]) -> String {
+pub fn b64(to_encode: &[u8]) -> String {
     encode_config(to_encode, base64::URL_SAFE_NO_PAD)
 }
 
-// Extract the payload and nonce from a response.
+// Extract the payload and nonce from a response. An error response is turned into an error
+// by `check_problem` instead of being read as the payload.
+#[cfg(feature = "client")]
 #[inline]
 pub(crate) fn extract_payload_and_nonce<T>(response: Response) -> Result<(Nonce, T)>
 where
     T: DeserializeOwned,
 {
-    let replay_nonce = response
-        .headers()
-        .get("replay-nonce")
-        .ok_or(Error::IncorrectResponse)?
-        .to_str()?
-        .to_owned();
+    let response = check_problem(response, |_| None)?;
+    let request_id = request_id(&response);
+    let result = (|| {
+        let replay_nonce = replay_nonce(&response)?;
+
+        Ok((replay_nonce, response.json()?))
+    })();
 
-    Ok((replay_nonce, response.json()?))
+    with_request_id(result, request_id)
 }
 
-// Extract the location and nonce from a response.
+// Extract the location and nonce from a response, error responses like in
+// `extract_payload_and_nonce`.
+#[cfg(feature = "client")]
 #[inline]
 pub(crate) fn extract_payload_location_and_nonce<T>(
     response: Response,
@@ -114,32 +571,473 @@ pub(crate) fn extract_payload_location_and_nonce<T>(
 where
     T: DeserializeOwned,
 {
-    let replay_nonce = response
+    let response = check_problem(response, |_| None)?;
+    let request_id = request_id(&response);
+    let result = (|| {
+        let replay_nonce = replay_nonce(&response)?;
+
+        let location = response
+            .headers()
+            .get("location")
+            .ok_or(Error::IncorrectResponse)?
+            .to_str()?
+            .to_owned();
+
+        Ok((location, replay_nonce, response.json()?))
+    })();
+
+    with_request_id(result, request_id)
+}
+
+// Reads the nonce of a response. A missing nonce is returned empty, a fresh one is
+// fetched before the next request is signed.
+#[cfg(feature = "client")]
+pub(crate) fn replay_nonce(response: &Response) -> Result<Nonce> {
+    Ok(response
         .headers()
         .get("replay-nonce")
-        .ok_or(Error::IncorrectResponse)?
-        .to_str()?
-        .to_owned();
+        .map(|nonce| nonce.to_str())
+        .transpose()?
+        .unwrap_or_default()
+        .to_owned())
+}
+
+// Reads the id the CA assigned to the request of a response, operators ask for it in
+// support tickets.
+#[cfg(feature = "client")]
+pub(crate) fn request_id(response: &Response) -> Option<String> {
+    REQUEST_ID_HEADERS
+        .iter()
+        .find_map(|header| response.headers().get(*header)?.to_str().ok())
+        .map(str::to_owned)
+}
 
-    let location = response
+// Passes a successful response on and turns an error response into an error: the problem
+// types the caller knows into its errors, a rate limit into `RateLimited` with the delay
+// the CA asks for and anything else into the HTTP status, with the detail of the problem
+// if the body is one at all and not e.g. the error page of a proxy.
+#[cfg(feature = "client")]
+pub(crate) fn check_problem(
+    response: Response,
+    known: impl Fn(&str) -> Option<Error>,
+) -> Result<Response> {
+    let status_error = match response.error_for_status_ref() {
+        Ok(_) => return Ok(response),
+        Err(e) => e,
+    };
+    let request_id = request_id(&response);
+    let rate_limited = response.status() == StatusCode::TOO_MANY_REQUESTS;
+    let delay = retry_after(&response);
+    let problem: serde_json::Value = response.json().unwrap_or_default();
+    let problem_type = problem["type"].as_str().unwrap_or_default();
+
+    let error = match known(problem_type) {
+        Some(error) => error,
+        None if rate_limited || problem_type == RATE_LIMITED_PROBLEM => Error::RateLimited(delay),
+        None => match problem["detail"].as_str() {
+            Some(detail) => Error::WithDetail(Box::new(status_error.into()), detail.to_owned()),
+            None => status_error.into(),
+        },
+    };
+    with_request_id(Err(error), request_id)
+}
+
+// Attaches the CA request id of a response to the error of processing it.
+#[cfg(feature = "client")]
+pub(crate) fn with_request_id<T>(result: Result<T>, request_id: Option<String>) -> Result<T> {
+    match request_id {
+        Some(request_id) => result.map_err(|e| Error::WithRequestId(Box::new(e), request_id)),
+        None => result,
+    }
+}
+
+// Reads the delay requested by the server via the `Retry-After` header, if it's given in seconds.
+#[cfg(feature = "client")]
+pub(crate) fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get("retry-after")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+// Reads the time of the server from the `Date` header of a response.
+#[cfg(feature = "client")]
+pub(crate) fn server_date(response: &Response) -> Option<DateTime<Utc>> {
+    let date = response.headers().get("date")?.to_str().ok()?;
+    DateTime::parse_from_rfc2822(date)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+// Extracts the targets of all `Link` headers with the given relation type, relative ones
+// resolved against the URL of the response (RFC 8288 section 3.1).
+#[cfg(feature = "client")]
+pub(crate) fn links_with_rel(response: &Response, rel: &str) -> Vec<String> {
+    response
         .headers()
-        .get("location")
-        .ok_or(Error::IncorrectResponse)?
-        .to_str()?
-        .to_owned();
+        .get_all("link")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|link| {
+            let (target, params) = link.trim().split_once(';')?;
+            let has_rel = params.split(';').any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("rel=")
+                    .is_some_and(|value| value.trim_matches('"') == rel)
+            });
+
+            if !has_rel {
+                return None;
+            }
+            let target = target.trim().trim_matches(|c| c == '<' || c == '>');
+            response.url().join(target).ok()
+        })
+        .map(String::from)
+        .collect()
+}
+
+// An additional field of the CSR subject, e.g. `O=Example Inc.`, for internal PKIs whose
+// policies require them. Most public CAs ignore everything but the domains for DV
+// certificates, so the fields usually won't show up in the issued certificate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubjectField {
+    // The short name of the field, e.g. `O`, `OU`, `C`, `ST` or `L`.
+    pub name: String,
+    pub value: String,
+}
+
+impl SubjectField {
+    pub fn nid(&self) -> Result<Nid> {
+        let object = Asn1Object::from_str(&self.name).map_err(|_| Error::BadCSR)?;
+        Ok(object.nid())
+    }
+}
+
+impl FromStr for SubjectField {
+    type Err = Error;
+
+    fn from_str(field: &str) -> Result<Self> {
+        let (name, value) = field.split_once('=').ok_or(Error::BadCSR)?;
+        let field = SubjectField {
+            name: name.trim().to_owned(),
+            value: value.trim().to_owned(),
+        };
+        field.nid()?;
 
-    Ok((location, replay_nonce, response.json()?))
+        Ok(field)
+    }
+}
+
+// An additional extension of the generated CSR, e.g. a certificate policy an internal CA
+// requires, given as `OID=HEX` or `OID=critical,HEX` with the DER encoded extension value.
+// The SANs are managed by the client and can't be replaced this way.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsrExtension {
+    // The dotted OID or short name of the extension, e.g. `2.5.29.32`.
+    pub oid: String,
+    pub critical: bool,
+    pub value: Vec<u8>,
+}
+
+impl CsrExtension {
+    pub fn object(&self) -> Result<Asn1Object> {
+        let object = Asn1Object::from_str(&self.oid).map_err(|_| Error::BadCSR)?;
+        if object.nid() == Nid::SUBJECT_ALT_NAME {
+            return Err(Error::CsrExtensionConflict(self.oid.clone()));
+        }
+        Ok(object)
+    }
+
+    fn to_extension(&self) -> Result<X509Extension> {
+        let object = self.object()?;
+        let value = Asn1OctetString::new_from_bytes(&self.value)?;
+        Ok(X509Extension::new_from_der(&object, self.critical, &value)?)
+    }
+}
+
+impl FromStr for CsrExtension {
+    type Err = Error;
+
+    fn from_str(extension: &str) -> Result<Self> {
+        let (oid, value) = extension.split_once('=').ok_or(Error::BadCSR)?;
+        let (critical, value) = match value.trim().strip_prefix("critical,") {
+            Some(value) => (true, value),
+            None => (false, value.trim()),
+        };
+        if value.is_empty() || value.len() % 2 != 0 {
+            return Err(Error::BadCSR);
+        }
+        let value = (0..value.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(value.get(index..index + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or(Error::BadCSR)?;
+        let extension = CsrExtension {
+            oid: oid.trim().to_owned(),
+            critical,
+            value,
+        };
+        extension.object()?;
+
+        Ok(extension)
+    }
+}
+
+// A domain in both of its IDNA forms, e.g. `例え.テスト` and `xn--r8jz45g.xn--zckzah`. The
+// U-label is shown to the user, the A-label is what goes into orders, CSRs and DNS names.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DomainName {
+    pub unicode: String,
+    pub ascii: String,
+}
+
+impl FromStr for DomainName {
+    type Err = Error;
+
+    fn from_str(domain: &str) -> Result<Self> {
+        let ascii = to_ascii_domain(domain)?;
+        let (label, wildcard) = match ascii.strip_prefix("*.") {
+            Some(label) => (label, "*."),
+            None => (ascii.as_str(), ""),
+        };
+        let (unicode, _) = idna::domain_to_unicode(label);
+
+        Ok(DomainName {
+            unicode: format!("{}{}", wildcard, unicode),
+            ascii,
+        })
+    }
+}
+
+// The ACME identifier type of a domain name or IP address, `dns` or `ip` (RFC 8738).
+pub fn identifier_type(identifier: &str) -> &'static str {
+    if identifier.parse::<IpAddr>().is_ok() {
+        "ip"
+    } else {
+        "dns"
+    }
+}
+
+// Converts a domain to its A-label form, ASCII domains are only lowercased. The wildcard
+// label of a wildcard domain and IP addresses are kept as is.
+pub fn to_ascii_domain(domain: &str) -> Result<String> {
+    if identifier_type(domain) == "ip" {
+        return Ok(domain.to_owned());
+    }
+    let (label, wildcard) = match domain.strip_prefix("*.") {
+        Some(label) => (label, "*."),
+        None => (domain, ""),
+    };
+    let ascii = idna::domain_to_ascii_strict(label)
+        .map_err(|_| Error::InvalidDomain(domain.to_owned()))?;
+
+    Ok(format!("{}{}", wildcard, ascii))
+}
+
+// Factors a csr request, which needs to be sent during finalization. The common name
+// defaults to the first domain unless the subject fields contain one.
+pub fn request_csr(
+    keypair: &(PKey<Private>, PKey<Public>),
+    domains: &[&str],
+    digest: CsrDigest,
+    subject: &[SubjectField],
+    additional_extensions: &[CsrExtension],
+) -> Result<X509Req> {
+    let domains = domains
+        .iter()
+        .map(|domain| to_ascii_domain(domain))
+        .collect::<Result<Vec<_>>>()?;
+    let common_name = domains.first().ok_or(Error::BadCSR)?;
+    let mut request = X509ReqBuilder::new()?;
+    let mut c_name = X509NameBuilder::new()?;
+
+    let (pri_key, public_key) = keypair;
+    digest.check_key(pri_key)?;
+
+    // Ed25519 hashes internally and has to be signed with the null digest
+    let message_digest = if pri_key.id() == Id::ED25519 {
+        MessageDigest::null()
+    } else {
+        digest.message_digest()
+    };
+
+    let mut fields = Vec::new();
+    for field in subject {
+        fields.push((field.nid()?, field.value.as_str()));
+    }
+    if !fields.iter().any(|(nid, _)| *nid == Nid::COMMONNAME) {
+        c_name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+    }
+    for (nid, value) in fields {
+        c_name.append_entry_by_nid(nid, value)?;
+    }
+    let name = c_name.build();
+    request.set_pubkey(public_key)?;
+    request.set_subject_name(name.as_ref())?;
+
+    // the first domain is the common name, all of them are listed as SANs
+    let mut san = SubjectAlternativeName::new();
+    for domain in &domains {
+        match identifier_type(domain) {
+            "ip" => san.ip(domain),
+            _ => san.dns(domain),
+        };
+    }
+    let mut extensions = Stack::new()?;
+    extensions.push(san.build(&request.x509v3_context(None))?)?;
+    // a request can't carry an extension twice
+    let mut oids = Vec::new();
+    for extension in additional_extensions {
+        let oid = extension.object()?.to_string();
+        if oids.contains(&oid) {
+            return Err(Error::CsrExtensionConflict(extension.oid.clone()));
+        }
+        oids.push(oid);
+        extensions.push(extension.to_extension()?)?;
+    }
+    request.add_extensions(&extensions)?;
+
+    request.sign(pri_key, message_digest)?;
+
+    Ok(request.build())
 }
 
 // Load a certificate from a pem file.
 pub fn load_csr_from_file(path: &str) -> Result<X509Req> {
     let bytes = std::fs::read(path)?;
+    let csr = X509Req::from_pem(&bytes)?;
+
+    // catch CSRs that aren't signed by the key they contain before the CA rejects them
+    let public_key = csr.public_key()?;
+    if !csr.verify(&public_key).unwrap_or(false) {
+        return Err(Error::BadCSR);
+    }
+
+    Ok(csr)
+}
+
+// The contents of a CSR, to show what gets requested before it's submitted.
+#[derive(Debug, Clone)]
+pub struct CsrInfo {
+    pub common_name: Option<String>,
+    pub subject_alt_names: Vec<String>,
+    pub key_type: String,
+    pub key_bits: u32,
+    pub signature_algorithm: String,
+}
+
+// Describes the subject, the SANs, the key and the signature algorithm of a CSR.
+pub fn describe_csr(csr: &X509Req) -> Result<CsrInfo> {
+    let public_key = csr.public_key()?;
+    let common_name = csr
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .map(|entry| String::from_utf8_lossy(entry.data().as_slice()).into_owned());
 
-    Ok(X509Req::from_pem(&bytes)?)
+    // openssl only decodes the SANs of a certificate, so the extensions of the request are
+    // put on an empty one, a request without any has none to put
+    let mut with_extensions = X509::builder()?;
+    if let Ok(extensions) = csr.extensions() {
+        for extension in &extensions {
+            with_extensions.append_extension2(extension)?;
+        }
+    }
+    let subject_alt_names = with_extensions
+        .build()
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| match (name.dnsname(), name.ipaddress()) {
+                    (Some(domain), _) => Some(format!("DNS:{}", domain)),
+                    (_, Some(address)) => match *address {
+                        [a, b, c, d] => Some(IpAddr::from([a, b, c, d])),
+                        _ => <[u8; 16]>::try_from(address).ok().map(IpAddr::from),
+                    }
+                    .map(|address| format!("IP Address:{}", address)),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let key_type = match public_key.id() {
+        Id::RSA => "RSA",
+        Id::EC => "EC",
+        Id::ED25519 => "Ed25519",
+        _ => "unknown",
+    };
+
+    Ok(CsrInfo {
+        common_name,
+        subject_alt_names,
+        key_type: key_type.to_owned(),
+        key_bits: public_key.bits(),
+        signature_algorithm: csr_signature_algorithm(csr)?,
+    })
+}
+
+// The name of the algorithm a CSR is signed with, e.g. `sha256WithRSAEncryption`. openssl
+// has no accessor for it, so the OID is read from the DER encoding, where it follows the
+// signed part: SEQUENCE { info SEQUENCE, algorithm SEQUENCE { OID, .. }, signature }.
+fn csr_signature_algorithm(csr: &X509Req) -> Result<String> {
+    let der = csr.to_der()?;
+    let (request, _) = der_element(&der).ok_or(Error::BadCSR)?;
+    let (_, rest) = der_element(request).ok_or(Error::BadCSR)?;
+    let (algorithm, _) = der_element(rest).ok_or(Error::BadCSR)?;
+    let oid = match algorithm {
+        [0x06, ..] => der_element(algorithm).ok_or(Error::BadCSR)?.0,
+        _ => return Err(Error::BadCSR),
+    };
+
+    // the first byte holds the first two arcs, every further arc is base 128 with the
+    // high bit set on all but its last byte
+    let (first, arcs) = oid.split_first().ok_or(Error::BadCSR)?;
+    let top = (first / 40).min(2);
+    let mut dotted = format!("{}.{}", top, first - top * 40);
+    let mut arc = 0u64;
+    for byte in arcs {
+        arc = (arc << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            dotted.push_str(&format!(".{}", arc));
+            arc = 0;
+        }
+    }
+
+    let object = Asn1Object::from_str(&dotted)?;
+    Ok(object
+        .nid()
+        .long_name()
+        .map_or(dotted, |name| name.to_owned()))
+}
+
+// Splits the contents of the first DER element of the data off the elements after it.
+fn der_element(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&length, rest) = data.get(1..)?.split_first()?;
+    let (length, rest) = match length {
+        0..=0x7f => (length as usize, rest),
+        // the long form gives the number of bytes the length takes up
+        _ => {
+            let bytes = rest.get(..(length & 0x7f) as usize)?;
+            let length = bytes
+                .iter()
+                .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+            (length, &rest[bytes.len()..])
+        }
+    };
+    (rest.len() >= length).then(|| rest.split_at(length))
 }
 
 // Parses the certificate and writes them into to files.
-pub fn save_certificates(certificate_chain: Certificate) -> Result<()> {
+pub fn save_certificates(certificate_chain: String) -> Result<()> {
     // extract the first certificat (certificate for the specified domain)
     let cert_me = certificate_chain
         .lines()
@@ -158,29 +1056,784 @@ pub fn save_certificates(certificate_chain: Certificate) -> Result<()> {
     Ok(())
 }
 
-// Save rsa keypair to private and public key files.
-pub fn save_keypair(keypair: &(Rsa<Private>, Rsa<Public>)) -> Result<()> {
-    let private_key = keypair.0.private_key_to_pem()?;
-    let public_key = keypair.1.public_key_to_pem()?;
+// Writes a PEM encoded chain and the private key of the certificate to a directory in the
+// layout of the format, so the web server config can point straight at the files. Without
+// a private key, e.g. because the user brought their own, only the certificates are
+// written. Returns the written files.
+pub fn save_bundle(
+    certificate_chain: &str,
+    private_key: Option<&PKey<Private>>,
+    format: OutputFormat,
+    dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+
+    let files = match format {
+        OutputFormat::Nginx => vec![("fullchain.pem", certificate_chain.to_owned())],
+        OutputFormat::Apache => {
+            let leaf = X509::from_pem(certificate_chain.as_bytes())?.to_pem()?;
+            let leaf = String::from_utf8(leaf).map_err(|e| e.utf8_error())?;
+            vec![
+                ("cert.pem", leaf),
+                ("chain.pem", intermediate_certificates(certificate_chain)?),
+            ]
+        }
+    };
+
+    let mut paths = Vec::new();
+    for (name, contents) in files {
+        let path = dir.join(name);
+        std::fs::write(&path, contents)?;
+        paths.push(path);
+    }
+    if let Some(private_key) = private_key {
+        let path = dir.join("privkey.pem");
+        write_private_key(&path, &private_key.private_key_to_pem_pkcs8()?)?;
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
 
-    std::fs::write("priv.pem", &private_key)?;
-    std::fs::write("pub.pem", &public_key)?;
+// Writes a private key to a file that's only readable by the current user. The mode only
+// applies to a new file, an existing one, e.g. of an earlier run, is restricted before the
+// key gets written into it.
+fn write_private_key(path: &Path, pem: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(pem)?;
 
     Ok(())
 }
 
-// Load a private and public key from files.
+// Runs a hook command through the shell with the variables in its environment, e.g. to
+// reload the web server once the certificate is saved. Fails with `HookFailed` and the
+// exit status of the command unless it succeeds.
+pub fn run_hook(command: &str, variables: &[(&str, String)]) -> Result<()> {
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(variables.iter().map(|(name, value)| (name, value)))
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::HookFailed(command.to_owned(), status.to_string()))
+    }
+}
+
+// Converts every certificate of a PEM encoded chain to DER, starting with the leaf.
+pub fn certificate_chain_der(certificate_chain: &str) -> Result<Vec<Vec<u8>>> {
+    let mut certificates = Vec::new();
+    for certificate in X509::stack_from_pem(certificate_chain.as_bytes())? {
+        certificates.push(certificate.to_der()?);
+    }
+
+    Ok(certificates)
+}
+
+// Writes a PEM encoded chain as DER files to a directory. DER holds a single certificate,
+// so the leaf goes to `my_cert.der` and the rest of the chain to `chain_1.der`,
+// `chain_2.der`, ... in the order the CA sent them. Returns the written files.
+pub fn save_certificates_der(certificate_chain: &str, dir: &Path) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut paths = Vec::new();
+    for (index, der) in certificate_chain_der(certificate_chain)?.iter().enumerate() {
+        let path = match index {
+            0 => dir.join("my_cert.der"),
+            index => dir.join(format!("chain_{}.der", index)),
+        };
+        std::fs::write(&path, der)?;
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+// Reads the expiry date of the first (leaf) certificate in a PEM encoded chain. openssl only
+// tells the difference between two times, so it is taken from the epoch.
+pub fn certificate_expiry(certificate_chain: &str) -> Result<DateTime<Utc>> {
+    let leaf = X509::from_pem(certificate_chain.as_bytes())?;
+    let diff = Asn1Time::from_unix(0)?.diff(leaf.not_after())?;
+
+    Ok(DateTime::<Utc>::from(std::time::UNIX_EPOCH)
+        + chrono::Duration::days(diff.days.into())
+        + chrono::Duration::seconds(diff.secs.into()))
+}
+
+// Whether the first (leaf) certificate in a PEM encoded chain embeds signed certificate
+// timestamps, the proof that it was logged for certificate transparency that browsers
+// enforcing CT require. openssl has no accessor for arbitrary extensions, so they are read
+// from the DER encoding: SEQUENCE { tbsCertificate SEQUENCE { .., [3] SEQUENCE { Extension
+// SEQUENCE { OID, .. }, .. } }, .. }.
+pub fn has_scts(certificate_chain: &str) -> Result<bool> {
+    let leaf = X509::from_pem(certificate_chain.as_bytes())?.to_der()?;
+    let sct_list = Asn1Object::from_str(SCT_LIST_OID)?;
+
+    let mut fields = der_element(&leaf)
+        .and_then(|(certificate, _)| der_element(certificate))
+        .map_or(&[][..], |(tbs, _)| tbs);
+    let mut extensions: &[u8] = &[];
+    while let Some((field, rest)) = der_element(fields) {
+        // the extensions are the only field tagged [3]
+        if fields[0] == 0xa3 {
+            extensions = der_element(field).map_or(&[][..], |(extensions, _)| extensions);
+            break;
+        }
+        fields = rest;
+    }
+
+    while let Some((extension, rest)) = der_element(extensions) {
+        if let [0x06, ..] = extension {
+            if der_element(extension).map(|(oid, _)| oid) == Some(sct_list.as_slice()) {
+                return Ok(true);
+            }
+        }
+        extensions = rest;
+    }
+    Ok(false)
+}
+
+// Puts the certificates of a PEM encoded chain in the order web servers expect, the leaf
+// first and then issuer by issuer, for CAs or proxies that return it reversed or shuffled.
+// Certificates that aren't part of the path from the leaf go last, a chain whose leaf
+// can't be told apart is returned as is.
+pub fn normalize_chain(certificate_chain: &str) -> Result<String> {
+    let certificates = X509::stack_from_pem(certificate_chain.as_bytes())?;
+    let issues = |issuer: usize, subject: usize| {
+        issuer != subject
+            && certificates[issuer].issued(&certificates[subject]) == X509VerifyResult::OK
+    };
+    let indices = 0..certificates.len();
+
+    let leaves: Vec<usize> = indices
+        .clone()
+        .filter(|&candidate| !indices.clone().any(|subject| issues(candidate, subject)))
+        .collect();
+    let mut order = match leaves[..] {
+        [leaf] => vec![leaf],
+        _ => return Ok(certificate_chain.to_owned()),
+    };
+    while let Some(issuer) = indices
+        .clone()
+        .find(|&issuer| !order.contains(&issuer) && issues(issuer, order[order.len() - 1]))
+    {
+        order.push(issuer);
+    }
+    let unrelated: Vec<usize> = indices
+        .clone()
+        .filter(|index| !order.contains(index))
+        .collect();
+    order.extend(unrelated);
+    if order.iter().copied().eq(indices) {
+        return Ok(certificate_chain.to_owned());
+    }
+
+    let mut normalized = String::new();
+    for index in order {
+        normalized.push_str(std::str::from_utf8(&certificates[index].to_pem()?)?);
+    }
+
+    Ok(normalized)
+}
+
+// Returns the intermediate certificates of a PEM encoded chain, i.e. everything after the leaf.
+pub fn intermediate_certificates(certificate_chain: &str) -> Result<String> {
+    let mut intermediates = String::new();
+    for certificate in X509::stack_from_pem(certificate_chain.as_bytes())?
+        .iter()
+        .skip(1)
+    {
+        intermediates.push_str(std::str::from_utf8(&certificate.to_pem()?)?);
+    }
+
+    Ok(intermediates)
+}
+
+// Returns the subjects of all certificates of a PEM encoded chain, starting with the leaf.
+pub fn certificate_subjects(certificate_chain: &str) -> Result<Vec<String>> {
+    let mut subjects = Vec::new();
+    for certificate in X509::stack_from_pem(certificate_chain.as_bytes())? {
+        let mut subject = Vec::new();
+        for entry in certificate.subject_name().entries() {
+            subject.push(format!(
+                "{}={}",
+                entry.object().nid().short_name()?,
+                String::from_utf8_lossy(entry.data().as_slice())
+            ));
+        }
+        subjects.push(subject.join(", "));
+    }
+
+    Ok(subjects)
+}
+
+// Returns the common name of the issuer of the topmost certificate of a PEM encoded chain,
+// i.e. the root the chain leads to, e.g. `ISRG Root X1`.
+pub fn chain_root_issuer(certificate_chain: &str) -> Result<Option<String>> {
+    let chain = X509::stack_from_pem(certificate_chain.as_bytes())?;
+    let root_issuer = chain.last().and_then(|topmost| {
+        topmost
+            .issuer_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .map(|entry| String::from_utf8_lossy(entry.data().as_slice()).into_owned())
+    });
+
+    Ok(root_issuer)
+}
+
+// Checks that a PEM encoded chain holds together: the leaf is currently valid and for the
+// given public key, and every certificate is signed by the one after it. Whether the chain
+// leads to a trusted root is left to the clients of the certificate.
+pub fn verify_chain(certificate_chain: &str, public_key: &PKey<Public>) -> Result<()> {
+    let chain = X509::stack_from_pem(certificate_chain.as_bytes())?;
+    let leaf = chain
+        .first()
+        .ok_or_else(|| Error::InvalidChain("it holds no certificate".to_owned()))?;
+    if !leaf.public_key()?.public_eq(public_key) {
+        return Err(Error::InvalidChain(
+            "the leaf isn't for the key of the CSR".to_owned(),
+        ));
+    }
+    let now = Asn1Time::days_from_now(0)?;
+    if leaf.not_before() > now || leaf.not_after() < now {
+        return Err(Error::InvalidChain("the leaf isn't valid now".to_owned()));
+    }
+
+    for (index, pair) in chain.windows(2).enumerate() {
+        let (subject, issuer) = (&pair[0], &pair[1]);
+        let issuer_key = issuer.public_key()?;
+        let signed = subject.verify(&issuer_key)?;
+        if issuer.issued(subject) != X509VerifyResult::OK || !signed {
+            return Err(Error::InvalidChain(format!(
+                "certificate {} isn't issued by the next one",
+                index + 1
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the DNS names in the SANs of the leaf certificate of a PEM encoded chain, or
+// its common name if it has no SANs.
+pub fn certificate_domains(certificate_chain: &str) -> Result<Vec<String>> {
+    let leaf = X509::from_pem(certificate_chain.as_bytes())?;
+    let domains: Vec<String> = leaf
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.dnsname().map(str::to_owned))
+                .collect()
+        })
+        .unwrap_or_default();
+    if !domains.is_empty() {
+        return Ok(domains);
+    }
+
+    leaf.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .map(|entry| vec![String::from_utf8_lossy(entry.data().as_slice()).into_owned()])
+        .ok_or(Error::BadCSR)
+}
+
+// What inventory systems track a deployed certificate by.
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateInfo {
+    pub domains: Vec<String>,
+    // The serial number in hex.
+    pub serial: String,
+    // The key identifier of the issuing CA's key in hex, if the certificate carries one.
+    pub authority_key_id: Option<String>,
+    pub not_after: DateTime<Utc>,
+}
+
+// Describes the leaf certificate of a PEM encoded chain.
+pub fn describe_certificate(certificate_chain: &str) -> Result<CertificateInfo> {
+    let leaf = X509::from_pem(certificate_chain.as_bytes())?;
+
+    Ok(CertificateInfo {
+        domains: certificate_domains(certificate_chain)?,
+        serial: hex(&leaf.serial_number().to_bn()?.to_vec()),
+        authority_key_id: leaf.authority_key_id().map(|id| hex(id.as_slice())),
+        not_after: certificate_expiry(certificate_chain)?,
+    })
+}
+
+// Uppercase hex without separators, the way CAs and CMDBs list serial numbers.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+// Writes the intermediate certificates of a chain to a file, e.g. for OCSP stapling.
+pub fn save_intermediate_certificates(certificate_chain: &str, path: &Path) -> Result<()> {
+    std::fs::write(path, intermediate_certificates(certificate_chain)?)?;
+
+    Ok(())
+}
+
+// Encodes a keypair as PEM, the private key as PKCS#8, e.g. to hand it to a secret store
+// without writing it to disk.
+pub fn keypair_to_pem(keypair: &(PKey<Private>, PKey<Public>)) -> Result<(String, String)> {
+    let private_key = String::from_utf8(keypair.0.private_key_to_pem_pkcs8()?)
+        .map_err(|e| e.utf8_error())?;
+    let public_key = String::from_utf8(keypair.1.public_key_to_pem()?)
+        .map_err(|e| e.utf8_error())?;
+
+    Ok((private_key, public_key))
+}
+
+// Save keypair to the priv.pem and pub.pem files in a directory.
+pub fn save_keypair(keypair: &(PKey<Private>, PKey<Public>), dir: &Path) -> Result<()> {
+    let (private_key, public_key) = keypair_to_pem(keypair)?;
+
+    write_private_key(&dir.join("priv.pem"), private_key.as_bytes())?;
+    std::fs::write(dir.join("pub.pem"), public_key)?;
+
+    Ok(())
+}
+
+// Load a private and public key from files. Fails with `BadPublicKey` if the keys don't
+// match or a RSA modulus is shorter than `MIN_KEY_WIDTH` bits.
 pub fn load_keys_from_file(
     path_to_private: &str,
     path_to_public: &str,
-    ) -> Result<(Rsa<Private>, Rsa<Public>)> {
+    ) -> Result<(PKey<Private>, PKey<Public>)> {
     let priv_key = std::fs::read(path_to_private)?;
     let pub_key = std::fs::read(path_to_public)?;
 
-    Ok((
-        Rsa::private_key_from_pem(&priv_key)?,
-        Rsa::public_key_from_pem(&pub_key)?,
-    ))
+    let priv_key = PKey::private_key_from_pem(&priv_key)?;
+    let pub_key = PKey::public_key_from_pem(&pub_key)?;
+
+    // catch key files that don't belong together and keys that are too weak to be accepted
+    if !priv_key.public_eq(&pub_key) {
+        return Err(Error::BadPublicKey);
+    }
+    if pub_key.id() == Id::RSA && pub_key.bits() < MIN_KEY_WIDTH {
+        return Err(Error::BadPublicKey);
+    }
+
+    Ok((priv_key, pub_key))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::x509::X509Builder;
+
+    fn ec_key(curve: Nid) -> PKey<Private> {
+        let group = EcGroup::from_curve_name(curve).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    // A self-signed PEM certificate with the given extensions.
+    fn certificate(extensions: Vec<X509Extension>) -> String {
+        let (key, _) = generate_keypair(KeyType::Ed25519).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "example.org")
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(90).unwrap())
+            .unwrap();
+        for extension in extensions {
+            builder.append_extension(extension).unwrap();
+        }
+        builder.sign(&key, MessageDigest::null()).unwrap();
+
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    #[cfg(feature = "client")]
+    fn response(status: u16, headers: &[(&str, &str)], body: &str) -> Response {
+        let mut response = ::http::Response::builder().status(status);
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        response.body(body.to_owned()).unwrap().into()
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn check_problem_maps_a_429_to_rate_limited() {
+        let response = response(429, &[("Retry-After", "30")], "");
+        let error = check_problem(response, |_| None).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::RateLimited(Some(delay)) if delay == Duration::from_secs(30)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn check_problem_maps_the_rate_limited_type() {
+        let problem = r#"{"type": "urn:ietf:params:acme:error:rateLimited", "detail": "too many"}"#;
+        let response = response(403, &[("X-Request-Id", "abc")], problem);
+        let error = check_problem(response, |_| None).unwrap_err();
+        assert!(matches!(error, Error::WithRequestId(_, ref id) if id == "abc"));
+        assert!(matches!(error.root(), Error::RateLimited(None)));
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn check_problem_prefers_the_known_types() {
+        let problem = r#"{"type": "urn:ietf:params:acme:error:badCSR"}"#;
+        let error = check_problem(response(400, &[], problem), |problem_type| {
+            (problem_type == "urn:ietf:params:acme:error:badCSR").then_some(Error::BadCSR)
+        })
+        .unwrap_err();
+        assert!(matches!(error, Error::BadCSR));
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn check_problem_keeps_the_status_of_other_bodies() {
+        let error =
+            check_problem(response(502, &[], "<html>Bad Gateway</html>"), |_| None).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::FromReqwestError(e) if e.status() == Some(StatusCode::BAD_GATEWAY)
+        ));
+        assert!(check_problem(response(200, &[], "{}"), |_| None).is_ok());
+    }
+
+    #[test]
+    fn describe_csr_reads_the_sans_and_the_signature_algorithm() {
+        let keypair = generate_keypair(KeyType::Ed25519).unwrap();
+        let csr = request_csr(
+            &keypair,
+            &["example.org", "192.0.2.1", "2001:db8::1"],
+            CsrDigest::Sha256,
+            &[],
+            &[],
+        )
+        .unwrap();
+
+        let info = describe_csr(&csr).unwrap();
+        assert_eq!(info.common_name.as_deref(), Some("example.org"));
+        assert_eq!(
+            info.subject_alt_names,
+            [
+                "DNS:example.org",
+                "IP Address:192.0.2.1",
+                "IP Address:2001:db8::1"
+            ]
+        );
+        assert_eq!(info.key_type, "Ed25519");
+        assert_eq!(info.signature_algorithm, "ED25519");
+
+        let keypair = generate_keypair(KeyType::Rsa).unwrap();
+        let csr = request_csr(&keypair, &["example.org"], CsrDigest::Sha384, &[], &[]).unwrap();
+        assert_eq!(
+            describe_csr(&csr).unwrap().signature_algorithm,
+            "sha384WithRSAEncryption"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn private_keys_are_only_readable_by_the_owner() {
+        let dir = std::env::temp_dir().join(format!("acme-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // a key file of an earlier run that anyone could read
+        let earlier = dir.join("privkey.pem");
+        std::fs::write(&earlier, "").unwrap();
+        std::fs::set_permissions(&earlier, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let keypair = generate_keypair(KeyType::Ed25519).unwrap();
+        save_keypair(&keypair, &dir).unwrap();
+        let paths = save_bundle(
+            &certificate(Vec::new()),
+            Some(&keypair.0),
+            OutputFormat::Nginx,
+            &dir,
+        )
+        .unwrap();
+
+        let mode = |name: &str| {
+            let metadata = std::fs::metadata(dir.join(name)).unwrap();
+            metadata.permissions().mode() & 0o777
+        };
+        let modes = [mode("priv.pem"), mode("privkey.pem")];
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(paths, [dir.join("fullchain.pem"), dir.join("privkey.pem")]);
+        assert_eq!(modes, [0o600, 0o600]);
+    }
+
+    #[test]
+    fn certificate_expiry_is_the_not_after_of_the_leaf() {
+        let pem = certificate(Vec::new());
+        let not_after = X509::from_pem(pem.as_bytes())
+            .unwrap()
+            .not_after()
+            .to_string();
+        let expected =
+            chrono::NaiveDateTime::parse_from_str(&not_after, "%b %e %H:%M:%S %Y GMT").unwrap();
+
+        assert_eq!(certificate_expiry(&pem).unwrap(), expected.and_utc());
+    }
+
+    #[test]
+    fn has_scts_finds_the_sct_list_extension() {
+        let oid = Asn1Object::from_str(SCT_LIST_OID).unwrap();
+        let scts = Asn1OctetString::new_from_bytes(&[0x04, 0x02, 0x00, 0x00]).unwrap();
+        let sct_list = X509Extension::new_from_der(&oid, false, &scts).unwrap();
+
+        assert!(has_scts(&certificate(vec![sct_list])).unwrap());
+        assert!(!has_scts(&certificate(Vec::new())).unwrap());
+    }
+
+    #[test]
+    fn has_scts_ignores_the_oid_outside_of_an_extension_id() {
+        // an unknown extension whose value happens to hold the encoded OID of the SCT list
+        let sct_list = Asn1Object::from_str(SCT_LIST_OID).unwrap();
+        let mut value = vec![0x06, sct_list.as_slice().len() as u8];
+        value.extend_from_slice(sct_list.as_slice());
+        let oid = Asn1Object::from_str("1.3.6.1.4.1.55555.1").unwrap();
+        let value = Asn1OctetString::new_from_bytes(&value).unwrap();
+        let other = X509Extension::new_from_der(&oid, false, &value).unwrap();
 
+        assert!(!has_scts(&certificate(vec![other])).unwrap());
+    }
+
+    #[test]
+    fn jws_round_trips_through_decode_jws() {
+        let (private_key, public_key) = generate_keypair(KeyType::Ed25519).unwrap();
+        let header = json!({ "url": "https://example.org/new-order", "nonce": "nonce" });
+        let payload = json!({ "identifiers": [{ "type": "dns", "value": "example.org" }] });
+
+        let decoded = decode_jws(&jws(payload.clone(), header, &private_key).unwrap()).unwrap();
+        assert_eq!(decoded.header["alg"], "EdDSA");
+        assert_eq!(decoded.header["url"], "https://example.org/new-order");
+        assert_eq!(decoded.payload, Some(payload));
+        assert!(decoded.verify(&public_key).unwrap());
+
+        let (_, other_key) = generate_keypair(KeyType::Ed25519).unwrap();
+        assert!(!decoded.verify(&other_key).unwrap());
+    }
+
+    #[test]
+    fn the_empty_payload_of_a_post_as_get_decodes_to_none() {
+        let (private_key, public_key) = generate_keypair(KeyType::Ed25519).unwrap();
+        let signed = jws(json!(""), json!({ "nonce": "nonce" }), &private_key).unwrap();
+        assert_eq!(signed["payload"], "");
+
+        let decoded = decode_jws(&signed).unwrap();
+        assert_eq!(decoded.payload, None);
+        assert!(decoded.verify(&public_key).unwrap());
+    }
+
+    #[test]
+    fn decode_jws_rejects_what_isnt_one() {
+        let error = decode_jws(&json!({ "protected": "e30", "payload": "" })).unwrap_err();
+        assert!(matches!(error, Error::MalformedRequest));
+    }
+
+    #[test]
+    fn jwk_and_thumbprint_match_rfc_8037() {
+        // the Ed25519 key of RFC 8037, appendix A
+        let d = decode_config(
+            "nWGxne_9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A",
+            base64::URL_SAFE_NO_PAD,
+        )
+        .unwrap();
+        let private_key = PKey::private_key_from_raw_bytes(&d, Id::ED25519).unwrap();
+
+        assert_eq!(
+            jwk(&private_key).unwrap(),
+            json!({
+                "crv": "Ed25519",
+                "kty": "OKP",
+                "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo",
+            })
+        );
+        assert_eq!(
+            thumbprint(&private_key).unwrap(),
+            "kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k"
+        );
+    }
+
+    #[test]
+    fn jws_algorithm_follows_the_key_type() {
+        let rsa = generate_keypair(KeyType::Rsa).unwrap().0;
+        let p256 = ec_key(Nid::X9_62_PRIME256V1);
+        let ed25519 = generate_keypair(KeyType::Ed25519).unwrap().0;
+
+        assert_eq!(jws_algorithm(&rsa).unwrap(), "RS256");
+        assert_eq!(jws_algorithm(&p256).unwrap(), "ES256");
+        assert_eq!(jws_algorithm(&ed25519).unwrap(), "EdDSA");
+        // ES384 isn't implemented, a P-384 key must not be sent with a wrong `alg`
+        assert!(matches!(
+            jws_algorithm(&ec_key(Nid::SECP384R1)),
+            Err(Error::BadSignatureAlgorithm)
+        ));
+    }
+
+    #[test]
+    fn an_es256_signature_is_the_raw_r_and_s() {
+        let private_key = ec_key(Nid::X9_62_PRIME256V1);
+        let public_key =
+            PKey::public_key_from_pem(&private_key.public_key_to_pem().unwrap()).unwrap();
+
+        let signed = jws(json!({}), json!({ "nonce": "nonce" }), &private_key).unwrap();
+        let decoded = decode_jws(&signed).unwrap();
+        assert_eq!(decoded.header["alg"], "ES256");
+        assert_eq!(decoded.signature.len(), 2 * ES256_COORDINATE_LEN as usize);
+        assert!(decoded.verify(&public_key).unwrap());
+    }
+
+    #[test]
+    fn to_ascii_domain_converts_the_u_labels() {
+        assert_eq!(
+            to_ascii_domain("Bücher.Example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            to_ascii_domain("*.bücher.example").unwrap(),
+            "*.xn--bcher-kva.example"
+        );
+        assert_eq!(to_ascii_domain("example.org").unwrap(), "example.org");
+        assert_eq!(to_ascii_domain("2001:db8::1").unwrap(), "2001:db8::1");
+        assert!(matches!(
+            to_ascii_domain("exa mple.org"),
+            Err(Error::InvalidDomain(domain)) if domain == "exa mple.org"
+        ));
+    }
+
+    #[test]
+    fn a_domain_name_keeps_both_forms() {
+        let name: DomainName = "例え.テスト".parse().unwrap();
+        assert_eq!(name.ascii, "xn--r8jz45g.xn--zckzah");
+        assert_eq!(name.unicode, "例え.テスト");
+
+        let wildcard: DomainName = "*.xn--r8jz45g.xn--zckzah".parse().unwrap();
+        assert_eq!(wildcard.unicode, "*.例え.テスト");
+    }
+
+    // A port that's free on the IPv6 loopback, e.g. to bind both families to.
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("[::1]:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn check_for_existing_server_on_finds_a_server_on_ipv6() {
+        let listener = std::net::TcpListener::bind("[::1]:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        assert!(check_for_existing_server_on(port));
+    }
+
+    #[test]
+    fn bind_both_families_serves_ipv4_and_ipv6() {
+        let port = free_port();
+        let in_use = |error: &io::Error| error.kind() == io::ErrorKind::AddrInUse;
+        let listeners = bind_both_families(port, std::net::TcpListener::bind, in_use).unwrap();
+        assert!(!listeners.is_empty());
+
+        assert!(TcpStream::connect(("::1", port)).is_ok());
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn normalize_chain_orders_a_shuffled_chain_issuer_by_issuer() {
+        let (root, root_key) = crate::mock::certificate("Mock Root", None);
+        let (intermediate, intermediate_key) =
+            crate::mock::certificate("Mock Intermediate", Some((&root, &root_key)));
+        let (leaf, _) =
+            crate::mock::certificate("example.org", Some((&intermediate, &intermediate_key)));
+        let pem = |certificates: &[&X509]| -> String {
+            certificates
+                .iter()
+                .map(|certificate| String::from_utf8(certificate.to_pem().unwrap()).unwrap())
+                .collect()
+        };
+        let ordered = pem(&[&leaf, &intermediate, &root]);
+
+        for shuffled in [
+            pem(&[&root, &intermediate, &leaf]),
+            pem(&[&intermediate, &root, &leaf]),
+            pem(&[&root, &leaf, &intermediate]),
+        ] {
+            assert_eq!(normalize_chain(&shuffled).unwrap(), ordered);
+        }
+        assert_eq!(normalize_chain(&ordered).unwrap(), ordered);
+    }
+
+    #[test]
+    fn csr_extension_parses_the_oid_criticality_and_hex_value() {
+        let extension: CsrExtension = "1.3.6.1.5.5.7.1.24=critical,3003020105".parse().unwrap();
+        assert_eq!(
+            extension,
+            CsrExtension {
+                oid: "1.3.6.1.5.5.7.1.24".to_owned(),
+                critical: true,
+                value: vec![0x30, 0x03, 0x02, 0x01, 0x05],
+            }
+        );
+
+        let extension: CsrExtension = "2.5.29.32 = 3000".parse().unwrap();
+        assert_eq!(extension.oid, "2.5.29.32");
+        assert!(!extension.critical);
+        assert_eq!(extension.value, [0x30, 0x00]);
+    }
+
+    #[test]
+    fn csr_extension_rejects_malformed_input_and_the_sans() {
+        for malformed in [
+            "2.5.29.32",
+            "2.5.29.32=",
+            "2.5.29.32=300",
+            "2.5.29.32=zz00",
+            "x=3000",
+        ] {
+            assert!(
+                matches!(malformed.parse::<CsrExtension>(), Err(Error::BadCSR)),
+                "{}",
+                malformed
+            );
+        }
+        assert!(matches!(
+            "2.5.29.17=3000".parse::<CsrExtension>(),
+            Err(Error::CsrExtensionConflict(oid)) if oid == "2.5.29.17"
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn extract_payload_and_nonce_maps_an_error_response() {
+        let problem = r#"{"type": "urn:ietf:params:acme:error:malformed", "detail": "bad"}"#;
+        let rejected = response(400, &[("Replay-Nonce", "nonce")], problem);
+        let error = extract_payload_and_nonce::<serde_json::Value>(rejected).unwrap_err();
+        assert!(matches!(error, Error::WithDetail(_, ref detail) if detail == "bad"));
+
+        // e.g. the error page of a proxy in front of the CA
+        let error_page = response(502, &[], "<html>Bad Gateway</html>");
+        let error = extract_payload_and_nonce::<serde_json::Value>(error_page).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::FromReqwestError(ref e) if e.status() == Some(StatusCode::BAD_GATEWAY)
+        ));
+    }
+}